@@ -1,5 +1,5 @@
 use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+use easy_fs::{BlockDevice, BlockError, EasyFileSystem};
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
@@ -10,18 +10,20 @@ const BLOCK_SZ: usize = 512;
 struct BlockFile(Mutex<File>);
 
 impl BlockDevice for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
         let mut file = self.0.lock().unwrap();
         file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
             .expect("Error when seeking!");
         assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        Ok(())
     }
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
         let mut file = self.0.lock().unwrap();
         file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        Ok(())
     }
 }
 
@@ -60,7 +62,7 @@ fn easy_fs_pack() -> std::io::Result<()> {
     })));
     // 4MiB, at most 4095 files
     let efs = EasyFileSystem::create(block_file.clone(), 14000, 1);
-    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    let root_inode = EasyFileSystem::root_inode(&efs);
     for dir_entry in read_dir(src_path).unwrap() {
         let dir_entry = dir_entry.unwrap();
         let path = dir_entry.path();
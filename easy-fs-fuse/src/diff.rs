@@ -0,0 +1,245 @@
+//! Comparing the trees of two easy-fs images
+
+use super::BLOCK_SZ;
+use easy_fs::{EasyFileSystem, Inode, InodeType};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+/// Number of differing bytes shown for the first differing block
+const SHOWN_BYTES: usize = 16;
+
+/// The first block of a file which differs between two images
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockDiff {
+    /// Index of the block in the file
+    pub block: usize,
+    /// Differing bytes as (offset in the file, old, new), None past the end
+    pub bytes: Vec<(usize, Option<u8>, Option<u8>)>,
+}
+
+/// How a path differs between two images
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffKind {
+    Added(InodeType),
+    Removed(InodeType),
+    TypeChanged(InodeType, InodeType),
+    Modified {
+        /// Old and new sizes, if they differ
+        size: Option<(usize, usize)>,
+        /// Old and new link counts, if they differ
+        links: Option<(usize, usize)>,
+        /// Old and new symbolic link targets, if they differ
+        target: Option<(String, String)>,
+        /// The first differing block of a file, if the data differs
+        first_block: Option<BlockDiff>,
+    },
+}
+
+/// A path which differs between two images
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub path: String,
+    pub kind: DiffKind,
+}
+
+fn type_name(type_: InodeType) -> &'static str {
+    match type_ {
+        InodeType::File => "file",
+        InodeType::Directory => "directory",
+        InodeType::SymLink => "symbolic link",
+    }
+}
+
+/// One line per difference, "A", "D" or "M" and the path, then what changed
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.kind {
+            DiffKind::Added(type_) => write!(f, "A {} ({})", self.path, type_name(*type_)),
+            DiffKind::Removed(type_) => write!(f, "D {} ({})", self.path, type_name(*type_)),
+            DiffKind::TypeChanged(old, new) => {
+                write!(f, "M {}: {} -> {}", self.path, type_name(*old), type_name(*new))
+            }
+            DiffKind::Modified { size, links, target, first_block } => {
+                let mut changes = Vec::new();
+                if let Some((old, new)) = size {
+                    changes.push(format!("size {} -> {}", old, new));
+                }
+                if let Some((old, new)) = links {
+                    changes.push(format!("links {} -> {}", old, new));
+                }
+                if let Some((old, new)) = target {
+                    changes.push(format!("target {:?} -> {:?}", old, new));
+                }
+                if size.is_none() && first_block.is_some() {
+                    changes.push(String::from("content"));
+                }
+                write!(f, "M {}: {}", self.path, changes.join(", "))
+            }
+        }
+    }
+}
+
+impl BlockDiff {
+    /// Describe the differing bytes, one line each
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!("  first differing block: {}", self.block)];
+        let byte = |value: Option<u8>| value.map_or(String::from("--"), |value| format!("{:02x}", value));
+        for &(offset, old, new) in self.bytes.iter().take(SHOWN_BYTES) {
+            lines.push(format!("    {:#010x}: {} -> {}", offset, byte(old), byte(new)));
+        }
+        if self.bytes.len() > SHOWN_BYTES {
+            lines.push(format!("    and {} more bytes", self.bytes.len() - SHOWN_BYTES));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Count the directory entries linking to every inode, "." and ".." excluded
+fn link_counts(efs: &Arc<EasyFileSystem>) -> HashMap<u32, usize> {
+    let mut counts = HashMap::new();
+    let mut dirs = vec![EasyFileSystem::root_inode(efs)];
+    while let Some(dir) = dirs.pop() {
+        for (name, inode_id, type_) in dir.read_dir(0) {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let count = counts.entry(inode_id).or_insert(0);
+            *count += 1;
+            if type_ == InodeType::Directory && *count == 1 {
+                dirs.push(EasyFileSystem::get_inode(efs, inode_id));
+            }
+        }
+    }
+    counts
+}
+
+/// Find the first block where the data of two files differs
+fn first_block_diff(old: &Inode, new: &Inode) -> Option<BlockDiff> {
+    let mut old_block = [0u8; BLOCK_SZ];
+    let mut new_block = [0u8; BLOCK_SZ];
+    let mut block = 0;
+    loop {
+        let old_len = old.read_at(block * BLOCK_SZ, &mut old_block);
+        let new_len = new.read_at(block * BLOCK_SZ, &mut new_block);
+        if old_len == 0 && new_len == 0 {
+            return None;
+        }
+        if old_block[..old_len] != new_block[..new_len] {
+            let at = |data: &[u8], len: usize, i: usize| if i < len { Some(data[i]) } else { None };
+            let bytes = (0..old_len.max(new_len))
+                .map(|i| (block * BLOCK_SZ + i, at(&old_block, old_len, i), at(&new_block, new_len, i)))
+                .filter(|(_, old, new)| old != new)
+                .collect();
+            return Some(BlockDiff { block, bytes });
+        }
+        block += 1;
+    }
+}
+
+/// Get the old and new values if they differ
+fn changed<T: PartialEq>(old: T, new: T) -> Option<(T, T)> {
+    if old != new {
+        Some((old, new))
+    } else {
+        None
+    }
+}
+
+/// Walks the trees of two images side by side
+struct Differ<'a> {
+    old: &'a Arc<EasyFileSystem>,
+    new: &'a Arc<EasyFileSystem>,
+    old_links: HashMap<u32, usize>,
+    new_links: HashMap<u32, usize>,
+    differences: Vec<Difference>,
+}
+
+/// Compare the trees of two images, returning the differing paths in
+/// path order
+///
+/// Everything under an added or removed directory is listed.
+pub fn diff_images(old: &Arc<EasyFileSystem>, new: &Arc<EasyFileSystem>) -> Vec<Difference> {
+    let mut differ = Differ {
+        old,
+        new,
+        old_links: link_counts(old),
+        new_links: link_counts(new),
+        differences: Vec::new(),
+    };
+    differ.diff_dir(
+        Some(&EasyFileSystem::root_inode(old)),
+        Some(&EasyFileSystem::root_inode(new)),
+        "",
+    );
+    differ.differences
+}
+
+/// Get the entries of a directory by name, "." and ".." excluded
+fn entries(dir: Option<&Arc<Inode>>) -> BTreeMap<String, (u32, InodeType)> {
+    dir.map_or_else(BTreeMap::new, |dir| {
+        dir.read_dir(0)
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .map(|(name, inode_id, type_)| (name, (inode_id, type_)))
+            .collect()
+    })
+}
+
+impl Differ<'_> {
+    fn push(&mut self, path: &str, kind: DiffKind) {
+        self.differences.push(Difference { path: String::from(path), kind });
+    }
+    /// Compare two directories, either of which may be missing
+    fn diff_dir(&mut self, old: Option<&Arc<Inode>>, new: Option<&Arc<Inode>>, rel: &str) {
+        let old_entries = entries(old);
+        let new_entries = entries(new);
+        let names: BTreeSet<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+        for name in names {
+            let path = if rel.is_empty() { name.clone() } else { format!("{}/{}", rel, name) };
+            let old = old_entries.get(name).copied();
+            let new = new_entries.get(name).copied();
+            let old_inode = old.map(|(inode_id, _)| EasyFileSystem::get_inode(self.old, inode_id));
+            let new_inode = new.map(|(inode_id, _)| EasyFileSystem::get_inode(self.new, inode_id));
+            match (old, new) {
+                (Some((_, old_type)), None) => self.push(&path, DiffKind::Removed(old_type)),
+                (None, Some((_, new_type))) => self.push(&path, DiffKind::Added(new_type)),
+                (Some((_, old_type)), Some((_, new_type))) if old_type != new_type => {
+                    self.push(&path, DiffKind::TypeChanged(old_type, new_type))
+                }
+                (Some((old_id, type_)), Some((new_id, _))) if type_ != InodeType::Directory => {
+                    self.diff_inode(
+                        &path,
+                        (old_inode.as_ref().unwrap(), old_id),
+                        (new_inode.as_ref().unwrap(), new_id),
+                    );
+                }
+                _ => {}
+            }
+            let is_dir = |entry: Option<(u32, InodeType)>| {
+                entry.map_or(false, |(_, type_)| type_ == InodeType::Directory)
+            };
+            if is_dir(old) || is_dir(new) {
+                self.diff_dir(
+                    old_inode.as_ref().filter(|_| is_dir(old)),
+                    new_inode.as_ref().filter(|_| is_dir(new)),
+                    &path,
+                );
+            }
+        }
+    }
+    /// Compare two files or two symbolic links
+    fn diff_inode(&mut self, path: &str, (old, old_id): (&Inode, u32), (new, new_id): (&Inode, u32)) {
+        let size = changed(old.size(), new.size());
+        let links = changed(self.old_links[&old_id], self.new_links[&new_id]);
+        let (target, first_block) = match old.inode_type() {
+            InodeType::SymLink => {
+                let read_link = |inode: &Inode| inode.read_link().unwrap_or_default();
+                (changed(read_link(old), read_link(new)), None)
+            }
+            _ => (None, first_block_diff(old, new)),
+        };
+        if size.is_some() || links.is_some() || target.is_some() || first_block.is_some() {
+            self.push(path, DiffKind::Modified { size, links, target, first_block });
+        }
+    }
+}
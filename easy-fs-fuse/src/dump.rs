@@ -0,0 +1,292 @@
+//! Dumping the on-disk structures of an easy-fs image
+//!
+//! Dumps are meant for damaged images too: the super block is read as is
+//! and everything else through the checked calls of easy-fs, so what
+//! can't be read is reported in place of the item and the dump goes on.
+
+use serde_json::{json, Value};
+use easy_fs::{BlockDevice, BlockMap, EasyFileSystem, EfsError, InodeInfo, InodeType, SuperBlockInfo};
+use std::fmt::Write;
+use std::sync::Arc;
+
+fn type_name(type_: InodeType) -> &'static str {
+    match type_ {
+        InodeType::File => "file",
+        InodeType::Directory => "directory",
+        InodeType::SymLink => "symlink",
+    }
+}
+
+/// Format runs as "a-b, c"
+fn runs_text(runs: &[(u32, u32)]) -> String {
+    let runs: Vec<String> = runs
+        .iter()
+        .map(|&(start, len)| match len {
+            1 => format!("{}", start),
+            _ => format!("{}-{}", start, start + len - 1),
+        })
+        .collect();
+    runs.join(", ")
+}
+
+fn runs_json(runs: Result<Vec<(u32, u32)>, EfsError>) -> Value {
+    match runs {
+        Ok(runs) => json!({
+            "allocated": runs.iter().map(|&(_, len)| len).sum::<u32>(),
+            "runs": runs.iter().map(|&(start, len)| [start, len]).collect::<Vec<_>>(),
+        }),
+        Err(err) => json!({ "error": err.to_string() }),
+    }
+}
+
+fn super_block_json(sb: &SuperBlockInfo) -> Value {
+    json!({
+        "valid": sb.valid,
+        "version": sb.version,
+        "feature_compat": sb.feature_compat,
+        "feature_incompat": sb.feature_incompat,
+        "feature_ro_compat": sb.feature_ro_compat,
+        "total_blocks": sb.total_blocks,
+        "inode_bitmap_blocks": sb.inode_bitmap_blocks,
+        "inode_area_blocks": sb.inode_area_blocks,
+        "data_bitmap_blocks": sb.data_bitmap_blocks,
+        "refcount_blocks": sb.refcount_blocks,
+        "data_area_blocks": sb.data_area_blocks,
+        "data_area_start": sb.data_area_start,
+        "snapshot_inode": sb.snapshot_inode,
+    })
+}
+
+fn inode_json(efs: &EasyFileSystem, info: &InodeInfo) -> Value {
+    let mut json = json!({
+        "id": info.inode_id,
+        "type": type_name(info.type_),
+        "size": info.size,
+        "inline": info.inline,
+    });
+    if info.inline {
+        json["inline_data"] = json!(info.inline_data);
+    } else {
+        json["direct"] = json!(info.direct);
+        json["indirect1"] = json!(info.indirect1);
+        json["indirect2"] = json!(info.indirect2);
+    }
+    match efs.try_dir_entries_of(info.inode_id) {
+        Ok(Some(entries)) => {
+            json["entries"] = entries
+                .into_iter()
+                .map(|(name, inode_id)| json!({ "name": name, "inode": inode_id }))
+                .collect();
+        }
+        Ok(None) => {}
+        Err(err) => json["error"] = json!(err.to_string()),
+    }
+    json
+}
+
+/// Dump an allocated inode as JSON, or what keeps it from being read
+fn checked_inode_json(efs: &EasyFileSystem, inode_id: u32) -> Option<(Value, Option<InodeInfo>)> {
+    match efs.try_inode_info(inode_id) {
+        Ok(Some(info)) => Some((inode_json(efs, &info), Some(info))),
+        Ok(None) => None,
+        Err(err) => Some((json!({ "id": inode_id, "error": err.to_string() }), None)),
+    }
+}
+
+fn block_map_json(map: &BlockMap, info: &InodeInfo) -> Value {
+    let mut json = json!({ "data": map.data });
+    if !map.indirect1.is_empty() {
+        json["indirect1"] = json!({ "block": info.indirect1, "entries": map.indirect1 });
+    }
+    if !map.indirect2.is_empty() {
+        let blocks: Vec<Value> = map
+            .indirect2
+            .iter()
+            .map(|(block_id, entries)| json!({ "block": block_id, "entries": entries }))
+            .collect();
+        json["indirect2"] = json!({ "block": info.indirect2, "entries": blocks });
+    }
+    json
+}
+
+/// Get the ids of the allocated inodes, none if the bitmap can't be read
+fn inode_ids(efs: &EasyFileSystem) -> impl Iterator<Item = u32> {
+    efs.try_allocated_inodes()
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|(start, len)| start..start + len)
+}
+
+/// Dump the whole image on a device as JSON
+///
+/// An image which can't be opened is dumped down to its super block
+/// along with the reason.
+pub fn dump_json(device: &Arc<dyn BlockDevice>) -> Value {
+    let super_block = match EasyFileSystem::read_super_block(device) {
+        Ok(super_block) => super_block,
+        Err(err) => return json!({ "error": EfsError::from(err).to_string() }),
+    };
+    let mut json = json!({ "super_block": super_block_json(&super_block) });
+    let efs = match EasyFileSystem::try_open(Arc::clone(device)) {
+        Ok(efs) => efs,
+        Err(err) => {
+            json["error"] = json!(err.to_string());
+            return json;
+        }
+    };
+    let inodes: Vec<Value> = inode_ids(&efs)
+        .filter_map(|inode_id| checked_inode_json(&efs, inode_id))
+        .map(|(json, _)| json)
+        .collect();
+    json["inode_bitmap"] = runs_json(efs.try_allocated_inodes());
+    json["data_bitmap"] = runs_json(efs.try_allocated_data_blocks());
+    json["inodes"] = json!(inodes);
+    json
+}
+
+/// Dump one inode along with its full block map as JSON, none if it is
+/// not allocated
+pub fn dump_inode_json(efs: &EasyFileSystem, inode_id: u32) -> Option<Value> {
+    let (mut json, info) = checked_inode_json(efs, inode_id)?;
+    if let Some(info) = info {
+        json["block_map"] = match efs.try_block_map(inode_id) {
+            Ok(map) => block_map_json(&map.unwrap_or_default(), &info),
+            Err(err) => json!({ "error": err.to_string() }),
+        };
+    }
+    Some(json)
+}
+
+fn inode_text(out: &mut String, efs: &EasyFileSystem, info: &InodeInfo) {
+    let _ = write!(out, "inode {}: {}, {} bytes", info.inode_id, type_name(info.type_), info.size);
+    if info.inline {
+        let _ = writeln!(out, ", inline");
+    } else {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "  direct: {:?}", info.direct);
+        if info.indirect1 != 0 || info.indirect2 != 0 {
+            let _ = writeln!(out, "  indirect1: {}, indirect2: {}", info.indirect1, info.indirect2);
+        }
+    }
+    match efs.try_dir_entries_of(info.inode_id) {
+        Ok(entries) => {
+            for (name, inode_id) in entries.unwrap_or_default() {
+                let _ = writeln!(out, "  {:?} -> {}", name, inode_id);
+            }
+        }
+        Err(err) => {
+            let _ = writeln!(out, "  entries: error: {}", err);
+        }
+    }
+}
+
+/// Dump an allocated inode as text, or what keeps it from being read
+fn checked_inode_text(out: &mut String, efs: &EasyFileSystem, inode_id: u32) {
+    match efs.try_inode_info(inode_id) {
+        Ok(Some(info)) => inode_text(out, efs, &info),
+        Ok(None) => {}
+        Err(err) => {
+            let _ = writeln!(out, "inode {}: error: {}", inode_id, err);
+        }
+    }
+}
+
+fn runs_line(out: &mut String, what: &str, runs: Result<Vec<(u32, u32)>, EfsError>) {
+    match runs {
+        Ok(runs) => {
+            let count = runs.iter().map(|&(_, len)| len).sum::<u32>();
+            let _ = writeln!(out, "{}: {} allocated: {}", what, count, runs_text(&runs));
+        }
+        Err(err) => {
+            let _ = writeln!(out, "{}: error: {}", what, err);
+        }
+    }
+}
+
+/// Dump the whole image on a device as text
+///
+/// An image which can't be opened is dumped down to its super block
+/// along with the reason.
+pub fn dump_text(device: &Arc<dyn BlockDevice>) -> String {
+    let mut out = String::new();
+    let sb = match EasyFileSystem::read_super_block(device) {
+        Ok(sb) => sb,
+        Err(err) => {
+            let _ = writeln!(out, "super block: error: {}", EfsError::from(err));
+            return out;
+        }
+    };
+    let _ = writeln!(out, "super block:");
+    let _ = writeln!(out, "  valid: {}", sb.valid);
+    let _ = writeln!(
+        out,
+        "  version: {}, features: compat {:#x}, incompat {:#x}, ro_compat {:#x}",
+        sb.version, sb.feature_compat, sb.feature_incompat, sb.feature_ro_compat
+    );
+    let _ = writeln!(out, "  total blocks: {}", sb.total_blocks);
+    let _ = writeln!(
+        out,
+        "  inode bitmap: {} blocks, inode area: {} blocks",
+        sb.inode_bitmap_blocks, sb.inode_area_blocks
+    );
+    let _ = writeln!(
+        out,
+        "  data bitmap: {} blocks, refcounts: {} blocks",
+        sb.data_bitmap_blocks, sb.refcount_blocks
+    );
+    let _ = writeln!(
+        out,
+        "  data area: {} blocks from block {}",
+        sb.data_area_blocks, sb.data_area_start
+    );
+    let _ = writeln!(out, "  snapshot inode: {}", sb.snapshot_inode);
+    let efs = match EasyFileSystem::try_open(Arc::clone(device)) {
+        Ok(efs) => efs,
+        Err(err) => {
+            let _ = writeln!(out, "error: {}", err);
+            return out;
+        }
+    };
+    runs_line(&mut out, "inode bitmap", efs.try_allocated_inodes());
+    runs_line(&mut out, "data bitmap", efs.try_allocated_data_blocks());
+    for inode_id in inode_ids(&efs) {
+        checked_inode_text(&mut out, &efs, inode_id);
+    }
+    out
+}
+
+/// Dump one inode along with its full block map as text, none if it is
+/// not allocated
+pub fn dump_inode_text(efs: &EasyFileSystem, inode_id: u32) -> Option<String> {
+    let mut out = String::new();
+    let info = match efs.try_inode_info(inode_id) {
+        Ok(info) => info?,
+        Err(err) => {
+            let _ = writeln!(out, "inode {}: error: {}", inode_id, err);
+            return Some(out);
+        }
+    };
+    inode_text(&mut out, efs, &info);
+    if info.inline {
+        let _ = writeln!(out, "  inline data: {:?}", String::from_utf8_lossy(&info.inline_data));
+        return Some(out);
+    }
+    let map = match efs.try_block_map(inode_id) {
+        Ok(map) => map.unwrap_or_default(),
+        Err(err) => {
+            let _ = writeln!(out, "  block map: error: {}", err);
+            return Some(out);
+        }
+    };
+    let _ = writeln!(out, "  data blocks: {:?}", map.data);
+    if !map.indirect1.is_empty() {
+        let _ = writeln!(out, "  indirect1 block {}: {:?}", info.indirect1, map.indirect1);
+    }
+    if !map.indirect2.is_empty() {
+        let _ = writeln!(out, "  indirect2 block {}:", info.indirect2);
+        for (block_id, entries) in map.indirect2.iter() {
+            let _ = writeln!(out, "    indirect1 block {}: {:?}", block_id, entries);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,203 @@
+//! Choosing the geometry of new images
+
+use super::{BLOCK_NUM, BLOCK_SZ};
+use clap::{Arg, ArgMatches};
+use easy_fs::{DiskInode, EasyFileSystem, Inode, MemBlockDevice, DIRENT_SZ, MAX_FILE_SIZE};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+/// Inodes per block of inode bitmap
+const INODES_PER_BITMAP_BLOCK: u32 = (BLOCK_SZ * 8) as u32;
+/// Slack of `--fit` images, in percent, unless told otherwise
+const DEFAULT_SLACK: &str = "10";
+
+/// An upper bound of what some content takes in an image
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Estimate {
+    pub data_blocks: u32,
+    pub inodes: u32,
+}
+
+impl Estimate {
+    /// Account for an inode of `size` bytes
+    pub fn add(&mut self, size: u64) {
+        self.inodes += 1;
+        // larger files don't fit in an image anyway
+        self.data_blocks += DiskInode::total_blocks(size.min(MAX_FILE_SIZE as u64) as u32);
+    }
+    /// Account for the directories holding the entries at `paths`, given
+    /// as '/'-separated paths from the root; the root is included and
+    /// missing parents are made
+    pub fn add_dirs<'a>(&mut self, paths: impl IntoIterator<Item = &'a str>) {
+        // number of entries of every directory, by path
+        let mut dirs: BTreeMap<&str, usize> = BTreeMap::new();
+        dirs.insert("", 0);
+        fn add_entry<'a>(dirs: &mut BTreeMap<&'a str, usize>, path: &'a str) {
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            if !dirs.contains_key(parent) {
+                dirs.insert(parent, 0);
+                add_entry(dirs, parent);
+            }
+            *dirs.get_mut(parent).unwrap() += 1;
+        }
+        for path in paths.into_iter().filter(|path| !path.is_empty()) {
+            add_entry(&mut dirs, path);
+        }
+        for entries in dirs.values() {
+            // "." and ".." come first
+            self.add(((entries + 2) * DIRENT_SZ) as u64);
+        }
+    }
+}
+
+/// The arguments choosing the geometry of a new image
+pub fn geometry_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .help("Path of the image to create"),
+        Arg::with_name("size")
+            .long("size")
+            .takes_value(true)
+            .conflicts_with_all(&["blocks", "fit"])
+            .help("Size of the image in bytes, with an optional K, M or G suffix"),
+        Arg::with_name("blocks")
+            .long("blocks")
+            .takes_value(true)
+            .conflicts_with("fit")
+            .help("Size of the image in blocks [default: 16384]"),
+        Arg::with_name("inodes")
+            .long("inodes")
+            .takes_value(true)
+            .help("Number of inodes, rounded up to a whole inode bitmap block"),
+        Arg::with_name("fit")
+            .long("fit")
+            .help("Make the smallest image holding the content plus some slack"),
+        Arg::with_name("slack")
+            .long("slack")
+            .takes_value(true)
+            .default_value(DEFAULT_SLACK)
+            .help("Room --fit leaves for more content, in percent"),
+    ]
+}
+
+fn invalid(what: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, what)
+}
+
+/// Parse a number of bytes with an optional K, M or G suffix
+pub fn parse_size(size: &str) -> Result<u64> {
+    let (digits, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        _ => return Err(invalid(format!("{}: unknown size unit", size))),
+    };
+    let value: u64 = digits.parse().map_err(|_| invalid(format!("{}: not a size", size)))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| invalid(format!("{}: size too large", size)))
+}
+
+fn parse_number(matches: &ArgMatches, name: &str) -> Result<Option<u32>> {
+    matches
+        .value_of(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| invalid(format!("--{} {}: not a number", name, value)))
+        })
+        .transpose()
+}
+
+/// Get the inode bitmap blocks for at least `inodes` inodes
+fn inode_bitmap_blocks(inodes: u32) -> u32 {
+    ((inodes + INODES_PER_BITMAP_BLOCK - 1) / INODES_PER_BITMAP_BLOCK).max(1)
+}
+
+/// Add `slack` percent to `n`
+fn with_slack(n: u32, slack: u32) -> u32 {
+    ((n as u64 * (100 + slack as u64) + 99) / 100) as u32
+}
+
+/// Create an image at `image_path` with the geometry the arguments ask
+/// for, and fill its root directory with `fill`
+///
+/// For `--fit`, the content is first packed in memory into an image sized
+/// after `estimate`, an upper bound, to measure what it really takes.
+pub fn build_image(
+    matches: &ArgMatches,
+    image_path: &str,
+    estimate: Estimate,
+    fill: impl Fn(&Arc<Inode>) -> Result<()>,
+) -> Result<Arc<EasyFileSystem>> {
+    let inodes = parse_number(matches, "inodes")?;
+    let (total_blocks, inode_bitmap_blocks) = if matches.is_present("fit") {
+        let slack = parse_number(matches, "slack")?.unwrap();
+        let trial_bitmap_blocks = inode_bitmap_blocks(estimate.inodes + 1);
+        let trial_blocks = EasyFileSystem::min_total_blocks(
+            trial_bitmap_blocks,
+            estimate.data_blocks,
+        );
+        let device = Arc::new(MemBlockDevice::new(trial_blocks as usize));
+        let efs = EasyFileSystem::create(device, trial_blocks, trial_bitmap_blocks);
+        fill(&EasyFileSystem::root_inode(&efs))?;
+        let usage = efs.usage();
+        let used_inodes = usage.inodes - usage.free_inodes;
+        let used_blocks = usage.data_blocks - usage.free_data_blocks;
+        let bitmap_blocks = inode_bitmap_blocks(inodes.unwrap_or_else(|| with_slack(used_inodes, slack)));
+        (
+            EasyFileSystem::min_total_blocks(bitmap_blocks, with_slack(used_blocks, slack)),
+            bitmap_blocks,
+        )
+    } else {
+        let total_blocks = match (matches.value_of("size"), parse_number(matches, "blocks")?) {
+            (Some(size), _) => {
+                let blocks = parse_size(size)? / BLOCK_SZ as u64;
+                u32::try_from(blocks).map_err(|_| invalid(format!("{}: size too large", size)))?
+            }
+            (None, Some(blocks)) => blocks,
+            (None, None) => BLOCK_NUM as u32,
+        };
+        (total_blocks, inode_bitmap_blocks(inodes.unwrap_or(1)))
+    };
+    let min_blocks = EasyFileSystem::min_total_blocks(inode_bitmap_blocks, 1);
+    if total_blocks < min_blocks {
+        return Err(invalid(format!(
+            "{} blocks are too few for {} inodes, {} at least",
+            total_blocks,
+            inode_bitmap_blocks * INODES_PER_BITMAP_BLOCK,
+            min_blocks
+        )));
+    }
+    let block_file = Arc::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image_path)?;
+        f.set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
+        f
+    });
+    let efs = EasyFileSystem::create(block_file, total_blocks, inode_bitmap_blocks);
+    fill(&EasyFileSystem::root_inode(&efs))?;
+    println!(
+        "{}: {} blocks ({} bytes), {} inodes",
+        image_path,
+        total_blocks,
+        total_blocks as u64 * BLOCK_SZ as u64,
+        inode_bitmap_blocks * INODES_PER_BITMAP_BLOCK
+    );
+    Ok(efs)
+}
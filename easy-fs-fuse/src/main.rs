@@ -1,41 +1,71 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+mod diff;
+mod dump;
+mod image;
+mod manifest;
+mod pack;
+mod tar;
+mod unpack;
+mod update;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{
+    BlockDevice,
+    DiscardMode,
+    EasyFileSystem,
+    EfsError,
+    EfsFile,
+    Fragmentation,
+    Inode,
+    InodeType,
+    MountOptions,
+    NAME_LENGTH_LIMIT,
+    EFS_MIN_VERSION,
+    EFS_VERSION,
+};
+#[cfg(test)]
+use easy_fs::MemBlockDevice;
+use diff::{diff_images, DiffKind};
+use dump::{dump_inode_json, dump_inode_text, dump_json, dump_text};
+use image::{build_image, geometry_args, Estimate};
+use pack::{estimate_tree, pack_tree, Filter, PackStats};
+use self::tar::{estimate_tar, export_tar, pack_tar};
+use unpack::unpack_tree;
+use update::{update_files, update_tree, UpdateStats};
 use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, BufWriter, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::Mutex;
 
 /// Use a block size of 512 bytes
 const BLOCK_SZ: usize = 512;
 const BLOCK_NUM: usize = 16384;
 
-/// Wrapper for turning a File into a BlockDevice
-struct BlockFile(Mutex<File>);
+/// The `-i`/`--image` argument of the subcommands working on an image
+fn image_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("image")
+        .short("i")
+        .long("image")
+        .takes_value(true)
+        .required(true)
+        .help("Path of the easy-fs image")
+}
 
-impl BlockDevice for BlockFile {
-    /// Read a block from file
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-    }
-    /// Write a block into file
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-    }
+/// The `--update` flag of the packers
+fn update_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("update")
+        .long("update")
+        .conflicts_with_all(&["size", "blocks", "inodes", "fit"])
+        .help("Update the image in place if it exists, rewriting only what changed")
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// A positional path argument
+fn path_arg<'a, 'b>(name: &'a str, index: u64, help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name(name).index(index).required(true).help(help)
 }
 
-/// Pack a directory into a easy-fs disk image
-fn easy_fs_pack() -> std::io::Result<()> {
-    let matches = App::new("EasyFileSystem packer")
+/// The command line of easy-fs-fuse
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
                 .short("s")
@@ -50,21 +80,278 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
-        .get_matches();
+        .args(&geometry_args())
+        .arg(update_arg())
+        .subcommand(
+            SubCommand::with_name("resize")
+                .about("Grow an existing easy-fs image in place")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("blocks")
+                        .short("b")
+                        .long("blocks")
+                        .takes_value(true)
+                        .required(true)
+                        .help("New total number of blocks"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Convert an existing easy-fs image in place to another format version")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("version")
+                        .short("v")
+                        .long("version")
+                        .takes_value(true)
+                        .help("Target format version, the latest one by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("defrag")
+                .about("Make the blocks of every file of an easy-fs image contiguous")
+                .arg(image_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("Pack a host directory tree as is into a new easy-fs image")
+                .arg(
+                    Arg::with_name("source")
+                        .short("s")
+                        .long("source")
+                        .takes_value(true)
+                        .required_unless("tar")
+                        .help("Host directory to pack"),
+                )
+                .arg(
+                    Arg::with_name("tar")
+                        .long("tar")
+                        .takes_value(true)
+                        .conflicts_with_all(&["source", "update"])
+                        .help("Tar archive to pack instead of a directory, - for standard input"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .takes_value(true)
+                        .required_unless("output")
+                        .help("Directory to write fs.img into (with backslash)"),
+                )
+                .args(&geometry_args())
+                .arg(update_arg())
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only pack paths matching this glob pattern"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Skip paths matching this glob pattern"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Build a new easy-fs image from a TOML or JSON manifest")
+                .arg(
+                    Arg::with_name("manifest")
+                        .short("m")
+                        .long("manifest")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Manifest listing the entries of the image, TOML if named *.toml"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .takes_value(true)
+                        .required_unless("output")
+                        .help("Directory to write fs.img into (with backslash)"),
+                )
+                .args(&geometry_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("Extract the tree of an easy-fs image to a host directory")
+                .arg(image_arg())
+                .arg(path_arg("dest", 1, "Host directory to extract into"))
+                .arg(Arg::with_name("path").index(2).help("Directory in the image, / by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the tree of an easy-fs image as a tar archive")
+                .arg(image_arg())
+                .arg(path_arg("archive", 1, "Tar archive to write, - for standard output"))
+                .arg(Arg::with_name("path").index(2).help("Directory in the image, / by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory of an easy-fs image")
+                .arg(image_arg())
+                .arg(Arg::with_name("path").index(1).help("Path in the image, / by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Print a file of an easy-fs image")
+                .arg(image_arg())
+                .arg(path_arg("path", 1, "Path in the image")),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Copy a host file into an easy-fs image")
+                .arg(image_arg())
+                .arg(path_arg("host", 1, "Path of the host file"))
+                .arg(path_arg("path", 2, "Path in the image, or a directory to copy into")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Copy a file of an easy-fs image to the host")
+                .arg(image_arg())
+                .arg(path_arg("path", 1, "Path in the image"))
+                .arg(path_arg("host", 2, "Path of the host file")),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory of an easy-fs image")
+                .arg(image_arg())
+                .arg(path_arg("path", 1, "Path in the image")),
+        )
+        .subcommand(
+            SubCommand::with_name("ln")
+                .about("Make a hard link to a file of an easy-fs image")
+                .arg(image_arg())
+                .arg(path_arg("target", 1, "Path of the existing file"))
+                .arg(path_arg("path", 2, "Path of the new link")),
+        )
+        .subcommand(
+            SubCommand::with_name("mkdir")
+                .about("Make a directory in an easy-fs image")
+                .arg(image_arg())
+                .arg(path_arg("path", 1, "Path in the image")),
+        )
+        .subcommand(
+            SubCommand::with_name("stat")
+                .about("Show the inode behind a path of an easy-fs image")
+                .arg(image_arg())
+                .arg(path_arg("path", 1, "Path in the image")),
+        )
+        .subcommand(
+            SubCommand::with_name("df")
+                .about("Show the space used in an easy-fs image")
+                .arg(image_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare the trees of two easy-fs images, exiting with 1 if they differ")
+                .arg(path_arg("old", 1, "Path of the old easy-fs image"))
+                .arg(path_arg("new", 2, "Path of the new easy-fs image"))
+                .arg(
+                    Arg::with_name("quiet")
+                        .short("q")
+                        .long("quiet")
+                        .help("Only tell whether the images differ, by the exit code"),
+                )
+                .arg(
+                    Arg::with_name("blocks")
+                        .long("blocks")
+                        .help("Show the bytes of the first differing block of modified files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Dump the on-disk structures of an easy-fs image")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print JSON instead of text"),
+                )
+                .arg(
+                    Arg::with_name("inode")
+                        .long("inode")
+                        .takes_value(true)
+                        .help("Dump only this inode, with its full block map"),
+                ),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+    match matches.subcommand() {
+        ("resize", Some(sub_matches)) => {
+            easy_fs_resize(sub_matches).expect("Error when resizing easy-fs!")
+        }
+        ("upgrade", Some(sub_matches)) => {
+            easy_fs_upgrade(sub_matches).expect("Error when upgrading easy-fs!")
+        }
+        ("defrag", Some(sub_matches)) => {
+            easy_fs_defrag(sub_matches).expect("Error when defragmenting easy-fs!")
+        }
+        ("pack", Some(sub_matches)) => {
+            easy_fs_pack_tree(sub_matches).expect("Error when packing easy-fs!")
+        }
+        ("build", Some(sub_matches)) => {
+            easy_fs_build(sub_matches).expect("Error when building easy-fs!")
+        }
+        ("unpack", Some(sub_matches)) => {
+            easy_fs_unpack(sub_matches).expect("Error when unpacking easy-fs!")
+        }
+        ("export", Some(sub_matches)) => {
+            easy_fs_export(sub_matches).expect("Error when exporting easy-fs!")
+        }
+        ("ls", Some(sub_matches)) => easy_fs_ls(sub_matches).expect("Error when listing easy-fs!"),
+        ("cat", Some(sub_matches)) => easy_fs_cat(sub_matches).expect("Error when reading easy-fs!"),
+        ("put", Some(sub_matches)) => {
+            easy_fs_put(sub_matches).expect("Error when copying into easy-fs!")
+        }
+        ("get", Some(sub_matches)) => {
+            easy_fs_get(sub_matches).expect("Error when copying out of easy-fs!")
+        }
+        ("rm", Some(sub_matches)) => easy_fs_rm(sub_matches).expect("Error when removing from easy-fs!"),
+        ("ln", Some(sub_matches)) => easy_fs_ln(sub_matches).expect("Error when linking in easy-fs!"),
+        ("mkdir", Some(sub_matches)) => {
+            easy_fs_mkdir(sub_matches).expect("Error when making a directory in easy-fs!")
+        }
+        ("stat", Some(sub_matches)) => easy_fs_stat(sub_matches).expect("Error when reading easy-fs!"),
+        ("df", Some(sub_matches)) => easy_fs_df(sub_matches).expect("Error when reading easy-fs!"),
+        ("diff", Some(sub_matches)) => {
+            // like diff(1): 0 if the same, 1 if different, 2 on trouble
+            let code = match easy_fs_diff(sub_matches) {
+                Ok(differ) => differ as i32,
+                Err(error) => {
+                    eprintln!("Error when comparing easy-fs: {}", error);
+                    2
+                }
+            };
+            std::process::exit(code);
+        }
+        ("dump", Some(sub_matches)) => easy_fs_dump(sub_matches).expect("Error when dumping easy-fs!"),
+        _ => easy_fs_pack(&matches).expect("Error when packing easy-fs!"),
+    }
+}
+
+/// Get the path of the image to create, fs.img in the target directory
+/// unless told otherwise
+fn output_path(matches: &ArgMatches) -> String {
+    match matches.value_of("output") {
+        Some(output) => String::from(output),
+        None => format!("{}{}", matches.value_of("target").unwrap(), "fs.img"),
+    }
+}
+
+/// Pack a directory into a easy-fs disk image
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let efs = EasyFileSystem::create(block_file.clone(), BLOCK_NUM as u32, 1);
-    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
         .into_iter()
@@ -74,82 +361,1297 @@ fn easy_fs_pack() -> std::io::Result<()> {
             name_with_ext
         })
         .collect();
-    for app in apps {
-        // load app data (elf) from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
-    }
+    let image_path = output_path(matches);
+    let efs = if matches.is_present("update") && Path::new(&image_path).exists() {
+        let efs = open_image(&image_path)?;
+        let files: Vec<_> = apps
+            .iter()
+            .map(|app| (app.clone(), PathBuf::from(format!("{}{}", target_path, app))))
+            .collect();
+        print_update_stats(&update_files(&EasyFileSystem::root_inode(&efs), &files)?, &image_path);
+        efs
+    } else {
+        let mut estimate = Estimate::default();
+        estimate.add((apps.len() * 32) as u64);
+        for app in apps.iter() {
+            estimate.add(std::fs::metadata(format!("{}{}", target_path, app))?.len());
+        }
+        build_image(matches, &image_path, estimate, |root_inode| {
+            for app in apps.iter() {
+                // load app data (elf) from host file system
+                let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
+                let mut all_data: Vec<u8> = Vec::new();
+                host_file.read_to_end(&mut all_data).unwrap();
+                // create a file in easy-fs
+                let inode = root_inode.create(app.as_str()).unwrap();
+                // write data to easy-fs
+                inode.write_at(0, all_data.as_slice());
+            }
+            Ok(())
+        })?
+    };
     // list apps
-    for app in root_inode.ls() {
+    for app in EasyFileSystem::root_inode(&efs).ls() {
         println!("{}", app);
     }
     Ok(())
 }
 
+/// Pack a host directory tree into a easy-fs disk image, keeping
+/// nested directories, full names and links
+fn easy_fs_pack_tree(matches: &ArgMatches) -> std::io::Result<()> {
+    let image_path = output_path(matches);
+    let patterns = |name: &str| -> Vec<String> {
+        matches.values_of(name).map_or(Vec::new(), |values| values.map(String::from).collect())
+    };
+    let filter = Filter::new(&patterns("include"), &patterns("exclude"))?;
+    if let Some(archive) = matches.value_of("tar") {
+        return easy_fs_pack_tar(matches, archive, &image_path, &filter);
+    }
+    let src_path = Path::new(matches.value_of("source").unwrap());
+    if matches.is_present("update") && Path::new(&image_path).exists() {
+        let efs = open_image(&image_path)?;
+        let stats = update_tree(src_path, &EasyFileSystem::root_inode(&efs), &filter)?;
+        print_update_stats(&stats, &image_path);
+        return Ok(());
+    }
+    let estimate = estimate_tree(src_path, &filter)?;
+    let stats = std::cell::Cell::new(None);
+    build_image(matches, &image_path, estimate, |root_inode| {
+        stats.set(Some(pack_tree(src_path, root_inode, &filter)?));
+        Ok(())
+    })?;
+    print_pack_stats(&stats.take().unwrap(), &image_path);
+    Ok(())
+}
+
+/// Pack a tar archive into a easy-fs disk image, without extracting it
+fn easy_fs_pack_tar(
+    matches: &ArgMatches,
+    archive: &str,
+    image_path: &str,
+    filter: &Filter,
+) -> std::io::Result<()> {
+    // standard input can be read once, but the archive is read once to
+    // estimate the image and once more per packing
+    let stdin = if archive == "-" {
+        let mut data = Vec::new();
+        std::io::stdin().read_to_end(&mut data)?;
+        Some(data)
+    } else {
+        None
+    };
+    let open = || -> std::io::Result<Box<dyn Read + '_>> {
+        Ok(match &stdin {
+            Some(data) => Box::new(data.as_slice()),
+            None => Box::new(BufReader::new(File::open(archive)?)),
+        })
+    };
+    let estimate = estimate_tar(open()?, filter)?;
+    let stats = std::cell::Cell::new(None);
+    build_image(matches, image_path, estimate, |root_inode| {
+        stats.set(Some(pack_tar(open()?, root_inode, filter)?));
+        Ok(())
+    })?;
+    print_pack_stats(&stats.take().unwrap(), image_path);
+    Ok(())
+}
+
+/// Report what packing an image holds
+fn print_pack_stats(stats: &PackStats, image_path: &str) {
+    println!(
+        "packed {} files ({} bytes), {} directories, {} symbolic links and {} hard links into {}",
+        stats.files, stats.bytes, stats.dirs, stats.symlinks, stats.hard_links, image_path
+    );
+    if stats.skipped > 0 {
+        println!("skipped {} special files", stats.skipped);
+    }
+}
+
+/// Build a easy-fs disk image from a manifest
+fn easy_fs_build(matches: &ArgMatches) -> std::io::Result<()> {
+    let manifest_path = Path::new(matches.value_of("manifest").unwrap());
+    let image_path = output_path(matches);
+    let manifest = manifest::load(manifest_path)?;
+    if !manifest.ignored.is_empty() {
+        println!("easy-fs keeps no {}, ignoring them", manifest.ignored.join(", "));
+    }
+    let stats = std::cell::Cell::new(None);
+    build_image(matches, &image_path, manifest.estimate()?, |root_inode| {
+        stats.set(Some(manifest.build(root_inode)?));
+        Ok(())
+    })?;
+    let stats = stats.take().unwrap();
+    println!(
+        "built {} files ({} bytes), {} directories, {} symbolic links and {} hard links into {}",
+        stats.files, stats.bytes, stats.dirs, stats.symlinks, stats.hard_links, image_path
+    );
+    Ok(())
+}
+
+/// Report what updating an image changed
+fn print_update_stats(stats: &UpdateStats, image_path: &str) {
+    println!(
+        "updated {}: {} added, {} replaced, {} removed, {} unchanged, {} bytes written",
+        image_path, stats.added, stats.replaced, stats.removed, stats.unchanged, stats.bytes
+    );
+    if stats.skipped > 0 {
+        println!("skipped {} special files", stats.skipped);
+    }
+}
+
+/// Grow an easy-fs disk image to a larger number of blocks
+fn easy_fs_resize(matches: &ArgMatches) -> std::io::Result<()> {
+    let image_path = matches.value_of("image").unwrap();
+    let new_blocks: u32 = matches
+        .value_of("blocks")
+        .unwrap()
+        .parse()
+        .expect("Number of blocks should be an integer");
+    let f = Arc::new(OpenOptions::new().read(true).write(true).open(image_path)?);
+    let efs = EasyFileSystem::try_open(f.clone())
+        .map_err(|err| path_error(ErrorKind::InvalidData, image_path, &err.to_string()))?;
+    let old_blocks = efs.super_block_info().total_blocks;
+    if new_blocks <= old_blocks {
+        println!("{} already holds {} blocks, only growing is supported", image_path, old_blocks);
+        return Ok(());
+    }
+    let old_len = f.metadata()?.len();
+    let new_len = new_blocks as u64 * BLOCK_SZ as u64;
+    if old_len < new_len {
+        f.set_len(new_len)?;
+    }
+    if !efs.grow(new_blocks) {
+        f.set_len(old_len)?;
+        return Err(path_error(
+            ErrorKind::Other,
+            image_path,
+            &format!("can't grow from {} to {} blocks, try a larger size", old_blocks, new_blocks),
+        ));
+    }
+    println!("resized {} from {} to {} blocks", image_path, old_blocks, new_blocks);
+    Ok(())
+}
+
+/// Convert an easy-fs disk image to another format version
+fn easy_fs_upgrade(matches: &ArgMatches) -> std::io::Result<()> {
+    let image_path = matches.value_of("image").unwrap();
+    let version: u32 = matches
+        .value_of("version")
+        .map(|version| version.parse().expect("Version should be an integer"))
+        .unwrap_or(EFS_VERSION);
+    let f = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(f);
+    let old_version = EasyFileSystem::version(&block_file)
+        .map_err(|err| path_error(ErrorKind::InvalidData, image_path, &err.to_string()))?;
+    if old_version == version {
+        println!("{} is already at version {}", image_path, version);
+        return Ok(());
+    }
+    match EasyFileSystem::set_version(&block_file, version) {
+        Ok(()) => println!("converted {} from version {} to {}", image_path, old_version, version),
+        Err(EfsError::Unsupported) => println!(
+            "{} can't be converted from version {} to {} (supported: {}..={})",
+            image_path, old_version, version, EFS_MIN_VERSION, EFS_VERSION
+        ),
+        Err(err) => return Err(path_error(ErrorKind::Other, image_path, &err.to_string())),
+    }
+    Ok(())
+}
+
+/// Print how fragmented an easy-fs image is
+fn print_fragmentation(when: &str, fragmentation: &Fragmentation) {
+    println!(
+        "{}: {} of {} files fragmented, {} extents over {} blocks",
+        when,
+        fragmentation.fragmented,
+        fragmentation.files,
+        fragmentation.extents,
+        fragmentation.blocks
+    );
+}
+
+/// Relocate the blocks of every file of an easy-fs disk image into contiguous runs
+fn easy_fs_defrag(matches: &ArgMatches) -> std::io::Result<()> {
+    let image_path = matches.value_of("image").unwrap();
+    let f = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let block_file = Arc::new(f);
+    // blocks moved away from become holes in the image where the host
+    // can punch them
+    let discard = if block_file.can_discard() { DiscardMode::Discard } else { DiscardMode::Zero };
+    let efs = EasyFileSystem::open_with_options(block_file, MountOptions { discard });
+    print_fragmentation("before", &efs.fragmentation());
+    let moved = efs.defrag();
+    print_fragmentation("after", &efs.fragmentation());
+    println!("moved {} blocks in {}", moved, image_path);
+    Ok(())
+}
+
+/// Open an existing easy-fs disk image
+fn open_image(image_path: &str) -> std::io::Result<Arc<EasyFileSystem>> {
+    let f = OpenOptions::new().read(true).write(true).open(image_path)?;
+    EasyFileSystem::try_open(Arc::new(f))
+        .map_err(|err| path_error(ErrorKind::InvalidData, image_path, &err.to_string()))
+}
+
+/// An error about a path of an image
+fn path_error(kind: ErrorKind, path: &str, what: &str) -> std::io::Error {
+    std::io::Error::new(kind, format!("{}: {}", path, what))
+}
+
+/// Find the inode at a '/'-separated path from the root of an image
+fn find_path(root_inode: &Arc<Inode>, path: &str) -> std::io::Result<Arc<Inode>> {
+    let mut inode = Arc::clone(root_inode);
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if inode.inode_type() != InodeType::Directory {
+            return Err(path_error(ErrorKind::NotFound, path, "not a directory"));
+        }
+        inode = inode
+            .find(name)
+            .ok_or_else(|| path_error(ErrorKind::NotFound, path, "no such file or directory"))?;
+    }
+    Ok(inode)
+}
+
+/// Find the directory holding the last component of a path,
+/// returned along with it
+fn find_parent<'a>(root_inode: &Arc<Inode>, path: &'a str) -> std::io::Result<(Arc<Inode>, &'a str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = trimmed.rsplit_once('/').unwrap_or(("", trimmed));
+    if name.is_empty() {
+        return Err(path_error(ErrorKind::InvalidInput, path, "no file name"));
+    }
+    if name.len() > NAME_LENGTH_LIMIT {
+        return Err(path_error(ErrorKind::InvalidInput, path, "file name too long"));
+    }
+    let dir = find_path(root_inode, dir)?;
+    if dir.inode_type() != InodeType::Directory {
+        return Err(path_error(ErrorKind::NotFound, path, "not a directory"));
+    }
+    Ok((dir, name))
+}
+
+/// Read a whole file of an image
+#[cfg(test)]
+fn read_all(inode: &Inode) -> Vec<u8> {
+    let mut data = vec![0u8; inode.size()];
+    let len = inode.read_at(0, &mut data);
+    data.truncate(len);
+    data
+}
+
+/// Create an empty in-memory image of `blocks` blocks
+#[cfg(test)]
+fn fresh_fs(blocks: usize) -> (Arc<MemBlockDevice>, Arc<EasyFileSystem>) {
+    let device = Arc::new(MemBlockDevice::new(blocks));
+    let efs = EasyFileSystem::create(device.clone(), blocks as u32, 1);
+    (device, efs)
+}
+
+/// List a directory of an easy-fs disk image, or show a single file
+fn easy_fs_ls(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let path = matches.value_of("path").unwrap_or("/");
+    let inode = find_path(&root_inode, path)?;
+    let print = |name: &str, inode: &Inode| {
+        let (type_, target) = match inode.inode_type() {
+            InodeType::Directory => ('d', String::new()),
+            InodeType::File => ('-', String::new()),
+            InodeType::SymLink => ('l', format!(" -> {}", inode.read_link().unwrap_or_default())),
+        };
+        println!(
+            "{} {:>6} {:>10} {}{}",
+            type_,
+            inode.get_inode_number(),
+            inode.size(),
+            name,
+            target
+        );
+    };
+    match inode.inode_type() {
+        InodeType::Directory => {
+            for (name, inode_id, _) in inode.read_dir(0) {
+                print(&name, &EasyFileSystem::get_inode(&efs, inode_id));
+            }
+        }
+        _ => print(path, &inode),
+    }
+    Ok(())
+}
+
+/// Print a file of an easy-fs disk image
+fn easy_fs_cat(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let inode = find_path(&EasyFileSystem::root_inode(&efs), path)?;
+    if inode.inode_type() != InodeType::File {
+        return Err(path_error(ErrorKind::InvalidInput, path, "not a regular file"));
+    }
+    std::io::copy(&mut EfsFile::new(inode), &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+/// Copy a host file into an easy-fs disk image, replacing the
+/// contents of the file if it exists; anything else in the way is left
+/// as is
+fn easy_fs_put(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let host_path = matches.value_of("host").unwrap();
+    let mut path = String::from(matches.value_of("path").unwrap());
+    let mut host_file = File::open(host_path)?;
+    if let Ok(dir) = find_path(&root_inode, &path) {
+        if dir.inode_type() == InodeType::Directory {
+            let file_name = std::path::Path::new(host_path).file_name().unwrap();
+            path = format!("{}/{}", path.trim_end_matches('/'), file_name.to_string_lossy());
+        }
+    }
+    let (dir, name) = find_parent(&root_inode, &path)?;
+    let inode = match dir.find(name) {
+        Some(inode) => match inode.inode_type() {
+            InodeType::File => {
+                inode.clear();
+                inode
+            }
+            InodeType::Directory => {
+                return Err(path_error(ErrorKind::InvalidInput, &path, "is a directory"));
+            }
+            InodeType::SymLink => {
+                return Err(path_error(ErrorKind::InvalidInput, &path, "is a symbolic link"));
+            }
+        },
+        None => dir
+            .create(name)
+            .ok_or_else(|| path_error(ErrorKind::AlreadyExists, &path, "file exists"))?,
+    };
+    std::io::copy(&mut host_file, &mut EfsFile::new(inode))?;
+    Ok(())
+}
+
+/// Copy a file of an easy-fs disk image to the host
+fn easy_fs_get(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let inode = find_path(&EasyFileSystem::root_inode(&efs), path)?;
+    if inode.inode_type() != InodeType::File {
+        return Err(path_error(ErrorKind::InvalidInput, path, "not a regular file"));
+    }
+    let mut host_file = File::create(matches.value_of("host").unwrap())?;
+    std::io::copy(&mut EfsFile::new(inode), &mut host_file)?;
+    Ok(())
+}
+
+/// Remove a file or an empty directory of an easy-fs disk image
+fn easy_fs_rm(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let (dir, name) = find_parent(&EasyFileSystem::root_inode(&efs), path)?;
+    if name == "." || name == ".." {
+        return Err(path_error(ErrorKind::InvalidInput, path, "refusing to remove '.' or '..'"));
+    }
+    let inode = dir
+        .find(name)
+        .ok_or_else(|| path_error(ErrorKind::NotFound, path, "no such file or directory"))?;
+    if dir.unlink(name) == 0 {
+        return Ok(());
+    }
+    // only a directory with entries is kept, otherwise the entry is gone
+    match inode.inode_type() {
+        InodeType::Directory => Err(path_error(ErrorKind::InvalidInput, path, "directory not empty")),
+        _ => Err(path_error(ErrorKind::NotFound, path, "no such file or directory")),
+    }
+}
+
+/// Make a hard link to a file of an easy-fs disk image
+fn easy_fs_ln(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let target = find_path(&root_inode, matches.value_of("target").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let (dir, name) = find_parent(&root_inode, path)?;
+    if dir.link(name, &target) != 0 {
+        return Err(path_error(
+            ErrorKind::InvalidInput,
+            path,
+            "file exists or target is a directory",
+        ));
+    }
+    Ok(())
+}
+
+/// Make a directory in an easy-fs disk image
+fn easy_fs_mkdir(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let (dir, name) = find_parent(&EasyFileSystem::root_inode(&efs), path)?;
+    if dir.create_dir(name).is_none() {
+        return Err(path_error(ErrorKind::AlreadyExists, path, "file exists"));
+    }
+    Ok(())
+}
+
+/// Extract a directory of an easy-fs disk image to the host
+fn easy_fs_unpack(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap_or("/");
+    let dir = find_path(&EasyFileSystem::root_inode(&efs), path)?;
+    if dir.inode_type() != InodeType::Directory {
+        return Err(path_error(ErrorKind::InvalidInput, path, "not a directory"));
+    }
+    let dest = matches.value_of("dest").unwrap();
+    let stats = unpack_tree(&efs, &dir, Path::new(dest))?;
+    println!(
+        "unpacked {} files ({} bytes), {} directories, {} symbolic links and {} hard links into {}",
+        stats.files, stats.bytes, stats.dirs, stats.symlinks, stats.hard_links, dest
+    );
+    Ok(())
+}
+
+/// Write the tree of an easy-fs disk image as a tar archive
+fn easy_fs_export(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap_or("/");
+    let dir = find_path(&EasyFileSystem::root_inode(&efs), path)?;
+    if dir.inode_type() != InodeType::Directory {
+        return Err(path_error(ErrorKind::InvalidInput, path, "not a directory"));
+    }
+    let archive = matches.value_of("archive").unwrap();
+    if archive == "-" {
+        export_tar(&efs, &dir, BufWriter::new(std::io::stdout().lock()))?;
+        return Ok(());
+    }
+    let stats = export_tar(&efs, &dir, BufWriter::new(File::create(archive)?))?;
+    println!(
+        "exported {} files ({} bytes), {} directories, {} symbolic links and {} hard links into {}",
+        stats.files, stats.bytes, stats.dirs, stats.symlinks, stats.hard_links, archive
+    );
+    Ok(())
+}
+
+/// Show the inode behind a path of an easy-fs disk image
+fn easy_fs_stat(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let inode = find_path(&EasyFileSystem::root_inode(&efs), path)?;
+    let type_ = match inode.inode_type() {
+        InodeType::Directory => "directory",
+        InodeType::File => "file",
+        InodeType::SymLink => "symbolic link",
+    };
+    let blocks = inode.data_blocks();
+    println!("path: {}", path);
+    println!("inode: {}", inode.get_inode_number());
+    println!("type: {}", type_);
+    if let Some(target) = inode.read_link() {
+        println!("target: {}", target);
+    }
+    println!("size: {}", inode.size());
+    println!("links: {}", inode.links());
+    if blocks.is_empty() {
+        println!("blocks: 0 (inline)");
+    } else {
+        println!("blocks: {} ({}..={})", blocks.len(), blocks[0], blocks[blocks.len() - 1]);
+    }
+    Ok(())
+}
+
+/// Show the space used in an easy-fs disk image
+fn easy_fs_df(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let usage = efs.usage();
+    println!(
+        "blocks: {} total, {} data, {} used, {} free",
+        usage.total_blocks,
+        usage.data_blocks,
+        usage.data_blocks - usage.free_data_blocks,
+        usage.free_data_blocks
+    );
+    println!(
+        "inodes: {} total, {} used, {} free",
+        usage.inodes,
+        usage.inodes - usage.free_inodes,
+        usage.free_inodes
+    );
+    Ok(())
+}
+
+/// Compare two easy-fs disk images, returning whether they differ
+fn easy_fs_diff(matches: &ArgMatches) -> std::io::Result<bool> {
+    let old = open_image(matches.value_of("old").unwrap())?;
+    let new = open_image(matches.value_of("new").unwrap())?;
+    let differences = diff_images(&old, &new);
+    if !matches.is_present("quiet") {
+        for difference in differences.iter() {
+            println!("{}", difference);
+            if let DiffKind::Modified { first_block: Some(block), .. } = &difference.kind {
+                if matches.is_present("blocks") {
+                    println!("{}", block.describe());
+                }
+            }
+        }
+    }
+    Ok(!differences.is_empty())
+}
+
+/// Dump the on-disk structures of an easy-fs disk image
+fn easy_fs_dump(matches: &ArgMatches) -> std::io::Result<()> {
+    let image_path = matches.value_of("image").unwrap();
+    let json = matches.is_present("json");
+    match matches.value_of("inode") {
+        Some(inode) => {
+            let efs = open_image(image_path)?;
+            let inode_id: u32 = inode
+                .parse()
+                .map_err(|_| path_error(ErrorKind::InvalidInput, inode, "not an inode number"))?;
+            let not_found = || path_error(ErrorKind::NotFound, inode, "no such inode");
+            if json {
+                println!("{:#}", dump_inode_json(&efs, inode_id).ok_or_else(not_found)?);
+            } else {
+                print!("{}", dump_inode_text(&efs, inode_id).ok_or_else(not_found)?);
+            }
+        }
+        None => {
+            // damaged images are dumped as far as they can be read
+            let f = OpenOptions::new().read(true).write(true).open(image_path)?;
+            let device: Arc<dyn BlockDevice> = Arc::new(f);
+            if json {
+                println!("{:#}", dump_json(&device));
+            } else {
+                print!("{}", dump_text(&device));
+            }
+        }
+    }
+    Ok(())
+}
+
 #[test]
-fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
+fn efs_commands_test() -> std::io::Result<()> {
+    let image = "target/fs_commands.img";
+    {
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("target/fs.img")?;
-        f.set_len(BLOCK_NUM * BLOCK_SZ).unwrap();
-        f
-    })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
-    let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
-        println!("{}", name);
-    }
-    let filea = root_inode.find("filea").unwrap();
-    let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
-    //let mut buffer = [0u8; BLOCK_SZ];
-    let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
-    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
-
-    let mut random_str_test = |len: usize| {
-        filea.clear();
-        assert_eq!(filea.read_at(0, &mut buffer), 0,);
-        let mut str = String::new();
-        use rand;
-        // random digit
-        for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
-        }
-        filea.write_at(0, str.as_bytes());
-        let mut read_buffer = [0u8; 127];
-        let mut offset = 0usize;
-        let mut read_str = String::new();
-        loop {
-            let len = filea.read_at(offset, &mut read_buffer);
-            if len == 0 {
-                break;
-            }
-            offset += len;
-            read_str.push_str(core::str::from_utf8(&read_buffer[..len]).unwrap());
+            .open(image)?;
+        f.set_len((4096 * BLOCK_SZ) as u64).unwrap();
+        EasyFileSystem::create(Arc::new(f), 4096, 1);
+    }
+    let run = |args: &[&str]| {
+        let matches = app().get_matches_from(["easy-fs-fuse"].iter().chain(args.iter()));
+        let (name, sub_matches) = matches.subcommand();
+        let sub_matches = sub_matches.unwrap();
+        match name {
+            "ls" => easy_fs_ls(sub_matches),
+            "cat" => easy_fs_cat(sub_matches),
+            "put" => easy_fs_put(sub_matches),
+            "get" => easy_fs_get(sub_matches),
+            "rm" => easy_fs_rm(sub_matches),
+            "ln" => easy_fs_ln(sub_matches),
+            "mkdir" => easy_fs_mkdir(sub_matches),
+            "stat" => easy_fs_stat(sub_matches),
+            "df" => easy_fs_df(sub_matches),
+            _ => unreachable!(),
         }
-        assert_eq!(str, read_str);
     };
+    {
+        let f = OpenOptions::new().read(true).write(true).open(image)?;
+        let efs = EasyFileSystem::open(Arc::new(f));
+        EasyFileSystem::root_inode(&efs).symlink("symlink", "/dir").unwrap();
+    }
+    let host = "target/fs_commands.txt";
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    std::fs::write(host, &data)?;
+    run(&["mkdir", "-i", image, "/dir"])?;
+    run(&["put", "-i", image, host, "/dir"])?;
+    run(&["put", "-i", image, host, "/dir/copy"])?;
+    run(&["ln", "-i", image, "/dir/copy", "/link"])?;
+    run(&["rm", "-i", image, "/dir/copy"])?;
+    run(&["ls", "-i", image, "/dir"])?;
+    run(&["stat", "-i", image, "/link"])?;
+    run(&["df", "-i", image])?;
+    // errors are reported, not panics
+    assert_eq!(run(&["cat", "-i", image, "/dir/copy"]).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(run(&["mkdir", "-i", image, "/dir"]).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert!(run(&["ln", "-i", image, "/dir", "/dir2"]).is_err());
+    let err = run(&["rm", "-i", image, "/dir"]).unwrap_err();
+    assert_eq!(err.to_string(), "/dir: directory not empty");
+    assert_eq!(run(&["rm", "-i", image, "/dir/."]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(run(&["rm", "-i", image, "/none"]).unwrap_err().kind(), ErrorKind::NotFound);
+    // only regular files are replaced
+    let err = run(&["put", "-i", image, host, "/symlink"]).unwrap_err();
+    assert_eq!(err.to_string(), "/symlink: is a symbolic link");
+    run(&["get", "-i", image, "/link", host])?;
+    assert_eq!(std::fs::read(host)?, data);
+    // the image holds what the commands did
+    let f = OpenOptions::new().read(true).write(true).open(image)?;
+    let efs = EasyFileSystem::open(Arc::new(f));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.find("dir").unwrap();
+    assert_eq!(dir.ls(), [".", "..", "fs_commands.txt"]);
+    assert_eq!(root_inode.find("symlink").unwrap().read_link().unwrap(), "/dir");
+    let link = root_inode.find("link").unwrap();
+    assert_eq!(link.size(), data.len());
+    assert_eq!(link.links(), 1);
+    let free = efs.usage().free_data_blocks;
+    assert_eq!(root_inode.unlink("link"), 0);
+    assert_eq!(efs.usage().free_data_blocks, free);
+    drop(link);
+    assert!(efs.usage().free_data_blocks > free);
+    Ok(())
+}
 
-    random_str_test(4 * BLOCK_SZ);
-    random_str_test(8 * BLOCK_SZ + BLOCK_SZ / 2);
-    random_str_test(100 * BLOCK_SZ);
-    random_str_test(70 * BLOCK_SZ + BLOCK_SZ / 7);
-    random_str_test((12 + 128) * BLOCK_SZ);
-    random_str_test(400 * BLOCK_SZ);
-    random_str_test(1000 * BLOCK_SZ);
-    random_str_test(2000 * BLOCK_SZ);
+#[test]
+fn efs_resize_test() -> std::io::Result<()> {
+    let image = "target/fs_resize.img";
+    // a full group of data blocks, on a host file longer than the image
+    let total = 1026 + 4113;
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)?;
+        f.set_len(((total + 100) * BLOCK_SZ) as u64)?;
+        EasyFileSystem::create(Arc::new(f), total as u32, 1);
+    }
+    let resize = |blocks: usize| {
+        let blocks = blocks.to_string();
+        let args = ["easy-fs-fuse", "resize", "-i", image, "-b", blocks.as_str()];
+        let matches = app().get_matches_from(args.iter());
+        easy_fs_resize(matches.subcommand_matches("resize").unwrap())
+    };
+    let blocks = || {
+        let f = File::open(image).unwrap();
+        EasyFileSystem::open(Arc::new(f)).super_block_info().total_blocks as usize
+    };
+    // one more block would shrink the data area
+    assert!(resize(total + 1).is_err());
+    assert_eq!(std::fs::metadata(image)?.len(), ((total + 100) * BLOCK_SZ) as u64);
+    assert_eq!(blocks(), total);
+    // the size comes from the super block, not from the host file
+    resize(total + 50)?;
+    assert_eq!(blocks(), total + 50);
+    resize(total + 200)?;
+    assert_eq!(std::fs::metadata(image)?.len(), ((total + 200) * BLOCK_SZ) as u64);
+    assert_eq!(blocks(), total + 200);
+    Ok(())
+}
 
+#[test]
+fn efs_pack_tree_test() -> std::io::Result<()> {
+    use std::os::unix::fs::symlink;
+    let glob_match = |pattern: &str, path: &str| Filter::new(&[""; 0], &[pattern]).unwrap().is_excluded(path);
+    assert!(glob_match("*.o", "a/b/c.o") && !glob_match("*.o", "a/b/c.out"));
+    assert!(glob_match("bin/*", "bin/ls") && !glob_match("bin/*", "bin/sub/ls"));
+    assert!(glob_match("bin/**", "bin/sub/ls") && glob_match("**/ls", "ls"));
+    assert!(glob_match("/etc/?s", "etc/fs") && !glob_match("etc/?", "etc/fs"));
+    assert_eq!(Filter::new(&["a["], &[""; 0]).err().unwrap().kind(), ErrorKind::InvalidInput);
+    let src = Path::new("target/pack_src");
+    let _ = std::fs::remove_dir_all(src);
+    std::fs::create_dir_all(src.join("usr/lib/deep"))?;
+    std::fs::create_dir_all(src.join("build"))?;
+    std::fs::write(src.join("hello.tar.gz"), b"not really")?;
+    std::fs::write(src.join("usr/lib/deep/data.bin"), vec![7u8; 5000])?;
+    std::fs::write(src.join("usr/lib/main.o"), b"object")?;
+    std::fs::write(src.join("build/out.o"), b"object")?;
+    std::fs::hard_link(src.join("hello.tar.gz"), src.join("usr/hello.link"))?;
+    symlink("lib/deep/data.bin", src.join("usr/data"))?;
+    let pack = |filter: &Filter| {
+        let (_, efs) = fresh_fs(4096);
+        let stats = pack_tree(src, &EasyFileSystem::root_inode(&efs), filter).unwrap();
+        (efs, stats)
+    };
+    let (efs, stats) = pack(&Filter::new(&[""; 0], &["*.o"])?);
+    assert_eq!((stats.files, stats.dirs, stats.symlinks, stats.hard_links), (2, 4, 1, 1));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), ["build", "hello.tar.gz", "usr"]);
+    let usr = root_inode.find("usr").unwrap();
+    assert_eq!(usr.ls(), [".", "..", "data", "hello.link", "lib"]);
+    let hello = root_inode.find("hello.tar.gz").unwrap();
+    assert_eq!(usr.find("hello.link").unwrap().get_inode_number(), hello.get_inode_number());
+    assert_eq!(hello.links(), 2);
+    let link = usr.find("data").unwrap();
+    assert_eq!(link.inode_type(), InodeType::SymLink);
+    assert_eq!(link.read_link().unwrap(), "lib/deep/data.bin");
+    let data = usr.find("lib").unwrap().find("deep").unwrap().find("data.bin").unwrap();
+    let mut buffer = vec![0u8; 6000];
+    assert_eq!(data.read_at(0, &mut buffer), 5000);
+    assert!(buffer[..5000].iter().all(|&byte| byte == 7));
+    // only what is included is packed, along with its directories
+    let (efs, stats) = pack(&Filter::new(&["usr/lib/**"], &["*.o"])?);
+    assert_eq!((stats.files, stats.dirs), (1, 3));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), ["usr"]);
+    assert_eq!(root_inode.find("usr").unwrap().ls(), [".", "..", "lib"]);
+    // names easy-fs can't hold are refused
+    std::fs::write(src.join("a_name_longer_than_the_limit"), b"")?;
+    let (_, efs) = fresh_fs(4096);
+    let err = pack_tree(src, &EasyFileSystem::root_inode(&efs), &Filter::default()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    std::fs::remove_file(src.join("a_name_longer_than_the_limit"))?;
+    // names the image already holds are refused, naming the host path
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("build").unwrap();
+    let err = pack_tree(src, &root_inode, &Filter::default()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    assert!(err.to_string().contains("pack_src/build"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn efs_geometry_test() -> std::io::Result<()> {
+    use image::parse_size;
+    assert_eq!(parse_size("4096")?, 4096);
+    assert_eq!(parse_size("8M")?, 8 << 20);
+    assert_eq!(parse_size("2kib")?, 2048);
+    assert!(parse_size("1T").is_err() && parse_size("M").is_err());
+    let src = Path::new("target/geometry_src");
+    let _ = std::fs::remove_dir_all(src);
+    std::fs::create_dir_all(src.join("dir"))?;
+    std::fs::write(src.join("big"), vec![1u8; 300 * 1024])?;
+    for i in 0..50 {
+        std::fs::write(src.join("dir").join(format!("file{}", i)), vec![i as u8; 1000])?;
+    }
+    let pack = |args: &[&str]| {
+        let mut all = vec!["easy-fs-fuse", "pack", "-s", "target/geometry_src"];
+        all.extend_from_slice(args);
+        let matches = app().get_matches_from(all);
+        easy_fs_pack_tree(matches.subcommand_matches("pack").unwrap())
+    };
+    let open = |image: &str| {
+        let f = OpenOptions::new().read(true).write(true).open(image).unwrap();
+        let len = f.metadata().unwrap().len();
+        (len, EasyFileSystem::open(Arc::new(f)))
+    };
+    // the smallest image holding the tree
+    pack(&["-o", "target/fs_fit.img", "--fit", "--slack", "0"])?;
+    let (len, efs) = open("target/fs_fit.img");
+    let usage = efs.usage();
+    assert_eq!(usage.total_blocks as u64 * BLOCK_SZ as u64, len);
+    assert_eq!(len, EasyFileSystem::min_total_blocks(1, usage.data_blocks) as u64 * BLOCK_SZ as u64);
+    assert_eq!(usage.free_data_blocks, 0);
+    let big = EasyFileSystem::root_inode(&efs).find("big").unwrap();
+    assert_eq!(big.size(), 300 * 1024);
+    // slack is left for more content
+    pack(&["-o", "target/fs_fit.img", "--fit", "--slack", "50"])?;
+    let (_, efs) = open("target/fs_fit.img");
+    let usage = efs.usage();
+    let used = usage.data_blocks - usage.free_data_blocks;
+    assert!(usage.free_data_blocks >= used / 2);
+    // explicit sizes and inode counts
+    pack(&["-o", "target/fs_geometry.img", "--size", "2M", "--inodes", "5000"])?;
+    let (len, efs) = open("target/fs_geometry.img");
+    assert_eq!(len, 2 << 20);
+    assert_eq!(efs.usage().inodes, 8192);
+    pack(&["-o", "target/fs_geometry.img", "--blocks", "3000"])?;
+    assert_eq!(open("target/fs_geometry.img").0, 3000 * BLOCK_SZ as u64);
+    let err = pack(&["-o", "target/fs_geometry.img", "--blocks", "100"]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    // content that doesn't fit is an error
+    let err = pack(&["-o", "target/fs_geometry.img", "--blocks", "1200"]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    Ok(())
+}
+
+#[test]
+fn efs_unpack_test() -> std::io::Result<()> {
+    use std::os::unix::fs::{symlink, MetadataExt};
+    let src = Path::new("target/unpack_src");
+    let dest = Path::new("target/unpack_dest");
+    let _ = std::fs::remove_dir_all(src);
+    let _ = std::fs::remove_dir_all(dest);
+    std::fs::create_dir_all(src.join("a/b/empty"))?;
+    std::fs::write(src.join("a/b/data"), vec![3u8; 20000])?;
+    std::fs::write(src.join("tiny.txt"), b"tiny")?;
+    std::fs::write(src.join("empty"), b"")?;
+    std::fs::hard_link(src.join("a/b/data"), src.join("a/data.link"))?;
+    symlink("../tiny.txt", src.join("a/tiny"))?;
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    pack_tree(src, &root_inode, &Filter::default())?;
+    // stale host files are replaced
+    std::fs::create_dir_all(dest)?;
+    std::fs::write(dest.join("tiny.txt"), b"stale contents")?;
+    let stats = unpack_tree(&efs, &root_inode, dest)?;
+    assert_eq!((stats.files, stats.dirs, stats.symlinks, stats.hard_links), (3, 3, 1, 1));
+    let read = |root: &Path, path: &str| std::fs::read(root.join(path)).unwrap();
+    for path in ["a/b/data", "a/data.link", "tiny.txt", "empty", "a/tiny"] {
+        assert_eq!(read(dest, path), read(src, path), "{}", path);
+    }
+    assert!(dest.join("a/b/empty").is_dir());
+    assert_eq!(std::fs::read_link(dest.join("a/tiny"))?, Path::new("../tiny.txt"));
+    let data = std::fs::metadata(dest.join("a/b/data"))?;
+    let link = std::fs::metadata(dest.join("a/data.link"))?;
+    assert_eq!((data.ino(), data.nlink()), (link.ino(), 2));
+
+    // host symbolic links in the way are replaced, not followed
+    let outside = Path::new("target/unpack_outside");
+    let _ = std::fs::remove_dir_all(outside);
+    let _ = std::fs::remove_dir_all(dest);
+    std::fs::create_dir_all(outside)?;
+    std::fs::write(outside.join("victim"), b"victim")?;
+    std::fs::create_dir_all(dest)?;
+    symlink(std::fs::canonicalize(outside)?, dest.join("a"))?;
+    symlink(std::fs::canonicalize(outside.join("victim"))?, dest.join("empty"))?;
+    unpack_tree(&efs, &root_inode, dest)?;
+    assert!(std::fs::symlink_metadata(dest.join("a"))?.is_dir());
+    assert!(std::fs::symlink_metadata(dest.join("empty"))?.is_file());
+    assert_eq!(std::fs::read_dir(outside)?.count(), 1);
+    assert_eq!(std::fs::read(outside.join("victim"))?, b"victim");
+    drop(root_inode);
+    drop(efs);
+
+    // damaged trees are errors, not panics or endless walks
+    let (device, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("dup_a").unwrap();
+    root_inode.create("dup_b").unwrap();
+    root_inode.create_dir("dir").unwrap().create("loop").unwrap();
+    efs.sync().unwrap();
+    drop((root_inode, efs));
+    let image = device.to_image();
+    let find = |image: &[u8], name: &[u8]| image.windows(name.len()).position(|w| w == name).unwrap();
+    let unpack = |image: &[u8]| {
+        let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_image(image)));
+        let _ = std::fs::remove_dir_all(dest);
+        unpack_tree(&efs, &EasyFileSystem::root_inode(&efs), dest)
+    };
+    let mut bad = image.clone();
+    let at = find(&bad, b"dup_b\0");
+    bad[at + 4] = b'a';
+    let err = unpack(&bad).unwrap_err();
+    assert!(err.to_string().contains("found twice"), "{}", err);
+    let mut bad = image.clone();
+    let at = find(&bad, b"loop\0");
+    bad[at + 28..at + 32].copy_from_slice(&0u32.to_le_bytes());
+    let err = unpack(&bad).unwrap_err();
+    assert!(err.to_string().contains("cycle"), "{}", err);
+    let mut bad = image;
+    let at = find(&bad, b"dup_a\0");
+    bad[at + 28..at + 32].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(unpack(&bad).unwrap_err().kind(), ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn efs_dump_test() {
+    let (device, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.create_dir("dir").unwrap();
+    let big = dir.create("big").unwrap();
+    // enough blocks to need indirect2
+    big.write_at(0, &vec![7u8; 200 * BLOCK_SZ]);
+    root_inode.create("tiny").unwrap().write_at(0, b"tiny");
+    let big_id = big.get_inode_number() as u32;
+    let info = efs.inode_info(big_id).unwrap();
+    assert!(!info.inline && info.direct.len() == 28);
+    assert!(info.indirect1 != 0 && info.indirect2 != 0);
+    let map = efs.block_map(big_id).unwrap();
+    assert_eq!(map.data.len(), 200);
+    assert_eq!(map.data[..28], info.direct[..]);
+    assert_eq!(map.data[28..28 + 128], map.indirect1[..]);
+    assert_eq!(map.indirect2.len(), 1);
+    assert_eq!(map.data[28 + 128..], map.indirect2[0].1[..]);
+    assert_eq!(map.data, big.data_blocks());
+    // every block the inodes hold is allocated, and nothing else
+    let mut held: Vec<u32> = vec![info.indirect1, info.indirect2, map.indirect2[0].0];
+    held.extend(map.data.iter().copied());
+    for inode_id in [0, dir.get_inode_number() as u32] {
+        held.extend(efs.block_map(inode_id).unwrap().data);
+    }
+    held.sort_unstable();
+    let allocated: Vec<u32> = efs
+        .allocated_data_blocks()
+        .into_iter()
+        .flat_map(|(start, len)| start..start + len)
+        .collect();
+    assert_eq!(held, allocated);
+    assert_eq!(efs.allocated_inodes(), vec![(0, 4)]);
+    assert!(efs.inode_info(4).is_none() && efs.block_map(100_000).is_none());
+    let tiny = efs.inode_info(3).unwrap();
+    assert!(tiny.inline && tiny.inline_data == b"tiny");
+    let entries = efs.dir_entries_of(0).unwrap();
+    assert_eq!(entries, vec![(String::from("dir"), 1), (String::from("tiny"), 3)]);
+    assert!(efs.dir_entries_of(3).is_none());
+    // the dumps mention it all
+    efs.sync().unwrap();
+    let block_device: Arc<dyn BlockDevice> = device.clone();
+    let json = format!("{:#}", dump_json(&block_device));
+    assert!(json.contains("\"total_blocks\": 4096"));
+    assert!(json.contains("\"name\": \"big\""));
+    assert!(json.contains("\"type\": \"directory\""));
+    let json = format!("{:#}", dump_inode_json(&efs, big_id).unwrap());
+    assert!(json.contains("\"indirect2\": {"));
+    assert!(json.contains(&format!("\"block\": {}", map.indirect2[0].0)));
+    let text = dump_text(&block_device);
+    assert!(text.contains("inode bitmap: 4 allocated: 0-3"));
+    assert!(text.contains("\"tiny\" -> 3"));
+    let text = dump_inode_text(&efs, big_id).unwrap();
+    assert!(text.contains(&format!("indirect2 block {}:", info.indirect2)));
+    assert!(dump_inode_text(&efs, 4).is_none());
+    drop((big, dir, root_inode, efs));
+
+    // damaged images are dumped as far as they can be read
+    let image = device.to_image();
+    let damaged = |at: usize, value: u32| -> Arc<dyn BlockDevice> {
+        let mut bad = image.clone();
+        bad[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        Arc::new(MemBlockDevice::from_image(&bad))
+    };
+    let json = dump_json(&damaged(0, 0));
+    assert_eq!(json["super_block"]["valid"], false);
+    assert_eq!(json["super_block"]["total_blocks"], 4096);
+    assert!(json["error"].is_string() && json.get("inodes").is_none());
+    assert!(dump_text(&damaged(0, 0)).contains("valid: false"));
+    // an invalid type for big, then a direct block of big in the super block
+    let inode_at = 2 * BLOCK_SZ + big_id as usize * 128;
+    let json = dump_json(&damaged(inode_at + 124, 7));
+    let inodes = json["inodes"].as_array().unwrap();
+    assert_eq!(inodes.len(), 4);
+    assert!(inodes[big_id as usize]["error"].is_string());
+    assert_eq!(inodes[3]["type"], "file");
+    assert!(dump_text(&damaged(inode_at + 124, 7)).contains(&format!("inode {}: error: ", big_id)));
+    let efs = EasyFileSystem::try_open(damaged(inode_at + 4, 0)).unwrap();
+    let json = dump_inode_json(&efs, big_id).unwrap();
+    assert_eq!(json["type"], "file");
+    assert!(json["block_map"]["error"].is_string());
+    assert!(dump_inode_text(&efs, big_id).unwrap().contains("block map: error: "));
+}
+
+#[test]
+fn efs_update_test() -> std::io::Result<()> {
+    use std::os::unix::fs::symlink;
+    let src = Path::new("target/update_src");
+    let dest = Path::new("target/update_dest");
+    let _ = std::fs::remove_dir_all(src);
+    let _ = std::fs::remove_dir_all(dest);
+    std::fs::create_dir_all(src.join("apps/old"))?;
+    std::fs::write(src.join("apps/same"), vec![1u8; 30000])?;
+    std::fs::write(src.join("apps/edited"), vec![2u8; 30000])?;
+    std::fs::write(src.join("apps/grown"), b"short")?;
+    std::fs::write(src.join("apps/old/gone"), b"gone")?;
+    std::fs::write(src.join("gone"), b"gone")?;
+    std::fs::write(src.join("becomes_dir"), b"file")?;
+    symlink("apps/same", src.join("link"))?;
+    let image = "target/fs_update.img";
+    let pack = |args: &[&str]| {
+        let mut all = vec!["easy-fs-fuse", "pack", "-s", "target/update_src", "-o", image];
+        all.extend_from_slice(args);
+        let matches = app().get_matches_from(all);
+        easy_fs_pack_tree(matches.subcommand_matches("pack").unwrap())
+    };
+    pack(&["--blocks", "4096"])?;
+    let blocks_of = |path: &str| {
+        let efs = open_image(image).unwrap();
+        find_path(&EasyFileSystem::root_inode(&efs), path).unwrap().data_blocks()
+    };
+    let same_blocks = blocks_of("apps/same");
+    // same size, other content; other size; removed; added; changed type
+    std::fs::write(src.join("apps/edited"), vec![3u8; 30000])?;
+    std::fs::write(src.join("apps/grown"), vec![4u8; 5000])?;
+    std::fs::remove_dir_all(src.join("apps/old"))?;
+    std::fs::remove_file(src.join("gone"))?;
+    std::fs::remove_file(src.join("becomes_dir"))?;
+    std::fs::create_dir(src.join("becomes_dir"))?;
+    std::fs::write(src.join("becomes_dir/new"), b"new")?;
+    std::fs::hard_link(src.join("apps/same"), src.join("same.link"))?;
+    std::fs::remove_file(src.join("link"))?;
+    symlink("apps/grown", src.join("link"))?;
+    // geometry can't change in place
+    assert!(app()
+        .get_matches_from_safe(vec!["easy-fs-fuse", "pack", "-s", "x", "-o", image, "--update", "--fit"])
+        .is_err());
+    pack(&["--update"])?;
+    let efs = open_image(image)?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(efs.usage().total_blocks, 4096);
+    // untouched files keep their blocks
+    assert_eq!(blocks_of("apps/same"), same_blocks);
+    let same = find_path(&root_inode, "apps/same")?;
+    assert_eq!(same.links(), 2);
+    assert_eq!(same.get_inode_number(), find_path(&root_inode, "same.link")?.get_inode_number());
+    assert_eq!(find_path(&root_inode, "link")?.read_link().as_deref(), Some("apps/grown"));
+    assert!(find_path(&root_inode, "gone").is_err() && find_path(&root_inode, "apps/old").is_err());
+    // the image holds what packing anew would
+    unpack_tree(&efs, &root_inode, dest)?;
+    let read = |root: &Path, path: &str| std::fs::read(root.join(path)).unwrap();
+    for path in ["apps/same", "apps/edited", "apps/grown", "becomes_dir/new", "same.link"] {
+        assert_eq!(read(dest, path), read(src, path), "{}", path);
+    }
+    let mut names = root_inode.ls();
+    names.sort();
+    assert_eq!(names, ["apps", "becomes_dir", "link", "same.link"]);
+    // updating again changes nothing
+    let stats = update_tree(src, &root_inode, &Filter::default())?;
+    assert_eq!((stats.added, stats.replaced, stats.removed, stats.bytes), (0, 0, 0, 0));
+    // flat updates of the legacy packer
+    let files = vec![(String::from("edited"), src.join("apps/edited"))];
+    let stats = update_files(&root_inode, &files)?;
+    assert_eq!((stats.added, stats.removed), (1, 4));
+    assert_eq!(root_inode.ls(), ["edited"]);
+    assert_eq!(read_all(&*find_path(&root_inode, "edited")?), read(src, "apps/edited"));
+    // a file with other names is replaced rather than rewritten in place
+    assert_eq!(root_inode.link("shared", &*find_path(&root_inode, "edited")?), 0);
+    std::fs::write(src.join("x"), b"xx")?;
+    std::fs::write(src.join("y"), b"yyy")?;
+    let files = vec![(String::from("edited"), src.join("x")), (String::from("shared"), src.join("y"))];
+    let stats = update_files(&root_inode, &files)?;
+    assert_eq!(stats.replaced, 2);
+    assert_eq!(read_all(&*find_path(&root_inode, "edited")?), b"xx");
+    assert_eq!(read_all(&*find_path(&root_inode, "shared")?), b"yyy");
+    Ok(())
+}
+
+#[test]
+fn efs_manifest_test() -> std::io::Result<()> {
+    let src = Path::new("target/manifest_src");
+    let _ = std::fs::remove_dir_all(src);
+    std::fs::create_dir_all(src.join("tree/sub"))?;
+    std::fs::write(src.join("init"), vec![5u8; 40000])?;
+    std::fs::write(src.join("tree/a"), b"a")?;
+    std::fs::write(src.join("tree/sub/b"), b"b")?;
+    std::fs::write(
+        src.join("image.json"),
+        r#"{ "entries": [
+            { "path": "/bin/init", "source": "init", "mode": 493 },
+            { "path": "etc/motd", "content": "hello\né" },
+            { "path": "usr", "source": "tree" },
+            { "path": "bin/sh", "type": "hardlink", "target": "bin/init" },
+            { "path": "lib", "type": "symlink", "target": "usr/sub" },
+            { "path": "empty", "type": "dir", "mtime": 0 }
+        ] }"#,
+    )?;
+    // the same entries in another order
+    std::fs::write(
+        src.join("image.toml"),
+        r#"# the same image
+[[entries]]
+path = "empty"
+type = "dir"
+
+[[entries]]
+path = "lib"
+type = 'symlink'
+target = "usr/sub"
+
+[[entries]]
+path = "etc/motd"
+content = """
+hello
+é"""
+
+[[entries]]
+"path" = "bin/sh"    # a hard link
+type = "hardlink"
+target = "bin/init"
+
+[[entries]]
+path = "bin/init"
+source = "init"
+mode = 0o755
+
+[[entries]]
+path = "usr"
+source = "tree"
+"#,
+    )?;
+    let build = |manifest: &str, image: &str| {
+        let matches = app().get_matches_from(vec!["easy-fs-fuse", "build", "-m", manifest, "-o", image, "--fit"]);
+        easy_fs_build(matches.subcommand_matches("build").unwrap())
+    };
+    build("target/manifest_src/image.json", "target/fs_manifest.img")?;
+    build("target/manifest_src/image.toml", "target/fs_manifest_toml.img")?;
+    let image = std::fs::read("target/fs_manifest.img")?;
+    assert!(image == std::fs::read("target/fs_manifest_toml.img")?);
+    build("target/manifest_src/image.json", "target/fs_manifest_toml.img")?;
+    assert!(image == std::fs::read("target/fs_manifest_toml.img")?);
+    let efs = open_image("target/fs_manifest.img")?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), ["bin", "empty", "etc", "lib", "usr"]);
+    assert_eq!(read_all(&*find_path(&root_inode, "bin/init")?), vec![5u8; 40000]);
+    assert_eq!(read_all(&*find_path(&root_inode, "etc/motd")?), "hello\n\u{e9}".as_bytes());
+    assert_eq!(read_all(&*find_path(&root_inode, "usr/sub/b")?), b"b");
+    assert_eq!(find_path(&root_inode, "bin/sh")?.links(), 2);
+    assert_eq!(find_path(&root_inode, "lib")?.read_link().as_deref(), Some("usr/sub"));
+    // mistakes are reported
+    let load = |name: &str, text: &str| {
+        std::fs::write(src.join(name), text).unwrap();
+        manifest::load(&src.join(name)).err().unwrap().to_string()
+    };
+    assert!(load("bad.json", r#"{"entries": [{"path": "a"}, {"path": "a/"}]}"#).contains("listed twice"));
+    assert!(load("bad.json", r#"{"entries": [{"path": "a", "size": 1}]}"#).contains("unknown field `size`"));
+    assert!(load("bad.json", r#"{"entries": [{"path": "../a"}]}"#).contains("not allowed"));
+    assert!(load("bad.json", r#"{"entries": [{"path": "a", "mode": [1]}]}"#).contains("Attribute"));
+    assert!(load("bad.toml", "[[entries]]\ntype = \"dir\"").contains("missing field `path`"));
+    assert!(load("bad.json", r#"{"entries": [{"path": "a", "type": "symlink"}]}"#).contains("missing target"));
+    assert!(load("bad.toml", "[[entries]]\npath = \"a\" x").contains("line 2"));
+    assert!(load("bad.json", r#"{"entries": [}"#).contains("line 1"));
+    Ok(())
+}
+
+#[test]
+fn efs_tar_test() -> std::io::Result<()> {
+    use std::os::unix::fs::{symlink, MetadataExt};
+    use std::process::Command;
+    let src = Path::new("target/tar_src");
+    let _ = std::fs::remove_dir_all(src);
+    // long enough for ustar prefixes and for GNU long names
+    let deep = ["d", "e", "f", "g", "h", "i", "j", "l", "m"].map(|c| c.repeat(27)).join("/");
+    let long = format!("{}/{}", deep, "k".repeat(27));
+    std::fs::create_dir_all(src.join(&deep))?;
+    std::fs::create_dir_all(src.join("empty"))?;
+    std::fs::write(src.join(&long), vec![6u8; 1000])?;
+    std::fs::write(src.join("big"), vec![7u8; 100_000])?;
+    std::fs::write(src.join("tiny"), b"tiny")?;
+    std::fs::hard_link(src.join("big"), src.join(&deep).join("big.link"))?;
+    symlink(format!("../{}", long), src.join("empty/long.link"))?;
+    // every file of a host tree, with its content or link target
+    fn walk(root: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let rel = path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+                let metadata = std::fs::symlink_metadata(&path).unwrap();
+                if metadata.is_dir() {
+                    dirs.push(path);
+                    files.push((rel, Vec::new()));
+                } else if metadata.file_type().is_symlink() {
+                    files.push((rel, std::fs::read_link(&path).unwrap().to_string_lossy().as_bytes().to_vec()));
+                } else {
+                    files.push((rel, std::fs::read(&path).unwrap()));
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+    let expected = walk(src);
+    let run = |command: &mut Command| assert!(command.status().unwrap().success());
+    for format in ["gnu", "pax"] {
+        let archive = format!("target/tar_src_{}.tar", format);
+        run(Command::new("tar").args(["--format", format, "-cf", &archive, "-C", "target/tar_src", "."]));
+        let matches = app().get_matches_from(vec![
+            "easy-fs-fuse", "pack", "--tar", &archive, "-o", "target/fs_tar.img", "--fit",
+        ]);
+        easy_fs_pack_tree(matches.subcommand_matches("pack").unwrap())?;
+        let dest = Path::new("target/tar_dest");
+        let _ = std::fs::remove_dir_all(dest);
+        let efs = open_image("target/fs_tar.img")?;
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        unpack_tree(&efs, &root_inode, dest)?;
+        assert_eq!(walk(dest), expected, "{}", format);
+        assert_eq!(find_path(&root_inode, "big")?.links(), 2);
+    }
+    // exported archives extract with tar, the same every time
+    let efs = open_image("target/fs_tar.img")?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut archive = Vec::new();
+    let stats = export_tar(&efs, &root_inode, &mut archive)?;
+    assert_eq!((stats.files, stats.hard_links, stats.symlinks), (3, 1, 1));
+    let mut again = Vec::new();
+    export_tar(&efs, &root_inode, &mut again)?;
+    assert!(archive == again);
+    std::fs::write("target/tar_export.tar", &archive)?;
+    let dest = Path::new("target/tar_export");
+    let _ = std::fs::remove_dir_all(dest);
+    std::fs::create_dir_all(dest)?;
+    run(Command::new("tar").args(["-xf", "target/tar_export.tar", "-C", "target/tar_export"]));
+    assert_eq!(walk(dest), expected);
+    let big = std::fs::metadata(dest.join("big"))?;
+    assert_eq!(big.nlink(), 2);
+    // and pack back the same
+    let (_, copy) = fresh_fs(4096);
+    let filter = Filter::new(&[""; 0], &["empty"])?;
+    let stats = pack_tar(archive.as_slice(), &EasyFileSystem::root_inode(&copy), &filter)?;
+    assert_eq!((stats.files, stats.hard_links, stats.symlinks), (3, 1, 0));
+    let root_inode = EasyFileSystem::root_inode(&copy);
+    assert_eq!(read_all(&*find_path(&root_inode, &long)?), vec![6u8; 1000]);
+    assert!(find_path(&root_inode, "empty").is_err());
+    // damage is reported
+    archive[100] ^= 1;
+    let error = pack_tar(archive.as_slice(), &root_inode, &filter).unwrap_err();
+    assert!(error.to_string().contains("checksum"), "{}", error);
+    // so are truncated archives and paths leaving the image
+    archive[100] ^= 1;
+    assert!(pack_tar(&archive[..archive.len() / 2], &root_inode, &filter).is_err());
+    let mut header = ::tar::Header::new_gnu();
+    header.as_old_mut().name[..4].copy_from_slice(b"../a");
+    header.set_size(0);
+    header.set_cksum();
+    let mut escaping = header.as_bytes().to_vec();
+    escaping.resize(512 * 3, 0);
+    let error = pack_tar(escaping.as_slice(), &root_inode, &filter).unwrap_err();
+    assert!(error.to_string().contains("not allowed"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn efs_diff_test() -> std::io::Result<()> {
+    use diff::Difference;
+    let (_, old) = fresh_fs(4096);
+    let (_, new) = fresh_fs(8192);
+    for efs in [&old, &new] {
+        let root_inode = EasyFileSystem::root_inode(efs);
+        let dir = root_inode.create_dir("dir").unwrap();
+        dir.create("same").unwrap().write_at(0, &vec![1u8; 3000]);
+        dir.create("edited").unwrap().write_at(0, &vec![2u8; 3000]);
+        root_inode.create("grown").unwrap().write_at(0, b"grown");
+        root_inode.create("gone").unwrap();
+        root_inode.symlink("link", "dir/same").unwrap();
+        root_inode.create_dir("was_dir").unwrap().create("inner").unwrap();
+    }
+    assert!(diff_images(&old, &new).is_empty());
+    let root_inode = EasyFileSystem::root_inode(&new);
+    let dir = root_inode.find("dir").unwrap();
+    dir.find("edited").unwrap().write_at(1030, b"xy");
+    root_inode.find("grown").unwrap().write_at(5, b" more");
+    assert_eq!(root_inode.unlink("gone"), 0);
+    root_inode.link("same.link", &dir.find("same").unwrap());
+    assert_eq!(root_inode.unlink("link"), 0);
+    root_inode.symlink("link", "grown").unwrap();
+    root_inode.create_dir("new_dir").unwrap().create("inner").unwrap();
+    assert_eq!(root_inode.find("was_dir").unwrap().unlink("inner"), 0);
+    assert_eq!(root_inode.unlink("was_dir"), 0);
+    root_inode.create("was_dir").unwrap();
+    let differences = diff_images(&old, &new);
+    let lines: Vec<String> = differences.iter().map(Difference::to_string).collect();
+    assert_eq!(
+        lines,
+        [
+            "M dir/edited: content",
+            "M dir/same: links 1 -> 2",
+            "D gone (file)",
+            "M grown: size 5 -> 10",
+            "M link: size 8 -> 5, target \"dir/same\" -> \"grown\"",
+            "A new_dir (directory)",
+            "A new_dir/inner (file)",
+            "A same.link (file)",
+            "M was_dir: directory -> file",
+            "D was_dir/inner (file)",
+        ]
+    );
+    match &differences[0].kind {
+        DiffKind::Modified { first_block: Some(block), .. } => {
+            assert_eq!(block.block, 2);
+            assert_eq!(block.bytes, [(1030, Some(2), Some(b'x')), (1031, Some(2), Some(b'y'))]);
+            assert!(block.describe().contains("0x00000406: 02 -> 78"));
+        }
+        kind => panic!("{:?}", kind),
+    }
+    match &differences[3].kind {
+        DiffKind::Modified { first_block: Some(block), .. } => {
+            assert_eq!(block.bytes[0], (5, None, Some(b' ')));
+        }
+        kind => panic!("{:?}", kind),
+    }
+    // the other way round
+    let reverse = diff_images(&new, &old);
+    assert_eq!(reverse.len(), differences.len());
+    assert_eq!(reverse[2].to_string(), "A gone (file)");
     Ok(())
 }
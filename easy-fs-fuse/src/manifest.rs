@@ -0,0 +1,302 @@
+//! Building images from a declarative manifest
+//!
+//! A manifest lists the entries of an image, in JSON:
+//!
+//! ```json
+//! { "entries": [
+//!     { "path": "bin/init", "source": "build/init" },
+//!     { "path": "etc/motd", "content": "hello\n" },
+//!     { "path": "usr", "type": "dir", "source": "rootfs/usr" },
+//!     { "path": "bin/sh", "type": "hardlink", "target": "bin/init" },
+//!     { "path": "lib", "type": "symlink", "target": "usr/lib" }
+//! ] }
+//! ```
+//!
+//! or in TOML, with one `[[entries]]` table per entry. Sources are host
+//! paths relative to the manifest; a directory source is packed as is.
+//! Missing parent directories are created. Entries are made in path
+//! order, hard links last, whatever their order in the manifest, so the
+//! same manifest always builds the same image.
+
+use super::image::Estimate;
+use super::find_path;
+use super::pack::{estimate_tree, make_dirs, pack_tree, write_host_file, Filter, PackStats};
+use easy_fs::{Inode, InodeType, NAME_LENGTH_LIMIT};
+use serde::Deserialize;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A manifest as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    entries: Vec<EntryDoc>,
+}
+
+/// An entry of a manifest as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntryDoc {
+    path: String,
+    #[serde(rename = "type")]
+    type_: Option<String>,
+    source: Option<PathBuf>,
+    content: Option<String>,
+    target: Option<String>,
+    // attributes easy-fs doesn't keep
+    mode: Option<Attribute>,
+    owner: Option<Attribute>,
+    group: Option<Attribute>,
+    mtime: Option<Attribute>,
+    atime: Option<Attribute>,
+    ctime: Option<Attribute>,
+}
+
+/// The value of an attribute easy-fs doesn't keep, checked then dropped
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Attribute {
+    Number(#[allow(dead_code)] i64),
+    String(#[allow(dead_code)] String),
+}
+
+enum Kind {
+    /// A file copied from the host
+    HostFile(PathBuf),
+    /// A file with the given content
+    Content(Vec<u8>),
+    /// A directory, with the host directory packed into it if any
+    Dir(Option<PathBuf>),
+    SymLink(String),
+    /// A hard link to the file at a path of the image
+    HardLink(String),
+}
+
+struct Entry {
+    /// Path in the image without leading, trailing or double '/'
+    path: String,
+    kind: Kind,
+}
+
+/// A parsed manifest
+pub struct Manifest {
+    /// Entries in the order they are made
+    entries: Vec<Entry>,
+    /// Attributes given which the image can't hold
+    pub ignored: Vec<&'static str>,
+}
+
+/// Normalize a path of an image
+fn image_path(path: &str) -> std::result::Result<String, String> {
+    let mut components = Vec::new();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if name == "." || name == ".." {
+            return Err(format!("{}: '.' and '..' are not allowed", path));
+        }
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(format!("{}: file name too long", path));
+        }
+        components.push(name);
+    }
+    Ok(components.join("/"))
+}
+
+impl Entry {
+    /// Interpret an entry of a manifest found in directory `base`
+    fn parse(
+        doc: EntryDoc,
+        base: &Path,
+        ignored: &mut Vec<&'static str>,
+    ) -> std::result::Result<Self, String> {
+        let attributes = [
+            ("mode", &doc.mode),
+            ("owner", &doc.owner),
+            ("group", &doc.group),
+            ("mtime", &doc.mtime),
+            ("atime", &doc.atime),
+            ("ctime", &doc.ctime),
+        ];
+        for (name, _) in attributes.iter().filter(|(_, value)| value.is_some()) {
+            if !ignored.contains(name) {
+                ignored.push(name);
+            }
+        }
+        let path = image_path(&doc.path)?;
+        let source = doc.source.map(|source| base.join(source));
+        let (content, target) = (doc.content, doc.target);
+        let type_ = match doc.type_.as_deref() {
+            Some(type_) => type_,
+            None if source.as_ref().map_or(false, |source| source.is_dir()) => "dir",
+            None => "file",
+        };
+        let unexpected = |key: &str| Err(format!("{}: a {} has no {}", path, type_, key));
+        let kind = match type_ {
+            "file" => {
+                if target.is_some() {
+                    return unexpected("target");
+                }
+                match (source, content) {
+                    (Some(_), Some(_)) => return Err(format!("{}: both source and content", path)),
+                    (Some(source), None) => Kind::HostFile(source),
+                    (None, content) => Kind::Content(content.unwrap_or_default().into_bytes()),
+                }
+            }
+            "dir" => {
+                if content.is_some() {
+                    return unexpected("content");
+                }
+                if target.is_some() {
+                    return unexpected("target");
+                }
+                Kind::Dir(source)
+            }
+            "symlink" | "hardlink" => {
+                if source.is_some() {
+                    return unexpected("source");
+                }
+                if content.is_some() {
+                    return unexpected("content");
+                }
+                let target = target.ok_or_else(|| format!("{}: missing target", path))?;
+                if type_ == "symlink" {
+                    Kind::SymLink(target)
+                } else {
+                    Kind::HardLink(image_path(&target)?)
+                }
+            }
+            _ => return Err(format!("{}: unknown type {}", path, type_)),
+        };
+        if path.is_empty() && !matches!(kind, Kind::Dir(_)) {
+            return Err(String::from("only a directory can be the root"));
+        }
+        Ok(Entry { path, kind })
+    }
+}
+
+/// Load a manifest, in TOML if its name ends with .toml, in JSON otherwise
+pub fn load(manifest_path: &Path) -> Result<Manifest> {
+    let text = fs::read_to_string(manifest_path)?;
+    let invalid = |what: String| {
+        Error::new(ErrorKind::InvalidData, format!("{}: {}", manifest_path.display(), what))
+    };
+    let document: Document = match manifest_path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&text).map_err(|e| e.to_string()),
+    }
+    .map_err(invalid)?;
+    let base = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut ignored = Vec::new();
+    let mut entries = Vec::new();
+    for (i, doc) in document.entries.into_iter().enumerate() {
+        let entry = Entry::parse(doc, base, &mut ignored)
+            .map_err(|what| invalid(format!("entry {}: {}", i + 1, what)))?;
+        entries.push(entry);
+    }
+    // hard links last, in path order otherwise
+    entries.sort_by(|a, b| {
+        let is_link = |entry: &Entry| matches!(entry.kind, Kind::HardLink(_));
+        (is_link(a), &a.path).cmp(&(is_link(b), &b.path))
+    });
+    if let Some(pair) = entries.windows(2).find(|pair| pair[0].path == pair[1].path) {
+        return Err(invalid(format!("{}: listed twice", pair[0].path)));
+    }
+    Ok(Manifest { entries, ignored })
+}
+
+/// An error about a path of the image being built
+fn entry_error(kind: ErrorKind, path: &str, what: &str) -> Error {
+    Error::new(kind, format!("/{}: {}", path, what))
+}
+
+impl Manifest {
+    /// Get an upper bound of what building the manifest takes
+    pub fn estimate(&self) -> Result<Estimate> {
+        let mut estimate = Estimate::default();
+        estimate.add_dirs(self.entries.iter().map(|entry| entry.path.as_str()));
+        for entry in self.entries.iter() {
+            match &entry.kind {
+                Kind::HostFile(source) => estimate.add(fs::metadata(source)?.len()),
+                Kind::Content(content) => estimate.add(content.len() as u64),
+                Kind::Dir(Some(source)) => {
+                    let tree = estimate_tree(source, &Filter::default())?;
+                    estimate.data_blocks += tree.data_blocks;
+                    estimate.inodes += tree.inodes;
+                }
+                Kind::SymLink(target) => estimate.add(target.len() as u64),
+                Kind::Dir(None) | Kind::HardLink(_) => {}
+            }
+        }
+        Ok(estimate)
+    }
+    /// Make the entries of the manifest under directory `root` of an image
+    pub fn build(&self, root: &Arc<Inode>) -> Result<PackStats> {
+        let mut stats = PackStats::default();
+        for entry in self.entries.iter() {
+            let path = entry.path.as_str();
+            let exists = || entry_error(ErrorKind::AlreadyExists, path, "already exists");
+            let (parent, name) = match path.rsplit_once('/') {
+                Some((parent, name)) => (make_dirs(root, parent, &mut stats)?, name),
+                None => (Arc::clone(root), path),
+            };
+            match &entry.kind {
+                Kind::Dir(source) => {
+                    let dir = if path.is_empty() {
+                        Arc::clone(root)
+                    } else {
+                        let dir = parent.create_dir(name).ok_or_else(exists)?;
+                        stats.dirs += 1;
+                        dir
+                    };
+                    if let Some(source) = source {
+                        let tree = pack_tree(source, &dir, &Filter::default())?;
+                        stats.files += tree.files;
+                        stats.dirs += tree.dirs;
+                        stats.symlinks += tree.symlinks;
+                        stats.hard_links += tree.hard_links;
+                        stats.bytes += tree.bytes;
+                        stats.skipped += tree.skipped;
+                    }
+                }
+                Kind::HostFile(source) => {
+                    let len = fs::metadata(source)?.len();
+                    let inode = parent.create(name).ok_or_else(exists)?;
+                    stats.bytes += write_host_file(source, &inode, len)?;
+                    stats.files += 1;
+                }
+                Kind::Content(content) => {
+                    let inode = parent.create(name).ok_or_else(exists)?;
+                    if inode.preallocate(content.len()) != 0 {
+                        return Err(entry_error(ErrorKind::Other, path, "no space left in the image"));
+                    }
+                    inode
+                        .try_write_at(0, content)
+                        .map_err(|err| entry_error(Error::from(err).kind(), path, &err.to_string()))?;
+                    stats.files += 1;
+                    stats.bytes += content.len() as u64;
+                }
+                Kind::SymLink(target) => {
+                    if parent.find(name).is_some() {
+                        return Err(exists());
+                    }
+                    parent
+                        .symlink(name, target)
+                        .ok_or_else(|| entry_error(ErrorKind::Other, path, "image can't hold symbolic links"))?;
+                    stats.symlinks += 1;
+                }
+                Kind::HardLink(target) => {
+                    let inode = find_path(root, target)
+                        .ok()
+                        .filter(|inode| inode.inode_type() == InodeType::File)
+                        .ok_or_else(|| entry_error(ErrorKind::NotFound, path, "target is not a file"))?;
+                    if parent.link(name, &inode) != 0 {
+                        return Err(exists());
+                    }
+                    stats.hard_links += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
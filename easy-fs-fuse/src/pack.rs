@@ -0,0 +1,254 @@
+//! Packing a host directory tree into an easy-fs image as is
+
+use super::image::Estimate;
+use easy_fs::{Inode, InodeType, DIRENT_SZ, NAME_LENGTH_LIMIT};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Which entries of a tree to pack, by glob patterns over their paths
+/// relative to the root of the tree
+///
+/// `*` and `?` don't match '/', `**` matches anything. A pattern without
+/// '/' matches the last path component. Excluded entries are skipped along
+/// with everything under them; if there are include patterns, only the
+/// matching files and directories are packed, with the directories they
+/// are found in.
+#[derive(Default)]
+pub struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+/// Compile glob patterns into one set
+fn glob_set(patterns: &[impl AsRef<str>]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        let pattern = if pattern.contains('/') {
+            String::from(pattern.trim_start_matches('/'))
+        } else {
+            format!("**/{}", pattern)
+        };
+        let glob = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+        set.add(glob);
+    }
+    set.build().map_err(|error| Error::new(ErrorKind::InvalidInput, error))
+}
+
+impl Filter {
+    pub fn new(include: &[impl AsRef<str>], exclude: &[impl AsRef<str>]) -> Result<Self> {
+        Ok(Self {
+            include: if include.is_empty() { None } else { Some(glob_set(include)?) },
+            exclude: glob_set(exclude)?,
+        })
+    }
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.exclude.is_match(path)
+    }
+    pub fn is_included(&self, path: &str) -> bool {
+        self.include.as_ref().map_or(true, |include| include.is_match(path))
+    }
+}
+
+/// What was packed
+#[derive(Default, Debug)]
+pub struct PackStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    pub hard_links: usize,
+    pub bytes: u64,
+    /// Entries which are neither files, directories nor symbolic links
+    pub skipped: usize,
+}
+
+/// Get an upper bound of what packing the tree under host directory `src`
+/// into the root directory of an image takes
+pub fn estimate_tree(src: &Path, filter: &Filter) -> Result<Estimate> {
+    let mut estimate = Estimate::default();
+    let entries = estimate_dir(src, "", filter, &mut estimate)?;
+    estimate.add((entries * DIRENT_SZ) as u64);
+    Ok(estimate)
+}
+
+/// Account for the entries of a host directory, returning their number
+fn estimate_dir(host_dir: &Path, rel: &str, filter: &Filter, estimate: &mut Estimate) -> Result<usize> {
+    let mut entries = 0;
+    for entry in fs::read_dir(host_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if rel.is_empty() { name } else { format!("{}/{}", rel, name) };
+        if filter.is_excluded(&path) {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            let children = estimate_dir(&entry.path(), &path, filter, estimate)?;
+            // "." and ".." come first
+            estimate.add(((children + 2) * DIRENT_SZ) as u64);
+        } else {
+            estimate.add(metadata.len());
+        }
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+/// Copies a host tree into a directory of an image
+struct Packer<'a> {
+    filter: &'a Filter,
+    /// Inodes of the host files with several links packed so far,
+    /// by (device, inode number)
+    linked: HashMap<(u64, u64), Arc<Inode>>,
+    stats: PackStats,
+}
+
+/// An error about a host path
+pub fn host_error(kind: ErrorKind, path: &Path, what: &str) -> Error {
+    Error::new(kind, format!("{}: {}", path.display(), what))
+}
+
+/// Pack the tree under host directory `src` into directory `root` of an
+/// image, keeping full names, symbolic links and hard links
+///
+/// Entries are packed in name order. Fails on names easy-fs can't hold
+/// or when the image is full.
+pub fn pack_tree(src: &Path, root: &Arc<Inode>, filter: &Filter) -> Result<PackStats> {
+    let mut packer = Packer {
+        filter,
+        linked: HashMap::new(),
+        stats: PackStats::default(),
+    };
+    packer.pack_dir(src, "", root)?;
+    Ok(packer.stats)
+}
+
+impl Packer<'_> {
+    /// Pack the entries of a host directory, returning whether any was packed
+    fn pack_dir(&mut self, host_dir: &Path, rel: &str, dir: &Arc<Inode>) -> Result<bool> {
+        let mut entries = fs::read_dir(host_dir)?.collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut packed = false;
+        for entry in entries {
+            let host_path = entry.path();
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| host_error(ErrorKind::InvalidData, &host_path, "name is not UTF-8"))?;
+            let path = if rel.is_empty() { name.clone() } else { format!("{}/{}", rel, name) };
+            if self.filter.is_excluded(&path) {
+                continue;
+            }
+            if name.len() > NAME_LENGTH_LIMIT {
+                return Err(host_error(ErrorKind::InvalidInput, &host_path, "name too long"));
+            }
+            let metadata = fs::symlink_metadata(&host_path)?;
+            let file_type = metadata.file_type();
+            if file_type.is_dir() {
+                let child = dir
+                    .create_dir(&name)
+                    .ok_or_else(|| host_error(ErrorKind::AlreadyExists, &host_path, "already in the image"))?;
+                // a directory is kept if it matches or holds anything
+                if self.pack_dir(&host_path, &path, &child)? || self.filter.is_included(&path) {
+                    self.stats.dirs += 1;
+                    packed = true;
+                } else if dir.unlink(&name) != 0 {
+                    return Err(host_error(ErrorKind::Other, &host_path, "can't remove it from the image"));
+                }
+                continue;
+            }
+            if !self.filter.is_included(&path) {
+                continue;
+            }
+            if file_type.is_symlink() {
+                let target = fs::read_link(&host_path)?;
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| host_error(ErrorKind::InvalidData, &host_path, "target is not UTF-8"))?;
+                dir.symlink(&name, target).ok_or_else(|| {
+                    host_error(ErrorKind::Other, &host_path, "image can't hold symbolic links")
+                })?;
+                self.stats.symlinks += 1;
+            } else if file_type.is_file() {
+                let key = (metadata.dev(), metadata.ino());
+                if let Some(inode) = self.linked.get(&key) {
+                    if dir.link(&name, inode) != 0 {
+                        return Err(host_error(ErrorKind::Other, &host_path, "can't link it in the image"));
+                    }
+                    self.stats.hard_links += 1;
+                } else {
+                    let inode = dir
+                        .create(&name)
+                        .ok_or_else(|| host_error(ErrorKind::AlreadyExists, &host_path, "already in the image"))?;
+                    self.stats.bytes += write_host_file(&host_path, &inode, metadata.len())?;
+                    self.stats.files += 1;
+                    if metadata.nlink() > 1 {
+                        self.linked.insert(key, inode);
+                    }
+                }
+            } else {
+                eprintln!("skipping {}: not a file, directory or symbolic link", host_path.display());
+                self.stats.skipped += 1;
+                continue;
+            }
+            packed = true;
+        }
+        Ok(packed)
+    }
+}
+
+/// Copy the contents of a host file of `len` bytes into an empty inode,
+/// returning the number of bytes copied
+pub fn write_host_file(host_path: &Path, inode: &Inode, len: u64) -> Result<u64> {
+    let mut host_file = File::open(host_path)?;
+    write_from(&mut host_file, inode, len)
+        .map_err(|error| host_error(error.kind(), host_path, &error.to_string()))
+}
+
+/// Copy what `reader` gives into an empty inode with room for `len`
+/// bytes, returning the number of bytes copied
+pub fn write_from(reader: &mut impl Read, inode: &Inode, len: u64) -> Result<u64> {
+    if inode.preallocate(len as usize) != 0 {
+        return Err(Error::new(ErrorKind::Other, "no space left in the image"));
+    }
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let len = reader.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        inode.try_write_at(offset, &buffer[..len])?;
+        offset += len;
+    }
+    Ok(offset as u64)
+}
+
+/// Get the directory at a '/'-separated path from directory `root` of an
+/// image, making it and its parents if needed
+pub fn make_dirs(root: &Arc<Inode>, path: &str, stats: &mut PackStats) -> Result<Arc<Inode>> {
+    let mut dir = Arc::clone(root);
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        dir = match dir.find(name) {
+            Some(child) if child.inode_type() == InodeType::Directory => child,
+            Some(_) => {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("/{}: not a directory", path)))
+            }
+            None => {
+                stats.dirs += 1;
+                dir.create_dir(name).ok_or_else(|| {
+                    Error::new(ErrorKind::Other, format!("/{}: can't create {}", path, name))
+                })?
+            }
+        };
+    }
+    Ok(dir)
+}
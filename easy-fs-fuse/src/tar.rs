@@ -0,0 +1,266 @@
+//! Packing easy-fs images from tar archives and exporting them as such
+//!
+//! Archives are read and written as streams with the `tar` crate,
+//! nothing is extracted on the host. Reading understands ustar, GNU and
+//! pax archives; writing makes GNU archives.
+
+use super::find_path;
+use super::image::Estimate;
+use super::pack::{make_dirs, write_from, Filter, PackStats};
+use super::unpack::UnpackStats;
+use easy_fs::{EasyFileSystem, EfsFile, Inode, InodeType, NAME_LENGTH_LIMIT};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::Arc;
+use tar::{Archive, Builder, EntryType, Header};
+
+/// What an archive entry is
+#[derive(Debug, Clone, PartialEq)]
+enum TarKind {
+    File,
+    Dir,
+    SymLink(String),
+    /// A hard link to an earlier entry, by path
+    HardLink(String),
+    /// Anything else, by type flag
+    Other(u8),
+    /// Global pax records, which hold nothing easy-fs keeps
+    Global,
+}
+
+fn invalid(what: String) -> Error {
+    Error::new(ErrorKind::InvalidData, what)
+}
+
+/// An error about an archive path the image can't take
+fn refused(path: &str, what: &str) -> Error {
+    Error::new(ErrorKind::Other, format!("{}: {}", path, what))
+}
+
+/// Normalize an archive path
+fn entry_path(path: &[u8]) -> Result<String> {
+    let path = std::str::from_utf8(path).map_err(|_| invalid(String::from("path is not UTF-8")))?;
+    let mut components = Vec::new();
+    for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+        if name == ".." {
+            return Err(invalid(format!("{}: '..' is not allowed", path)));
+        }
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(invalid(format!("{}: file name too long", path)));
+        }
+        components.push(name);
+    }
+    Ok(components.join("/"))
+}
+
+/// Get the normalized path and the kind of an archive entry
+fn entry_kind<R: Read>(entry: &tar::Entry<R>) -> Result<(String, TarKind)> {
+    let link = || {
+        let link = entry.link_name_bytes().unwrap_or_default();
+        String::from_utf8(link.into_owned()).map_err(|_| invalid(String::from("link is not UTF-8")))
+    };
+    let kind = match entry.header().entry_type() {
+        EntryType::Regular | EntryType::Continuous => TarKind::File,
+        EntryType::Directory => TarKind::Dir,
+        EntryType::Link => TarKind::HardLink(entry_path(link()?.as_bytes())?),
+        EntryType::Symlink => TarKind::SymLink(link()?),
+        EntryType::XGlobalHeader => TarKind::Global,
+        type_ => TarKind::Other(type_.as_byte()),
+    };
+    Ok((entry_path(&entry.path_bytes())?, kind))
+}
+
+/// Whether a path or a directory above it is excluded
+fn is_excluded(filter: &Filter, path: &str) -> bool {
+    path.match_indices('/')
+        .map(|(i, _)| &path[..i])
+        .chain(std::iter::once(path))
+        .any(|path| filter.is_excluded(path))
+}
+
+/// Get an upper bound of what packing an archive takes
+pub fn estimate_tar(reader: impl Read, filter: &Filter) -> Result<Estimate> {
+    let mut archive = Archive::new(reader);
+    let mut estimate = Estimate::default();
+    let mut paths = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let (path, kind) = entry_kind(&entry)?;
+        if path.is_empty() || kind == TarKind::Global || is_excluded(filter, &path) {
+            continue;
+        }
+        match kind {
+            TarKind::File => estimate.add(entry.size()),
+            TarKind::SymLink(ref target) => estimate.add(target.len() as u64),
+            TarKind::Dir | TarKind::HardLink(_) | TarKind::Other(_) | TarKind::Global => {}
+        }
+        paths.push(path);
+    }
+    estimate.add_dirs(paths.iter().map(String::as_str));
+    Ok(estimate)
+}
+
+/// Pack the entries of a tar archive into directory `root` of an image
+///
+/// Parent directories missing from the archive are made. As when
+/// extracting, later entries replace earlier ones of the same path.
+/// Devices, FIFOs and the like are skipped. With include patterns, only
+/// the matching files and directories are packed, with the directories
+/// they are found in.
+pub fn pack_tar(reader: impl Read, root: &Arc<Inode>, filter: &Filter) -> Result<PackStats> {
+    let mut archive = Archive::new(reader);
+    let mut stats = PackStats::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let (path, kind) = entry_kind(&entry)?;
+        if path.is_empty()
+            || kind == TarKind::Global
+            || is_excluded(filter, &path)
+            || !filter.is_included(&path)
+        {
+            continue;
+        }
+        let path = path.as_str();
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = make_dirs(root, parent_path, &mut stats)?;
+        let existing = parent.find(name);
+        if kind == TarKind::Dir {
+            match existing {
+                Some(inode) if inode.inode_type() == InodeType::Directory => {}
+                Some(_) => return Err(invalid(format!("{}: not a directory", path))),
+                None => {
+                    parent
+                        .create_dir(name)
+                        .ok_or_else(|| refused(path, "can't create it in the image"))?;
+                    stats.dirs += 1;
+                }
+            }
+            continue;
+        }
+        if let Some(inode) = existing {
+            if inode.inode_type() == InodeType::Directory || parent.unlink(name) != 0 {
+                return Err(invalid(format!("{}: is a directory", path)));
+            }
+        }
+        match kind {
+            TarKind::File => {
+                let inode = parent
+                    .create(name)
+                    .ok_or_else(|| refused(path, "can't create it in the image"))?;
+                let size = entry.size();
+                stats.bytes += write_from(&mut entry, &inode, size)
+                    .map_err(|error| Error::new(error.kind(), format!("{}: {}", path, error)))?;
+                stats.files += 1;
+            }
+            TarKind::SymLink(target) => {
+                parent
+                    .symlink(name, &target)
+                    .ok_or_else(|| refused(path, "image can't hold symbolic links"))?;
+                stats.symlinks += 1;
+            }
+            TarKind::HardLink(target) => {
+                let inode = find_path(root, &target)
+                    .ok()
+                    .filter(|inode| inode.inode_type() == InodeType::File)
+                    .ok_or_else(|| invalid(format!("{}: link target {} is not a file", path, target)))?;
+                if parent.link(name, &inode) != 0 {
+                    return Err(refused(path, "can't link it in the image"));
+                }
+                stats.hard_links += 1;
+            }
+            TarKind::Other(type_flag) => {
+                eprintln!("skipping {}: unsupported tar entry type {:?}", path, type_flag as char);
+                stats.skipped += 1;
+            }
+            TarKind::Dir | TarKind::Global => unreachable!(),
+        }
+    }
+    Ok(stats)
+}
+
+/// Get a header with pinned owners and times, so that archives of an
+/// image don't vary
+fn header(type_: EntryType, mode: u32, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(type_);
+    header.set_mode(mode);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(size);
+    header
+}
+
+/// Exports the tree of an image
+struct Exporter<'a, W: Write> {
+    efs: &'a Arc<EasyFileSystem>,
+    builder: Builder<W>,
+    /// Archive path of every file written so far, by inode number
+    exported: HashMap<u32, String>,
+    stats: UnpackStats,
+}
+
+/// Write the tree under directory `dir` of an image as a tar stream
+///
+/// Entries are written in name order with pinned owners and times, so
+/// the same tree always makes the same archive. Files sharing an inode
+/// become hard links to the first one written.
+pub fn export_tar(efs: &Arc<EasyFileSystem>, dir: &Inode, writer: impl Write) -> Result<UnpackStats> {
+    let mut exporter = Exporter {
+        efs,
+        builder: Builder::new(writer),
+        exported: HashMap::new(),
+        stats: UnpackStats::default(),
+    };
+    exporter.export_dir(dir, "")?;
+    exporter.builder.into_inner()?.flush()?;
+    Ok(exporter.stats)
+}
+
+impl<W: Write> Exporter<'_, W> {
+    fn export_dir(&mut self, dir: &Inode, rel: &str) -> Result<()> {
+        let mut entries: Vec<_> = dir
+            .read_dir(0)
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, inode_id, type_) in entries {
+            if name.contains('/') {
+                return Err(invalid(format!("{}: invalid name {:?}", rel, name)));
+            }
+            let path = if rel.is_empty() { name } else { format!("{}/{}", rel, name) };
+            let inode = EasyFileSystem::get_inode(self.efs, inode_id);
+            match type_ {
+                InodeType::Directory => {
+                    let mut header = header(EntryType::Directory, 0o755, 0);
+                    self.builder.append_data(&mut header, format!("{}/", path), std::io::empty())?;
+                    self.stats.dirs += 1;
+                    self.export_dir(&inode, &path)?;
+                }
+                InodeType::SymLink => {
+                    let target = inode
+                        .read_link()
+                        .ok_or_else(|| invalid(format!("{}: invalid link", path)))?;
+                    let mut header = header(EntryType::Symlink, 0o777, 0);
+                    self.builder.append_link(&mut header, &path, target)?;
+                    self.stats.symlinks += 1;
+                }
+                InodeType::File => {
+                    if let Some(first) = self.exported.get(&inode_id) {
+                        let mut header = header(EntryType::Link, 0o644, 0);
+                        self.builder.append_link(&mut header, &path, first)?;
+                        self.stats.hard_links += 1;
+                        continue;
+                    }
+                    let size = inode.size() as u64;
+                    let mut header = header(EntryType::Regular, 0o644, size);
+                    self.builder.append_data(&mut header, &path, EfsFile::new(inode))?;
+                    self.stats.files += 1;
+                    self.stats.bytes += size;
+                    self.exported.insert(inode_id, path);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Extracting the tree of an easy-fs image to a host directory
+
+use easy_fs::{EasyFileSystem, EfsError, EfsFile, Inode, InodeType};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Error, ErrorKind, Result};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What was extracted
+#[derive(Default, Debug)]
+pub struct UnpackStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    pub hard_links: usize,
+    pub bytes: u64,
+}
+
+/// Recreates the tree of an image under a host directory
+struct Unpacker<'a> {
+    efs: &'a Arc<EasyFileSystem>,
+    /// Host path of every file extracted so far, by inode number
+    extracted: HashMap<u32, PathBuf>,
+    /// Inode number of every directory entered so far
+    entered: HashSet<u32>,
+    stats: UnpackStats,
+}
+
+/// Extract the tree under directory `dir` of an image into host directory
+/// `dest`, created if needed
+///
+/// Files sharing an inode become hard links to the first one extracted.
+/// The format keeps no modes, owners or timestamps, so the host picks them.
+/// Existing host files in the way are replaced; symbolic links already on
+/// the host are never followed. The image is read through the checked
+/// operations, so a damaged image, a name found twice in a directory or a
+/// directory found twice in the tree is an error.
+pub fn unpack_tree(efs: &Arc<EasyFileSystem>, dir: &Inode, dest: &Path) -> Result<UnpackStats> {
+    let mut unpacker = Unpacker {
+        efs,
+        extracted: HashMap::new(),
+        entered: HashSet::from([dir.get_inode_number() as u32]),
+        stats: UnpackStats::default(),
+    };
+    fs::create_dir_all(dest)?;
+    unpacker.unpack_dir(dir, dest)?;
+    Ok(unpacker.stats)
+}
+
+/// Remove whatever is at a host path, unless it is a directory
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{}: is a directory", path.display()),
+        )),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// An error reading the image at a host path
+fn image_error(path: &Path, err: EfsError) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), err))
+}
+
+impl Unpacker<'_> {
+    fn unpack_dir(&mut self, dir: &Inode, host_dir: &Path) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut entries = dir.read_dir(0);
+        while let Some((name, inode_id, type_)) =
+            entries.try_next().map_err(|err| image_error(host_dir, err))?
+        {
+            if name == "." || name == ".." {
+                continue;
+            }
+            if name.contains('/') {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: invalid name {:?}", host_dir.display(), name),
+                ));
+            }
+            let host_path = host_dir.join(&name);
+            if !names.insert(name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: found twice in the directory", host_path.display()),
+                ));
+            }
+            let inode = EasyFileSystem::get_inode(self.efs, inode_id);
+            match type_ {
+                InodeType::Directory => {
+                    if !self.entered.insert(inode_id) {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("{}: directory already extracted, the tree has a cycle", host_path.display()),
+                        ));
+                    }
+                    let is_dir = fs::symlink_metadata(&host_path).map_or(false, |metadata| metadata.is_dir());
+                    if !is_dir {
+                        remove_file(&host_path)?;
+                        fs::create_dir(&host_path)?;
+                    }
+                    self.unpack_dir(&inode, &host_path)?;
+                    self.stats.dirs += 1;
+                }
+                InodeType::SymLink => {
+                    let target = inode
+                        .try_read_link()
+                        .map_err(|err| image_error(&host_path, err))?
+                        .ok_or_else(|| {
+                            Error::new(ErrorKind::InvalidData, format!("{}: invalid link", host_path.display()))
+                        })?;
+                    remove_file(&host_path)?;
+                    symlink(target, &host_path)?;
+                    self.stats.symlinks += 1;
+                }
+                InodeType::File => {
+                    remove_file(&host_path)?;
+                    if let Some(first) = self.extracted.get(&inode_id) {
+                        fs::hard_link(first, &host_path)?;
+                        self.stats.hard_links += 1;
+                    } else {
+                        self.unpack_file(&inode, &host_path)?;
+                        self.extracted.insert(inode_id, host_path);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn unpack_file(&mut self, inode: &Arc<Inode>, host_path: &Path) -> Result<()> {
+        // whatever was in the way is gone, a file showing up meanwhile is
+        // not written through
+        let mut host_file = OpenOptions::new().write(true).create_new(true).open(host_path)?;
+        self.stats.bytes += io::copy(&mut EfsFile::new(Arc::clone(inode)), &mut host_file)
+            .map_err(|err| Error::new(err.kind(), format!("{}: {}", host_path.display(), err)))?;
+        self.stats.files += 1;
+        Ok(())
+    }
+}
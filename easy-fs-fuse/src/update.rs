@@ -0,0 +1,324 @@
+//! Bringing an existing easy-fs image in line with a host tree in place
+
+use super::pack::{host_error, write_host_file, Filter};
+use easy_fs::{Inode, InodeType, NAME_LENGTH_LIMIT};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::hash::Hasher;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What was changed
+#[derive(Default, Debug)]
+pub struct UpdateStats {
+    pub added: usize,
+    pub replaced: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Bytes written into the image
+    pub bytes: u64,
+    /// Entries which are neither files, directories nor symbolic links
+    pub skipped: usize,
+}
+
+/// Applies the differences between a host tree and a directory of an image
+struct Updater<'a> {
+    filter: &'a Filter,
+    /// Inodes of the host files with several links updated so far,
+    /// by (device, inode number)
+    linked: HashMap<(u64, u64), Arc<Inode>>,
+    /// Number of entries linking to the files of the image, by inode
+    /// number; counted once and kept up to date by the updater
+    links: HashMap<usize, usize>,
+    stats: UpdateStats,
+}
+
+/// Update directory `root` of an image to hold what packing the tree
+/// under host directory `src` would
+///
+/// Files whose size and hash match are left alone, other files are
+/// rewritten, entries missing from the host are removed and new ones
+/// are added.
+pub fn update_tree(src: &Path, root: &Arc<Inode>, filter: &Filter) -> Result<UpdateStats> {
+    let mut updater = Updater {
+        filter,
+        linked: HashMap::new(),
+        links: count_links(root),
+        stats: UpdateStats::default(),
+    };
+    updater.update_dir(src, "", root)?;
+    Ok(updater.stats)
+}
+
+/// Update directory `root` of an image to hold exactly the host files
+/// `files`, as (name, host path)
+pub fn update_files(root: &Arc<Inode>, files: &[(String, PathBuf)]) -> Result<UpdateStats> {
+    let filter = Filter::default();
+    let mut updater = Updater {
+        filter: &filter,
+        linked: HashMap::new(),
+        links: count_links(root),
+        stats: UpdateStats::default(),
+    };
+    let mut kept = HashSet::new();
+    for (name, host_path) in files {
+        let metadata = fs::metadata(host_path)?;
+        updater.update_file(root, name, host_path, &metadata)?;
+        kept.insert(name.as_str());
+    }
+    updater.remove_others(root, &kept)?;
+    Ok(updater.stats)
+}
+
+/// Hash everything `read` gives until it returns 0
+fn hash_all(mut read: impl FnMut(&mut [u8]) -> Result<usize>) -> Result<u64> {
+    // the keys of `DefaultHasher::new` are fixed, so hashes compare
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let len = read(&mut buffer)?;
+        if len == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buffer[..len]);
+    }
+}
+
+/// Whether a file of an image holds the same data as a host file
+fn same_content(inode: &Inode, host_path: &Path, len: u64) -> Result<bool> {
+    if inode.size() as u64 != len {
+        return Ok(false);
+    }
+    let mut offset = 0;
+    let image_hash = hash_all(|buffer| {
+        let len = inode.read_at(offset, buffer);
+        offset += len;
+        Ok(len)
+    })?;
+    let mut host_file = File::open(host_path)?;
+    Ok(hash_all(|buffer| host_file.read(buffer))? == image_hash)
+}
+
+/// Count the entries linking to every file in the tree under directory
+/// `root`, which is the whole image when it is the root directory
+fn count_links(root: &Arc<Inode>) -> HashMap<usize, usize> {
+    let mut links = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![Arc::clone(root)];
+    while let Some(dir) = stack.pop() {
+        if !visited.insert(dir.get_inode_number()) {
+            continue;
+        }
+        for (name, inode_id, type_) in dir.read_dir(0) {
+            match type_ {
+                InodeType::Directory if name != "." && name != ".." => {
+                    stack.extend(dir.find(&name));
+                }
+                InodeType::File => *links.entry(inode_id as usize).or_insert(0) += 1,
+                _ => {}
+            }
+        }
+    }
+    links
+}
+
+/// An error about an entry of the image which can't be changed
+fn image_error(name: &str, what: &str) -> Error {
+    Error::new(ErrorKind::Other, format!("{}: {}", name, what))
+}
+
+impl Updater<'_> {
+    /// Update a directory of an image from a host directory, returning
+    /// whether it holds anything packing would keep
+    fn update_dir(&mut self, host_dir: &Path, rel: &str, dir: &Arc<Inode>) -> Result<bool> {
+        let mut entries = fs::read_dir(host_dir)?.collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut kept = HashSet::new();
+        for entry in entries {
+            let host_path = entry.path();
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| host_error(ErrorKind::InvalidData, &host_path, "name is not UTF-8"))?;
+            let path = if rel.is_empty() { name.clone() } else { format!("{}/{}", rel, name) };
+            if self.filter.is_excluded(&path) {
+                continue;
+            }
+            if name.len() > NAME_LENGTH_LIMIT {
+                return Err(host_error(ErrorKind::InvalidInput, &host_path, "name too long"));
+            }
+            let metadata = fs::symlink_metadata(&host_path)?;
+            let file_type = metadata.file_type();
+            if file_type.is_dir() {
+                let (child, created) = match dir.find(&name) {
+                    Some(child) if child.inode_type() == InodeType::Directory => (child, false),
+                    existing => {
+                        if existing.is_some() {
+                            self.remove_entry(dir, &name)?;
+                        }
+                        let child = dir.create_dir(&name).ok_or_else(|| {
+                            host_error(ErrorKind::Other, &host_path, "can't create it in the image")
+                        })?;
+                        (child, true)
+                    }
+                };
+                // a directory is kept if it matches or holds anything
+                if self.update_dir(&host_path, &path, &child)? || self.filter.is_included(&path) {
+                    self.count(created);
+                    kept.insert(name);
+                } else if created && dir.unlink(&name) != 0 {
+                    return Err(host_error(ErrorKind::Other, &host_path, "can't remove it from the image"));
+                }
+                continue;
+            }
+            if !self.filter.is_included(&path) {
+                continue;
+            }
+            if file_type.is_symlink() {
+                self.update_symlink(dir, &name, &host_path)?;
+            } else if file_type.is_file() {
+                self.update_file(dir, &name, &host_path, &metadata)?;
+            } else {
+                eprintln!("skipping {}: not a file, directory or symbolic link", host_path.display());
+                self.stats.skipped += 1;
+                continue;
+            }
+            kept.insert(name);
+        }
+        let kept: HashSet<&str> = kept.iter().map(String::as_str).collect();
+        self.remove_others(dir, &kept)?;
+        Ok(!kept.is_empty())
+    }
+    /// Count an entry as added or left alone
+    fn count(&mut self, added: bool) {
+        if added {
+            self.stats.added += 1;
+        } else {
+            self.stats.unchanged += 1;
+        }
+    }
+    /// Remove the entries of a directory which are not kept
+    fn remove_others(&mut self, dir: &Inode, kept: &HashSet<&str>) -> Result<()> {
+        let names: Vec<String> = dir.read_dir(0).map(|(name, _, _)| name).collect();
+        for name in names {
+            if name != "." && name != ".." && !kept.contains(name.as_str()) {
+                self.remove_entry(dir, &name)?;
+                self.stats.removed += 1;
+            }
+        }
+        Ok(())
+    }
+    /// Remove entry `name` of a directory, along with everything under it
+    fn remove_entry(&mut self, dir: &Inode, name: &str) -> Result<()> {
+        if let Some(inode) = dir.find(name) {
+            if inode.inode_type() == InodeType::Directory {
+                let names: Vec<String> = inode.read_dir(0).map(|(name, _, _)| name).collect();
+                for name in names.iter().filter(|name| *name != "." && *name != "..") {
+                    self.remove_entry(&inode, name)?;
+                }
+            }
+            if let Some(links) = self.links.get_mut(&inode.get_inode_number()) {
+                *links -= 1;
+            }
+        }
+        if dir.unlink(name) != 0 {
+            return Err(image_error(name, "can't remove it from the image"));
+        }
+        Ok(())
+    }
+    /// Remove whatever entry `name` of a directory is, returning whether
+    /// there was one
+    fn replace(&mut self, dir: &Inode, name: &str) -> Result<bool> {
+        let existed = dir.find(name).is_some();
+        if existed {
+            self.remove_entry(dir, name)?;
+        }
+        Ok(existed)
+    }
+    fn update_symlink(&mut self, dir: &Inode, name: &str, host_path: &Path) -> Result<()> {
+        let target = fs::read_link(host_path)?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| host_error(ErrorKind::InvalidData, host_path, "target is not UTF-8"))?;
+        if let Some(inode) = dir.find(name) {
+            if inode.read_link().as_deref() == Some(target) {
+                self.stats.unchanged += 1;
+                return Ok(());
+            }
+        }
+        let replaced = self.replace(dir, name)?;
+        dir.symlink(name, target)
+            .ok_or_else(|| host_error(ErrorKind::Other, host_path, "image can't hold symbolic links"))?;
+        self.count_changed(replaced);
+        Ok(())
+    }
+    fn update_file(&mut self, dir: &Inode, name: &str, host_path: &Path, metadata: &Metadata) -> Result<()> {
+        let key = (metadata.dev(), metadata.ino());
+        let existing = dir.find(name);
+        if let Some(inode) = self.linked.get(&key).cloned() {
+            if existing.map(|existing| existing.get_inode_number()) == Some(inode.get_inode_number()) {
+                self.stats.unchanged += 1;
+            } else {
+                let replaced = self.replace(dir, name)?;
+                if dir.link(name, &inode) != 0 {
+                    return Err(host_error(ErrorKind::Other, host_path, "can't link it in the image"));
+                }
+                *self.links.entry(inode.get_inode_number()).or_insert(0) += 1;
+                self.count_changed(replaced);
+            }
+            return Ok(());
+        }
+        let inode = match existing {
+            Some(inode) if inode.inode_type() == InodeType::File => {
+                if same_content(&inode, host_path, metadata.len())? {
+                    self.stats.unchanged += 1;
+                    inode
+                } else if self.links.get(&inode.get_inode_number()) == Some(&1) {
+                    // rewritten in place, unless other names share it
+                    inode.clear();
+                    self.stats.bytes += write_host_file(host_path, &inode, metadata.len())?;
+                    self.stats.replaced += 1;
+                    inode
+                } else {
+                    self.replace_file(dir, name, host_path, metadata.len(), true)?
+                }
+            }
+            existing => self.replace_file(dir, name, host_path, metadata.len(), existing.is_some())?,
+        };
+        if metadata.nlink() > 1 {
+            self.linked.insert(key, inode);
+        }
+        Ok(())
+    }
+    /// Put a new file in place of entry `name` of a directory
+    fn replace_file(
+        &mut self,
+        dir: &Inode,
+        name: &str,
+        host_path: &Path,
+        len: u64,
+        existed: bool,
+    ) -> Result<Arc<Inode>> {
+        if existed {
+            self.remove_entry(dir, name)?;
+        }
+        let inode = dir
+            .create(name)
+            .ok_or_else(|| host_error(ErrorKind::Other, host_path, "can't create it in the image"))?;
+        self.links.insert(inode.get_inode_number(), 1);
+        self.stats.bytes += write_host_file(host_path, &inode, len)?;
+        self.count_changed(existed);
+        Ok(inode)
+    }
+    /// Count an entry as replaced or added
+    fn count_changed(&mut self, replaced: bool) {
+        if replaced {
+            self.stats.replaced += 1;
+        } else {
+            self.stats.added += 1;
+        }
+    }
+}
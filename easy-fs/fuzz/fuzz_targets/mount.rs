@@ -0,0 +1,68 @@
+//! Mount arbitrary bytes as an image and look around in it
+//!
+//! Run from easy-fs with `cargo fuzz run mount`. Images made by
+//! easy-fs-fuse are good seeds for the corpus. Every corruption has to
+//! come back as an `EfsError`, a panic is a bug.
+
+#![no_main]
+
+use easy_fs::{EasyFileSystem, EfsError, Inode, InodeType, MemBlockDevice, BLOCK_SZ};
+use libfuzzer_sys::fuzz_target;
+use std::sync::Arc;
+
+/// Directories walked per image
+const MAX_DIRS: usize = 64;
+/// Entries looked at per directory, huge directories would time out
+const MAX_ENTRIES: usize = 64;
+
+fuzz_target!(|image: &[u8]| {
+    let device = Arc::new(MemBlockDevice::from_image(image));
+    if let Ok(efs) = EasyFileSystem::try_open(device) {
+        walk(&efs);
+    }
+});
+
+/// Read the first and the last block of a file
+fn read_file(file: &Inode) -> Result<(), EfsError> {
+    let mut buffer = [0u8; BLOCK_SZ];
+    file.try_read_at(0, &mut buffer)?;
+    let size = file.try_size()?;
+    file.try_read_at(size.saturating_sub(1) / BLOCK_SZ * BLOCK_SZ, &mut buffer)?;
+    Ok(())
+}
+
+/// List the directories reachable from the root, looking up and reading
+/// what they hold
+fn walk(efs: &Arc<EasyFileSystem>) {
+    let mut visited = Vec::new();
+    let mut dirs = vec![EasyFileSystem::root_inode(efs)];
+    while let Some(dir) = dirs.pop() {
+        // entries may link back to any directory
+        if visited.contains(&dir.get_inode_number()) || visited.len() == MAX_DIRS {
+            continue;
+        }
+        visited.push(dir.get_inode_number());
+        let mut entries = dir.read_dir(0);
+        let mut names = Vec::new();
+        while names.len() < MAX_ENTRIES {
+            match entries.try_next() {
+                Ok(Some((name, _, type_))) => names.push((name, type_)),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        for (name, type_) in names {
+            let inode = match dir.try_find(&name) {
+                Ok(Some(inode)) => inode,
+                _ => continue,
+            };
+            let _ = match type_ {
+                InodeType::Directory => {
+                    dirs.push(inode);
+                    Ok(())
+                }
+                InodeType::File => read_file(&inode),
+                InodeType::SymLink => inode.try_read_link().map(|_| ()),
+            };
+        }
+    }
+}
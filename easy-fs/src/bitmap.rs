@@ -1,8 +1,11 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use super::{
     BlockDevice,
+    BlockError,
     BLOCK_SZ,
     get_block_cache,
+    try_get_block_cache,
 };
 
 /// A bitmap block
@@ -20,7 +23,7 @@ pub struct Bitmap {
 /// Decompose bits into (block_pos, bits64_pos, inner_pos)
 fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
+    bit %= BLOCK_BITS;
     (block_pos, bit / 64, bit % 64)
 }
 
@@ -32,33 +35,117 @@ impl Bitmap {
             blocks,
         }
     }
-    /// Allocate a new block from a block device
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
-                Arc::clone(block_device),
+    /// Allocate the first free bit below `end` from a block device
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>, end: usize) -> Option<usize> {
+        let bit = self.find_free(block_device, 0, end.min(self.maximum()))?;
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+        });
+        Some(bit)
+    }
+    /// Allocate up to `len` contiguous bits below `end`, starting at the first
+    /// free bit at or after `goal` and wrapping around to the start if there
+    /// is none; returns (first bit, number of bits allocated)
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: usize,
+        end: usize,
+        len: usize,
+    ) -> Option<(usize, usize)> {
+        let end = end.min(self.maximum());
+        let goal = if goal < end { goal } else { 0 };
+        let start = self
+            .find_free(block_device, goal, end)
+            .or_else(|| self.find_free(block_device, 0, goal))?;
+        let mut count = 0;
+        while count < len && start + count < end {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(start + count);
+            let claimed = get_block_cache(
+                block_pos + self.start_block_id,
+                Arc::clone(block_device)
             ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| {
-                        (bits64_pos, bits64.trailing_ones() as usize)
-                    }) {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
+                if bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0 {
+                    false
                 } else {
-                    None
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                    true
                 }
             });
-            if pos.is_some() {
-                return pos;
+            if !claimed {
+                break;
+            }
+            count += 1;
+        }
+        Some((start, count))
+    }
+    /// Find the first free bit in `from..to`
+    fn find_free(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        from: usize,
+        to: usize,
+    ) -> Option<usize> {
+        let mut bit = from;
+        while bit < to {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+            let bits64 = get_block_cache(
+                block_pos + self.start_block_id,
+                Arc::clone(block_device)
+            ).lock().read(0, |bitmap_block: &BitmapBlock| bitmap_block[bits64_pos]);
+            // skip the bits below `bit` and whole words that are full
+            let free = !bits64 >> inner_pos;
+            if free != 0 {
+                let found = bit + free.trailing_zeros() as usize;
+                return if found < to { Some(found) } else { None };
             }
+            bit += 64 - inner_pos;
         }
         None
     }
+    /// Count the allocated bits
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_id| {
+                get_block_cache(
+                    block_id + self.start_block_id,
+                    Arc::clone(block_device)
+                ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block.iter().map(|bits64| bits64.count_ones() as usize).sum::<usize>()
+                })
+            })
+            .sum()
+    }
+    /// Get the runs of allocated bits as (first bit, length)
+    pub fn allocated_runs(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<(usize, usize)>, BlockError> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for block_id in 0..self.blocks {
+            let bitmap_block = try_get_block_cache(
+                block_id + self.start_block_id,
+                Arc::clone(block_device)
+            )?.lock().read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            for (bits64_pos, bits64) in bitmap_block.iter().enumerate() {
+                for inner_pos in 0..64 {
+                    if bits64 & (1u64 << inner_pos) == 0 {
+                        continue;
+                    }
+                    let bit = block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos;
+                    match runs.last_mut() {
+                        Some((start, len)) if *start + *len == bit => *len += 1,
+                        _ => runs.push((bit, 1)),
+                    }
+                }
+            }
+        }
+        Ok(runs)
+    }
     /// Deallocate a block
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
@@ -70,6 +157,25 @@ impl Bitmap {
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
     }
+    /// Whether a bit is allocated
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        self.try_is_allocated(block_device, bit)
+            .unwrap_or_else(|err| panic!("Error when reading bitmap: {:?}", err))
+    }
+    /// Like `is_allocated`, the error of the device if the bitmap can't be read
+    pub fn try_is_allocated(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        bit: usize,
+    ) -> Result<bool, BlockError> {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        Ok(try_get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        )?.lock().read(0, |bitmap_block: &BitmapBlock| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+        }))
+    }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
         self.blocks * BLOCK_BITS
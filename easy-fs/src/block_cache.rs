@@ -1,9 +1,13 @@
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    BlockError,
+    OnDisk,
 };
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
 use lazy_static::*;
 use spin::Mutex;
 
@@ -24,63 +28,92 @@ impl BlockCache {
     pub fn new(
         block_id: usize,
         block_device: Arc<dyn BlockDevice>
-    ) -> Self {
+    ) -> Result<Self, BlockError> {
         let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
-        Self {
+        block_device.read_block(block_id, &mut cache)?;
+        Ok(Self {
             cache,
             block_id,
             block_device,
             modified: false,
+        })
+    }
+    /// A dirty BlockCache of zeros, the block is not read
+    fn zeroed(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            cache: [0u8; BLOCK_SZ],
+            block_id,
+            block_device,
+            modified: true,
         }
     }
-    /// Get the address of an offset inside the cached block data
-    fn addr_of_offset(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const _ as usize
+    /// Decode the structure at an offset inside the cached block data
+    fn decode<T: OnDisk>(&self, offset: usize) -> T {
+        assert!(offset + T::SIZE <= BLOCK_SZ);
+        T::decode(&self.cache[offset..offset + T::SIZE]).expect("Corrupted EFS structure!")
     }
 
-    pub fn get_ref<T>(&self, offset: usize) -> &T where T: Sized {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
-        let addr = self.addr_of_offset(offset);
-        unsafe { &*(addr as *const T) } 
+    pub fn read<T: OnDisk, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
+        f(&self.decode(offset))
     }
 
-    pub fn get_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+    /// Like `read`, None if the bytes are not a valid encoding of `T`
+    pub fn try_read<T: OnDisk, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> Option<V> {
+        assert!(offset + T::SIZE <= BLOCK_SZ);
+        T::decode(&self.cache[offset..offset + T::SIZE]).map(|value| f(&value))
+    }
+
+    pub fn modify<T: OnDisk, V>(&mut self, offset:usize, f: impl FnOnce(&mut T) -> V) -> V {
+        let mut value = self.decode(offset);
+        let ret = f(&mut value);
+        value.encode(&mut self.cache[offset..offset + T::SIZE]);
         self.modified = true;
-        let addr = self.addr_of_offset(offset);
-        unsafe { &mut *(addr as *mut T) }
+        ret
     }
 
-    pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
-        f(self.get_ref(offset))
+    /// Overwrite the cached block with zeros
+    pub fn zero(&mut self) {
+        self.cache.fill(0);
+        self.modified = true;
     }
 
-    pub fn modify<T, V>(&mut self, offset:usize, f: impl FnOnce(&mut T) -> V) -> V {
-        f(self.get_mut(offset))
+    /// Forget the contents of a block discarded on the device
+    fn discard(&mut self) {
+        self.cache.fill(0);
+        self.modified = false;
     }
 
-    pub fn sync(&mut self) {
+    /// Write the block back if it is dirty, it stays dirty if that fails
+    pub fn sync(&mut self) -> Result<(), BlockError> {
         if self.modified {
+            self.block_device.write_block(self.block_id, &self.cache)?;
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
         }
+        Ok(())
     }
 }
 
 impl Drop for BlockCache {
     fn drop(&mut self) {
-        self.sync()
+        // the manager only drops caches it could sync
+        let _ = self.sync();
     }
 }
 
 /// Use a block cache of 16 blocks
 const BLOCK_CACHE_SIZE: usize = 16;
 
+/// Identify a block device by the address of its data, caches of
+/// different devices never alias even if their block ids do
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// (device id, block id) of a cached block
+type CacheKey = (usize, usize);
+
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<(CacheKey, Arc<Mutex<BlockCache>>)>,
 }
 
 impl BlockCacheManager {
@@ -92,32 +125,62 @@ impl BlockCacheManager {
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<Mutex<BlockCache>> {
+    ) -> Result<Arc<Mutex<BlockCache>>, BlockError> {
+        self.get_or_insert(block_id, block_device, BlockCache::new)
+    }
+
+    /// Get the cache of a block, making it with `new` if it is not cached
+    fn get_or_insert(
+        &mut self,
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        new: impl FnOnce(usize, Arc<dyn BlockDevice>) -> Result<BlockCache, BlockError>,
+    ) -> Result<Arc<Mutex<BlockCache>>, BlockError> {
+        let key = (device_id(&block_device), block_id);
         if let Some(pair) = self.queue
             .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
+            .find(|pair| pair.0 == key) {
+                Ok(Arc::clone(&pair.1))
         } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
+            // substitute, the queue may exceed its size while every
+            // cached block is in use or can't be written back, and
+            // shrinks back afterwards
+            while self.queue.len() >= BLOCK_CACHE_SIZE {
+                // from front to tail, nobody else can hold the lock of
+                // a cache only referenced by the queue
                 if let Some((idx, _)) = self.queue
                     .iter()
                     .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
+                    .find(|(_, pair)| {
+                        Arc::strong_count(&pair.1) == 1 && pair.1.lock().sync().is_ok()
+                    }) {
                     self.queue.drain(idx..=idx);
                 } else {
-                    panic!("Run out of BlockCache!");
+                    break;
                 }
             }
             // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
-            ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            let block_cache = Arc::new(Mutex::new(new(block_id, Arc::clone(&block_device))?));
+            self.queue.push_back((key, Arc::clone(&block_cache)));
+            Ok(block_cache)
         }
     }
+
+    /// Get the caches of a block device, of the blocks in `block_ids` if given
+    fn device_caches(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_ids: Option<&Range<usize>>,
+    ) -> Vec<Arc<Mutex<BlockCache>>> {
+        self.queue
+            .iter()
+            .filter(|((device, block_id), _)| {
+                *device == device_id(block_device)
+                    && block_ids.map_or(true, |block_ids| block_ids.contains(block_id))
+            })
+            .map(|(_, cache)| Arc::clone(cache))
+            .collect()
+    }
 }
 
 lazy_static! {
@@ -128,17 +191,84 @@ lazy_static! {
 }
 
 /// Get the block cache corresponding to the given block id and block device
+///
+/// Panics if the block can't be read
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>
 ) -> Arc<Mutex<BlockCache>> {
+    // don't panic while holding the manager
+    match try_get_block_cache(block_id, block_device) {
+        Ok(block_cache) => block_cache,
+        Err(err) => panic!("Error when reading block {}: {:?}", block_id, err),
+    }
+}
+
+/// Get the block cache corresponding to the given block id and block device,
+/// or the error of the device if the block can't be read
+pub fn try_get_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>
+) -> Result<Arc<Mutex<BlockCache>>, BlockError> {
     BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
 }
 
+/// Get the block cache of a block overwritten with zeros, without reading it
+pub fn get_zeroed_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>
+) -> Arc<Mutex<BlockCache>> {
+    let block_cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .get_or_insert(block_id, block_device, |block_id, block_device| {
+            Ok(BlockCache::zeroed(block_id, block_device))
+        })
+        .unwrap();
+    // a block cached before still holds its old contents
+    block_cache.lock().zero();
+    block_cache
+}
+
+/// Drop the contents of the cached blocks of a block device in
+/// `block_ids`, before they are discarded on the device itself
+pub fn block_cache_discard(block_device: &Arc<dyn BlockDevice>, block_ids: Range<usize>) {
+    // release the manager before locking any cache, its holder
+    // may be waiting for the manager itself
+    let caches = BLOCK_CACHE_MANAGER.lock().device_caches(block_device, Some(&block_ids));
+    for cache in caches {
+        cache.lock().discard();
+    }
+}
+
+/// Sync a list of block caches, returning the first error;
+/// blocks that fail stay dirty
+fn sync_caches(caches: Vec<Arc<Mutex<BlockCache>>>) -> Result<(), BlockError> {
+    let mut result = Ok(());
+    for cache in caches {
+        if let Err(err) = cache.lock().sync() {
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
 /// Sync all block cache to block device
+///
+/// Blocks that can't be written stay dirty and are retried next time
 pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
-    }
+    // release the manager before locking any cache, its holder
+    // may be waiting for the manager itself
+    let caches: Vec<Arc<Mutex<BlockCache>>> = BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .map(|(_, cache)| Arc::clone(cache))
+        .collect();
+    let _ = sync_caches(caches);
+}
+
+/// Sync the block caches of a block device, returning the first error
+pub fn block_cache_sync(block_device: &Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    let caches = BLOCK_CACHE_MANAGER.lock().device_caches(block_device, None);
+    sync_caches(caches)
 }
@@ -1,8 +1,34 @@
 use core::any::Any;
+use core::ops::Range;
+
+/// An error reported by a block device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block is past the end of the device
+    OutOfRange,
+    /// The device failed to transfer the block
+    Io,
+    /// The device does not support the request
+    Unsupported,
+}
 
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
+///
+/// A failed read is fatal to the filesystem on the device. A failed write
+/// leaves the block dirty in the cache, to be retried by the next sync and
+/// reported by `EasyFileSystem::sync`.
 pub trait BlockDevice : Send + Sync + Any {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError>;
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError>;
+    /// Tell the device the contents of some blocks are no longer needed,
+    /// they may read back as anything afterwards
+    fn discard(&self, block_ids: Range<usize>) -> Result<(), BlockError> {
+        let _ = block_ids;
+        Err(BlockError::Unsupported)
+    }
+    /// Whether the device implements `discard`
+    fn can_discard(&self) -> bool {
+        false
+    }
 }
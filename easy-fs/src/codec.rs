@@ -0,0 +1,64 @@
+use core::convert::TryInto;
+use core::mem::size_of;
+use super::BLOCK_SZ;
+
+/// A structure with a fixed on-disk encoding
+///
+/// Cached blocks are never reinterpreted in place: a structure is decoded
+/// from the bytes of a block into a value and encoded back once it has been
+/// modified. All fields are little-endian at fixed offsets, so an image is
+/// byte-identical whatever host wrote it, and bytes which are not a valid
+/// encoding are rejected by `decode` instead of becoming undefined behavior.
+pub trait OnDisk: Sized {
+    /// Size of the encoding in bytes
+    const SIZE: usize;
+    /// Decode from `SIZE` bytes, None if they are not a valid encoding
+    fn decode(bytes: &[u8]) -> Option<Self>;
+    /// Encode into `SIZE` bytes
+    fn encode(&self, bytes: &mut [u8]);
+}
+
+/// Read the little-endian u32 at `offset`
+pub fn get_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Write `value` as a little-endian u32 at `offset`
+pub fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl OnDisk for [u8; BLOCK_SZ] {
+    const SIZE: usize = BLOCK_SZ;
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(self);
+    }
+}
+
+/// Blocks of little-endian integers, such as bitmaps and indirect blocks
+macro_rules! impl_on_disk_block {
+    ($int:ty) => {
+        impl OnDisk for [$int; BLOCK_SZ / size_of::<$int>()] {
+            const SIZE: usize = BLOCK_SZ;
+            fn decode(bytes: &[u8]) -> Option<Self> {
+                let mut block = [0; BLOCK_SZ / size_of::<$int>()];
+                for (v, chunk) in block.iter_mut().zip(bytes.chunks_exact(size_of::<$int>())) {
+                    *v = <$int>::from_le_bytes(chunk.try_into().unwrap());
+                }
+                Some(block)
+            }
+            fn encode(&self, bytes: &mut [u8]) {
+                for (v, chunk) in self.iter().zip(bytes.chunks_exact_mut(size_of::<$int>())) {
+                    chunk.copy_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+    };
+}
+
+impl_on_disk_block!(u16);
+impl_on_disk_block!(u32);
+impl_on_disk_block!(u64);
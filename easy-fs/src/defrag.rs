@@ -0,0 +1,191 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    Bitmap,
+    EasyFileSystem,
+    SuperBlock,
+    BLOCK_SZ,
+    get_block_cache,
+    block_cache_sync_all,
+};
+
+/// A data block
+type DataBlock = [u8; BLOCK_SZ];
+
+/// How scattered the blocks of the inodes are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// Number of inodes holding blocks
+    pub files: u32,
+    /// Number of inodes whose blocks are not a single contiguous run
+    pub fragmented: u32,
+    /// Number of contiguous runs over all inodes
+    pub extents: u32,
+    /// Number of data and indirect blocks over all inodes
+    pub blocks: u32,
+}
+
+/// Number of contiguous runs in a list of blocks
+fn extents(blocks: &[u32]) -> u32 {
+    if blocks.is_empty() {
+        return 0;
+    }
+    1 + blocks.windows(2).filter(|w| w[1] != w[0] + 1).count() as u32
+}
+
+impl EasyFileSystem {
+    /// Measure how fragmented the inodes are
+    pub fn fragmentation(&self) -> Fragmentation {
+        let _tree = self.tree_lock().read();
+        let inode_bitmap = self.inode_bitmap.lock();
+        let mut fragmentation = Fragmentation::default();
+        for (_, blocks) in self.inode_blocks(&inode_bitmap) {
+            let extents = extents(&blocks);
+            fragmentation.files += 1;
+            if extents > 1 {
+                fragmentation.fragmented += 1;
+            }
+            fragmentation.extents += extents;
+            fragmentation.blocks += blocks.len() as u32;
+        }
+        fragmentation
+    }
+    /// Move the blocks of every inode into a single contiguous run,
+    /// returning the number of blocks moved
+    ///
+    /// Inodes are packed from the start of the data area in inode order,
+    /// each laid out as it would have been allocated in one go, indirect
+    /// blocks included. Blocks of other inodes in the way are moved behind
+    /// it and every pointer to them is rewritten. Inodes sharing blocks with
+    /// a clone or a snapshot stay where they are, as their blocks cannot be
+    /// contiguous for every owner. Blocks in flight are only in memory, so
+    /// this is meant for an image nothing else uses.
+    pub fn defrag(&self) -> u32 {
+        let _tree = self.tree_lock().write();
+        let inode_bitmap = self.inode_bitmap.lock();
+        let data_area = self.data_area();
+        let mut allocated = self.load_allocation(&data_area);
+        let was_allocated = allocated.clone();
+        let inodes = self.inode_blocks(&inode_bitmap);
+        let mut owners: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for (inode_id, blocks) in inodes.iter() {
+            for block_id in blocks.iter() {
+                owners.entry(*block_id).or_default().push(*inode_id);
+            }
+        }
+        let data_end = data_area.start_block + data_area.blocks;
+        // every block before `next` belongs to an inode already packed
+        let mut next = data_area.start_block;
+        let mut moved = 0;
+        for (inode_id, _) in inodes {
+            // earlier moves may have relocated some of its blocks
+            let blocks = self.load_disk_inode(inode_id).all_block_ids(&self.block_device);
+            if blocks.iter().any(|block_id| allocated[block_id] > 0) {
+                continue;
+            }
+            let window = next..next + blocks.len() as u32;
+            next = window.end;
+            let own: BTreeSet<u32> = blocks.iter().copied().collect();
+            let mut remap: BTreeMap<u32, u32> = BTreeMap::new();
+            // blocks of other inodes in the window go to the free blocks
+            // behind it, then to the ones the inode is leaving, which are
+            // always enough as no free block is left before the window
+            let mut free = window.end;
+            let mut leaving = blocks.iter().copied().filter(|block_id| !window.contains(block_id));
+            for block_id in window.clone() {
+                if !allocated.contains_key(&block_id) || own.contains(&block_id) {
+                    continue;
+                }
+                while free < data_end && allocated.contains_key(&free) {
+                    free += 1;
+                }
+                let new_block_id = if free < data_end {
+                    free += 1;
+                    free - 1
+                } else {
+                    leaving.next().unwrap()
+                };
+                remap.insert(block_id, new_block_id);
+            }
+            for (block_id, new_block_id) in blocks.iter().zip(window) {
+                if *block_id != new_block_id {
+                    remap.insert(*block_id, new_block_id);
+                }
+            }
+            moved += remap.len() as u32;
+            self.move_blocks(&remap, &mut allocated, &mut owners);
+        }
+        let (meta_start, bitmap_blocks) = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (
+                    1 + super_block.inode_bitmap_blocks + super_block.inode_area_blocks,
+                    super_block.data_bitmap_blocks,
+                )
+            });
+        // blocks left behind are freed like any other
+        let mut vacated: Vec<u32> = was_allocated
+            .into_keys()
+            .filter(|block_id| !allocated.contains_key(block_id))
+            .collect();
+        self.scrub_data(&mut vacated);
+        self.store_allocation(meta_start, bitmap_blocks, data_area.start_block, &allocated);
+        block_cache_sync_all();
+        moved
+    }
+    /// Get every allocated inode holding blocks, along with all of its
+    /// blocks in the order they were allocated
+    fn inode_blocks(&self, inode_bitmap: &Bitmap) -> Vec<(u32, Vec<u32>)> {
+        (0..inode_bitmap.maximum())
+            .filter(|inode_id| inode_bitmap.is_allocated(&self.block_device, *inode_id))
+            .map(|inode_id| {
+                let disk_inode = self.load_disk_inode(inode_id as u32);
+                (inode_id as u32, disk_inode.all_block_ids(&self.block_device))
+            })
+            .filter(|(_, blocks)| !blocks.is_empty())
+            .collect()
+    }
+    /// Move blocks all at once following `remap`, from old to new block id,
+    /// and rewrite the pointers of their owners
+    fn move_blocks(
+        &self,
+        remap: &BTreeMap<u32, u32>,
+        allocated: &mut BTreeMap<u32, u16>,
+        owners: &mut BTreeMap<u32, Vec<u32>>,
+    ) {
+        let contents: Vec<DataBlock> = remap
+            .keys()
+            .map(|block_id| {
+                get_block_cache(*block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| *data_block)
+            })
+            .collect();
+        for (new_block_id, data) in remap.values().zip(contents.iter()) {
+            get_block_cache(*new_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.copy_from_slice(data));
+        }
+        let moves: Vec<(u32, u16, Vec<u32>)> = remap
+            .iter()
+            .map(|(block_id, new_block_id)| {
+                let refcount = allocated.remove(block_id).unwrap();
+                (*new_block_id, refcount, owners.remove(block_id).unwrap_or_default())
+            })
+            .collect();
+        let mut affected: BTreeSet<u32> = BTreeSet::new();
+        for (new_block_id, refcount, block_owners) in moves {
+            allocated.insert(new_block_id, refcount);
+            affected.extend(block_owners.iter());
+            owners.insert(new_block_id, block_owners);
+        }
+        for inode_id in affected {
+            self.modify_disk_inode(inode_id, |disk_inode| {
+                disk_inode.remap_blocks(&self.block_device, |block_id| {
+                    *remap.get(&block_id).unwrap_or(&block_id)
+                });
+            });
+        }
+    }
+}
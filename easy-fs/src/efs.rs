@@ -1,56 +1,275 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
 use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use super::{
     BlockDevice,
+    BlockError,
+    EfsError,
     Bitmap,
+    BlockRefcount,
     SuperBlock,
+    DirEntry,
     DiskInode,
     DiskInodeType,
     Inode,
+    InodeCache,
+    DIRENT_SZ,
+    DISK_INODE_SZ,
+    FEATURE_INCOMPAT_INLINE_DATA,
+    FEATURE_INCOMPAT_NLINK,
+    FEATURE_INCOMPAT_REFCOUNT,
     get_block_cache,
+    try_get_block_cache,
+    get_zeroed_block_cache,
+    block_cache_discard,
+    block_cache_sync,
     block_cache_sync_all,
 };
+use core::ops::Range;
 use crate::BLOCK_SZ;
 
+/// What to do with the contents of freed data blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscardMode {
+    /// Overwrite them with zeros
+    Zero,
+    /// Discard them on the block device, zeroing those it fails to;
+    /// only for devices which implement discarding
+    Discard,
+    /// Leave them as they are
+    Keep,
+}
+
+/// Options to open a filesystem with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MountOptions {
+    pub discard: DiscardMode,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self { discard: DiscardMode::Zero }
+    }
+}
+
 /// An easy fs over a block device
+///
+/// There is no filesystem-wide mutex. Locks must be taken in this order:
+///
+/// 1. `tree_lock`: shared by operations on one or two inodes, exclusive
+///    for operations walking or rewriting the whole tree (rename, snapshots,
+///    grow, defrag)
+/// 2. inode locks, owned by the `Inode`s, in ascending inode id order
+/// 3. `inode_bitmap`
+/// 4. `data_area`, guarding the data bitmap and the block refcounts
+/// 5. block caches; an inode block may only be held while locking the
+///    indirect and data blocks it points to, writers work on a copy taken
+///    by `load_disk_inode` instead
+/// 6. `inodes`, nothing else is locked while it is held
+///
+/// Holders of the exclusive `tree_lock` skip the inode locks.
+///
+/// An `Inode` may be dropped under any of these locks. An orphan it was
+/// the last user of is freed under the shared `tree_lock`, or once the
+/// exclusive one is released.
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
-    pub inode_bitmap: Bitmap,
-    pub data_bitmap: Bitmap,
+    pub inode_bitmap: Mutex<Bitmap>,
+    data_area: Mutex<DataArea>,
     pub inode_area_start_block: u32,
-    data_area_start_block: u32,
+    tree_lock: RwLock<()>,
+    inodes: InodeCache,
+    /// New inodes start with inline data
+    inline_data: bool,
+    /// Disk inodes hold their link count, otherwise links are counted
+    /// by walking the tree
+    link_counts: bool,
+    /// What happens to freed data blocks
+    discard: DiscardMode,
+}
+
+/// Space usage of a filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Blocks of the whole image
+    pub total_blocks: u32,
+    /// Blocks of the data area
+    pub data_blocks: u32,
+    pub free_data_blocks: u32,
+    /// Inodes the inode bitmap can hold
+    pub inodes: u32,
+    pub free_inodes: u32,
+}
+
+/// Allocation state of the data area
+pub(crate) struct DataArea {
+    pub bitmap: Bitmap,
+    pub refcount: BlockRefcount,
+    pub start_block: u32,
+    /// Number of blocks in the data area, the bitmap may hold more bits
+    pub blocks: u32,
+    /// Number of free blocks, kept along with the bitmap by `alloc`,
+    /// `alloc_run` and `dealloc`
+    pub free: u32,
+}
+
+impl DataArea {
+    /// Allocate a data block, returning its block id
+    pub fn alloc(&mut self, block_device: &Arc<dyn BlockDevice>) -> Option<u32> {
+        let pos = self.bitmap.alloc(block_device, self.blocks as usize)?;
+        self.free -= 1;
+        Some(pos as u32 + self.start_block)
+    }
+    /// Allocate up to `len` contiguous blocks as `Bitmap::alloc_run` does,
+    /// returning (first block id, number of blocks allocated)
+    pub fn alloc_run(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: u32,
+        len: u32,
+    ) -> Option<(u32, u32)> {
+        let goal = goal.saturating_sub(self.start_block) as usize;
+        let (start, len) =
+            self.bitmap.alloc_run(block_device, goal, self.blocks as usize, len as usize)?;
+        self.free -= len as u32;
+        Some((start as u32 + self.start_block, len as u32))
+    }
+    /// Free a data block
+    pub fn dealloc(&mut self, block_device: &Arc<dyn BlockDevice>, block_id: u32) {
+        self.bitmap.dealloc(block_device, (block_id - self.start_block) as usize);
+        self.free += 1;
+    }
 }
 
 /// A data block of block size
 type DataBlock = [u8; BLOCK_SZ];
 
+/// The tree lock of a filesystem, see `EasyFileSystem`
+pub(crate) struct TreeLock<'a> {
+    fs: &'a EasyFileSystem,
+}
+
+impl<'a> TreeLock<'a> {
+    pub fn read(self) -> RwLockReadGuard<'a, ()> {
+        self.fs.tree_lock.read()
+    }
+    pub fn write(self) -> TreeWriteGuard<'a> {
+        TreeWriteGuard {
+            fs: self.fs,
+            guard: Some(self.fs.tree_lock.write()),
+        }
+    }
+}
+
+/// Exclusive hold of the tree lock, freeing the orphans released
+/// meanwhile when dropped
+pub(crate) struct TreeWriteGuard<'a> {
+    fs: &'a EasyFileSystem,
+    guard: Option<RwLockWriteGuard<'a, ()>>,
+}
+
+impl Drop for TreeWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.fs.free_released_inodes();
+    }
+}
+
+/// Split the blocks behind the inode area into
+/// (data bitmap blocks, refcount blocks, data area blocks)
+pub(crate) fn data_area_layout(data_total_blocks: u32, with_refcount: bool) -> (u32, u32, u32) {
+    if with_refcount {
+        // every 4096 data blocks need 1 bitmap block and 16 refcount blocks
+        let data_groups = (data_total_blocks + 4112) / 4113;
+        let data_bitmap_blocks = data_groups;
+        let refcount_blocks = data_groups * 16;
+        (
+            data_bitmap_blocks,
+            refcount_blocks,
+            data_total_blocks - data_bitmap_blocks - refcount_blocks,
+        )
+    } else {
+        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        (data_bitmap_blocks, 0, data_total_blocks - data_bitmap_blocks)
+    }
+}
+
 impl EasyFileSystem {
+    /// A filesystem over the given areas, nothing is written
+    fn new(
+        block_device: Arc<dyn BlockDevice>,
+        inode_bitmap: Bitmap,
+        inode_area_start_block: u32,
+        data_area: DataArea,
+        feature_incompat: u32,
+        options: MountOptions,
+    ) -> Self {
+        Self {
+            block_device,
+            inode_bitmap: Mutex::new(inode_bitmap),
+            data_area: Mutex::new(data_area),
+            inode_area_start_block,
+            tree_lock: RwLock::new(()),
+            inodes: InodeCache::new(),
+            inline_data: feature_incompat & FEATURE_INCOMPAT_INLINE_DATA != 0,
+            link_counts: feature_incompat & FEATURE_INCOMPAT_NLINK != 0,
+            discard: options.discard,
+        }
+    }
+    /// Get the number of blocks holding the inodes an inode bitmap can allocate
+    fn inode_area_blocks(inode_bitmap: &Bitmap) -> u32 {
+        ((inode_bitmap.maximum() * DISK_INODE_SZ + BLOCK_SZ - 1) / BLOCK_SZ) as u32
+    }
+    /// Get the smallest `total_blocks` to pass to `create` along with
+    /// `inode_bitmap_blocks` for the data area to hold `data_blocks` blocks
+    pub fn min_total_blocks(inode_bitmap_blocks: u32, data_blocks: u32) -> u32 {
+        let inode_area_blocks =
+            Self::inode_area_blocks(&Bitmap::new(1, inode_bitmap_blocks as usize));
+        // a data area needs at least one bitmap block and 16 refcount blocks
+        let mut data_total_blocks = data_blocks + 17;
+        while data_area_layout(data_total_blocks, true).2 < data_blocks {
+            data_total_blocks += 1;
+        }
+        1 + inode_bitmap_blocks + inode_area_blocks + data_total_blocks
+    }
     /// Create a filesystem from a block device
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+    ) -> Arc<Self> {
         // calculate block size of areas & create bitmaps
         let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
-        let inode_num = inode_bitmap.maximum();
-        let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        let inode_area_blocks = Self::inode_area_blocks(&inode_bitmap);
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
         let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
-        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
+        let (data_bitmap_blocks, refcount_blocks, data_area_blocks) =
+            data_area_layout(data_total_blocks, true);
         let data_bitmap = Bitmap::new(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
         );
-        let mut efs = Self {
-            block_device: Arc::clone(&block_device),
+        let block_refcount = BlockRefcount::new(
+            (1 + inode_total_blocks + data_bitmap_blocks) as usize,
+            refcount_blocks as usize,
+        );
+        assert!(block_refcount.maximum() >= data_area_blocks as usize);
+        let efs = Self::new(
+            Arc::clone(&block_device),
             inode_bitmap,
-            data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
-        };
+            1 + inode_bitmap_blocks,
+            DataArea {
+                bitmap: data_bitmap,
+                refcount: block_refcount,
+                start_block: 1 + inode_total_blocks + data_bitmap_blocks + refcount_blocks,
+                blocks: data_area_blocks,
+                free: data_area_blocks,
+            },
+            FEATURE_INCOMPAT_INLINE_DATA | FEATURE_INCOMPAT_NLINK,
+            MountOptions::default(),
+        );
         // clear all blocks
         for i in 0..total_blocks {
             get_block_cache(
@@ -72,6 +291,7 @@ impl EasyFileSystem {
                 inode_area_blocks,
                 data_bitmap_blocks,
                 data_area_blocks,
+                refcount_blocks,
             );
         });
         // write back immediately
@@ -85,80 +305,775 @@ impl EasyFileSystem {
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
             disk_inode.initialize(DiskInodeType::Directory);
+            disk_inode.set_inline();
         });
         block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        Arc::new(efs)
     }
     /// Open a block device as a filesystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Self::open_with_options(block_device, MountOptions::default())
+    }
+    /// Open a block device as a filesystem with the given options
+    pub fn open_with_options(
+        block_device: Arc<dyn BlockDevice>,
+        options: MountOptions,
+    ) -> Arc<Self> {
+        match Self::try_open_with_options(block_device, options) {
+            Ok(efs) => efs,
+            Err(EfsError::NotEfs) => panic!("Error loading EFS!"),
+            Err(EfsError::Unsupported) => panic!("Unsupported EFS version or features!"),
+            Err(err) => panic!("Error loading EFS: {}", err),
+        }
+    }
+    /// Open a block device as a filesystem, or tell why it can't be
+    pub fn try_open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, EfsError> {
+        Self::try_open_with_options(block_device, MountOptions::default())
+    }
+    /// Open a block device as a filesystem with the given options, or
+    /// tell why it can't be
+    ///
+    /// The areas the super block describes are checked to fit in the
+    /// device, and the root inode to be a directory. Discarding freed
+    /// blocks on a device which can't fails with `BlockError::Unsupported`.
+    pub fn try_open_with_options(
+        block_device: Arc<dyn BlockDevice>,
+        options: MountOptions,
+    ) -> Result<Arc<Self>, EfsError> {
+        if options.discard == DiscardMode::Discard && !block_device.can_discard() {
+            return Err(EfsError::Device(BlockError::Unsupported));
+        }
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        let (efs, total_blocks) = try_get_block_cache(0, Arc::clone(&block_device))?
             .lock()
             .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+                if !super_block.is_valid() {
+                    return Err(EfsError::NotEfs);
+                }
+                if !super_block.is_supported() {
+                    return Err(EfsError::Unsupported);
+                }
+                Self::check_layout(super_block)?;
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
+                let efs = Self::new(
                     block_device,
-                    inode_bitmap: Bitmap::new(
+                    Bitmap::new(
                         1,
                         super_block.inode_bitmap_blocks as usize
                     ),
-                    data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
-                        super_block.data_bitmap_blocks as usize,
-                    ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
+                    1 + super_block.inode_bitmap_blocks,
+                    DataArea {
+                        bitmap: Bitmap::new(
+                            (1 + inode_total_blocks) as usize,
+                            super_block.data_bitmap_blocks as usize,
+                        ),
+                        refcount: BlockRefcount::new(
+                            (1 + inode_total_blocks + super_block.data_bitmap_blocks) as usize,
+                            super_block.refcount_blocks as usize,
+                        ),
+                        start_block: 1 + inode_total_blocks
+                            + super_block.data_bitmap_blocks
+                            + super_block.refcount_blocks,
+                        blocks: super_block.data_area_blocks,
+                        // counted below
+                        free: 0,
+                    },
+                    super_block.feature_incompat(),
+                    options,
+                );
+                Ok((Arc::new(efs), super_block.total_blocks))
+            })?;
+        // the device must hold the whole image
+        try_get_block_cache(total_blocks as usize - 1, Arc::clone(&efs.block_device))?;
+        // the bitmap is counted once, the count is kept from then on
+        let mut data_area = efs.data_area();
+        let allocated: usize = data_area
+            .bitmap
+            .allocated_runs(&efs.block_device)?
+            .iter()
+            .map(|(_, len)| len)
+            .sum();
+        data_area.free = data_area.blocks.saturating_sub(allocated as u32);
+        drop(data_area);
+        if !efs.try_load_disk_inode(0)?.is_dir() {
+            return Err(EfsError::Corrupted("root is not a directory"));
+        }
+        Ok(efs)
+    }
+    /// Check that the areas of a super block fit in the image and are
+    /// large enough for what they hold
+    fn check_layout(super_block: &SuperBlock) -> Result<(), EfsError> {
+        let blocks = |count: u32| count as u64;
+        let areas = 1
+            + blocks(super_block.inode_bitmap_blocks)
+            + blocks(super_block.inode_area_blocks)
+            + blocks(super_block.data_bitmap_blocks)
+            + blocks(super_block.refcount_blocks)
+            + blocks(super_block.data_area_blocks);
+        if areas > blocks(super_block.total_blocks) {
+            return Err(EfsError::Corrupted("areas larger than the image"));
+        }
+        let bits = (BLOCK_SZ * 8) as u64;
+        let inodes = blocks(super_block.inode_bitmap_blocks) * bits;
+        if inodes * DISK_INODE_SZ as u64 > blocks(super_block.inode_area_blocks) * BLOCK_SZ as u64 {
+            return Err(EfsError::Corrupted("inode area smaller than its bitmap"));
+        }
+        if super_block.snapshot_inode != 0 && blocks(super_block.snapshot_inode) >= inodes {
+            return Err(EfsError::Corrupted("snapshot inode out of range"));
+        }
+        if blocks(super_block.data_bitmap_blocks) * bits < blocks(super_block.data_area_blocks) {
+            return Err(EfsError::Corrupted("data bitmap smaller than the data area"));
+        }
+        let refcount = BlockRefcount::new(0, super_block.refcount_blocks as usize);
+        if (super_block.feature_incompat() & FEATURE_INCOMPAT_REFCOUNT != 0) != refcount.is_enabled()
+            || (refcount.is_enabled()
+                && (refcount.maximum() as u64) < blocks(super_block.data_area_blocks))
+        {
+            return Err(EfsError::Corrupted("refcount area does not match the data area"));
+        }
+        Ok(())
+    }
+    /// Get the format version of the image on a block device
+    pub fn version(block_device: &Arc<dyn BlockDevice>) -> Result<u32, EfsError> {
+        try_get_block_cache(0, Arc::clone(block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                if !super_block.is_valid() {
+                    return Err(EfsError::NotEfs);
+                }
+                Ok(super_block.version())
             })
     }
+    /// Convert the image on a block device in place to another format version
+    ///
+    /// Inline data is moved to blocks for versions without it, link counts
+    /// are computed or cleared as versions gain or lose them, otherwise only
+    /// the super block changes. The image must not be open. Fails, leaving
+    /// the image as is, with `Unsupported` if this implementation does not
+    /// support the image or the target version cannot express the features
+    /// the image uses, and with `NoSpace` if the inline data does not fit
+    /// in the free blocks. The new version is written last.
+    pub fn set_version(block_device: &Arc<dyn BlockDevice>, version: u32) -> Result<(), EfsError> {
+        let incompat = try_get_block_cache(0, Arc::clone(block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                if !super_block.is_valid() {
+                    return Err(EfsError::NotEfs);
+                }
+                Ok(super_block.feature_incompat())
+            })?;
+        let spill = version == 1 && incompat & FEATURE_INCOMPAT_INLINE_DATA != 0;
+        let drop_links = version < 3 && incompat & FEATURE_INCOMPAT_NLINK != 0;
+        let count_links = version >= 3 && incompat & FEATURE_INCOMPAT_NLINK == 0;
+        let mut dropped = 0;
+        if spill {
+            dropped |= FEATURE_INCOMPAT_INLINE_DATA;
+        }
+        if drop_links {
+            dropped |= FEATURE_INCOMPAT_NLINK;
+        }
+        let convertible = get_block_cache(0, Arc::clone(block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.can_set_version(version, dropped));
+        if !convertible {
+            return Err(EfsError::Unsupported);
+        }
+        if spill || drop_links || count_links {
+            let efs = Self::try_open(Arc::clone(block_device))?;
+            if spill {
+                efs.spill_inline_data()?;
+            }
+            if drop_links {
+                efs.drop_link_counts();
+            }
+            if count_links {
+                efs.store_link_counts();
+            }
+        }
+        get_block_cache(0, Arc::clone(block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.remove_feature_incompat(dropped);
+                assert!(super_block.set_version(version));
+            });
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Move the data of all inline inodes to blocks, failing with nothing
+    /// moved if there are not enough free blocks; the super block is left
+    /// to `set_version`
+    fn spill_inline_data(&self) -> Result<(), EfsError> {
+        let _tree = self.tree_lock().write();
+        let inode_bitmap = self.inode_bitmap.lock();
+        let mut allocated = Vec::new();
+        for inode_id in 0..inode_bitmap.maximum() {
+            if inode_bitmap.try_is_allocated(&self.block_device, inode_id)? {
+                allocated.push(inode_id as u32);
+            }
+        }
+        drop(inode_bitmap);
+        let mut inline = Vec::new();
+        for inode_id in allocated {
+            let disk_inode = self.try_load_disk_inode(inode_id)?;
+            if disk_inode.is_inline() {
+                inline.push((inode_id, disk_inode));
+            }
+        }
+        let needed: u32 = inline
+            .iter()
+            .map(|(_, disk_inode)| DiskInode::total_blocks(disk_inode.size))
+            .sum();
+        if needed > self.free_data_blocks() {
+            return Err(EfsError::NoSpace);
+        }
+        for (inode_id, mut disk_inode) in inline {
+            let v = self
+                .alloc_data_run(0, DiskInode::total_blocks(disk_inode.size))
+                .ok_or(EfsError::NoSpace)?;
+            disk_inode.spill_inline(disk_inode.size, v, &self.block_device);
+            self.store_disk_inode(inode_id, disk_inode);
+        }
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Count the entries linking to every inode and store the counts in
+    /// the disk inodes; the super block is left to `set_version`
+    fn store_link_counts(&self) {
+        let _tree = self.tree_lock().write();
+        let inode_bitmap = self.inode_bitmap.lock();
+        let allocated: Vec<u32> = (0..inode_bitmap.maximum())
+            .filter(|inode_id| inode_bitmap.is_allocated(&self.block_device, *inode_id))
+            .map(|inode_id| inode_id as u32)
+            .collect();
+        drop(inode_bitmap);
+        let mut links: BTreeMap<u32, u16> = BTreeMap::new();
+        for dir_id in allocated.iter() {
+            if !self.read_disk_inode(*dir_id, |disk_inode| disk_inode.is_dir()) {
+                continue;
+            }
+            for (name, child_id) in self.dir_entries(*dir_id) {
+                if name != "." && name != ".." {
+                    let count = links.entry(child_id).or_insert(0);
+                    *count = count.saturating_add(1);
+                }
+            }
+        }
+        for inode_id in allocated {
+            let nlink = links.get(&inode_id).copied().unwrap_or(0);
+            self.modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink = nlink);
+        }
+        block_cache_sync_all();
+    }
+    /// Stop keeping link counts and clear them in every inode
+    ///
+    /// The feature goes first, as counts cleared under it would be taken
+    /// for real ones; the rest of the super block is left to `set_version`.
+    fn drop_link_counts(&self) {
+        let _tree = self.tree_lock().write();
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.remove_feature_incompat(FEATURE_INCOMPAT_NLINK);
+            });
+        block_cache_sync_all();
+        let inode_bitmap = self.inode_bitmap.lock();
+        for inode_id in 0..inode_bitmap.maximum() {
+            if inode_bitmap.is_allocated(&self.block_device, inode_id) {
+                self.modify_disk_inode(inode_id as u32, |disk_inode| disk_inode.nlink = 0);
+            }
+        }
+        block_cache_sync_all();
+    }
+    /// Write every cached block of the filesystem back to the device,
+    /// returning the first error; blocks that fail stay cached and dirty
+    pub fn sync(&self) -> Result<(), BlockError> {
+        block_cache_sync(&self.block_device)
+    }
     /// Get the root inode of the filesystem
-    pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
-        let block_device = Arc::clone(&efs.lock().block_device);
-        // acquire efs lock temporarily
-        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
-        // release efs lock
-        Inode::new(
-            block_id,
-            block_offset,
-            Arc::clone(efs),
-            block_device,
-        )
+    pub fn root_inode(efs: &Arc<Self>) -> Arc<Inode> {
+        Self::get_inode(efs, 0)
+    }
+    /// Get the inode with the given id, shared with every other
+    /// user of that inode
+    pub fn get_inode(efs: &Arc<Self>, inode_id: u32) -> Arc<Inode> {
+        efs.inodes
+            .get_or_insert(inode_id, || Inode::new(inode_id, Arc::clone(efs)))
+    }
+    /// Drop the cache entry of an inode nobody refers to anymore,
+    /// freeing it if it lost its last link while in use
+    ///
+    /// The tree lock may be held exclusively by this very thread, so it
+    /// is not waited for: the holder frees the inode when releasing it.
+    pub(crate) fn release_inode(&self, inode_id: u32) {
+        if self.inodes.release(inode_id) {
+            self.free_released_inodes();
+        }
+    }
+    /// Free the orphans evicted from the inode cache, unless somebody
+    /// holds the tree lock exclusively
+    fn free_released_inodes(&self) {
+        let _tree = match self.tree_lock.try_read() {
+            Some(tree) => tree,
+            None => return,
+        };
+        let released = self.inodes.take_released();
+        if released.is_empty() {
+            return;
+        }
+        for inode_id in released {
+            self.free_inode(inode_id);
+        }
+        block_cache_sync_all();
+    }
+    /// Number of inodes currently in use
+    pub fn inodes_in_use(&self) -> usize {
+        self.inodes.len()
+    }
+    /// Lock order position 1, see `EasyFileSystem`
+    pub(crate) fn tree_lock(&self) -> TreeLock<'_> {
+        TreeLock { fs: self }
+    }
+    /// Lock order position 4
+    pub(crate) fn data_area(&self) -> MutexGuard<DataArea> {
+        self.data_area.lock()
     }
     /// Get inode by id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
-        let inode_size = core::mem::size_of::<DiskInode>();
+        let inode_size = DISK_INODE_SZ;
         let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (block_id, (inode_id % inodes_per_block) as usize * inode_size)
     }
+    /// Get the ids of the blocks of the data area
+    pub(crate) fn data_area_range(&self) -> Range<u32> {
+        let data_area = self.data_area();
+        data_area.start_block..data_area.start_block + data_area.blocks
+    }
     /// Get data block by id
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
-        self.data_area_start_block + data_block_id
+        self.data_area().start_block + data_block_id
     }
     /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&self) -> u32 {
+        let inode_bitmap = self.inode_bitmap.lock();
+        inode_bitmap.alloc(&self.block_device, inode_bitmap.maximum()).unwrap() as u32
     }
-    /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// Allocate a zeroed data block
+    pub fn alloc_data(&self) -> u32 {
+        let block_id = self.data_area().alloc(&self.block_device).unwrap();
+        // freed blocks are not necessarily zeroed
+        get_zeroed_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        block_id
     }
-    /// Deallocate a data block
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(
-            block_id as usize,
-            Arc::clone(&self.block_device)
-        )
-        .lock()
-        .modify(0, |data_block: &mut DataBlock| {
-            data_block.iter_mut().for_each(|p| { *p = 0; })
+    /// Allocate `count` zeroed data blocks in as few contiguous runs as possible,
+    /// the first one starting at or after block `goal`; None, with nothing
+    /// allocated, if fewer than `count` blocks are free
+    pub fn alloc_data_run(&self, goal: u32, count: u32) -> Option<Vec<u32>> {
+        let mut data_area = self.data_area();
+        if data_area.free < count {
+            return None;
+        }
+        let mut v = Vec::with_capacity(count as usize);
+        let mut goal = goal;
+        while v.len() < count as usize {
+            let (start, len) = data_area
+                .alloc_run(&self.block_device, goal, count - v.len() as u32)
+                .unwrap();
+            v.extend(start..start + len);
+            goal = start + len;
+        }
+        for block_id in v.iter() {
+            get_zeroed_block_cache(*block_id as usize, Arc::clone(&self.block_device));
+        }
+        Some(v)
+    }
+    /// Allocate the blocks a disk inode needs to grow to `new_size`,
+    /// right behind its last data block when possible
+    pub(crate) fn alloc_blocks_for(&self, disk_inode: &DiskInode, new_size: u32) -> Option<Vec<u32>> {
+        let goal = match disk_inode.data_blocks() {
+            0 => 0,
+            n => disk_inode.get_block_id(n - 1, &self.block_device) + 1,
+        };
+        self.alloc_data_run(goal, disk_inode.blocks_num_needed(new_size))
+    }
+    /// Get the number of free data blocks
+    pub fn free_data_blocks(&self) -> u32 {
+        self.data_area().free
+    }
+    /// Get the space usage of the filesystem
+    pub fn usage(&self) -> Usage {
+        let total_blocks = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.total_blocks);
+        let inode_bitmap = self.inode_bitmap.lock();
+        let inodes = inode_bitmap.maximum() as u32;
+        let used_inodes = inode_bitmap.count_allocated(&self.block_device) as u32;
+        let data_area = self.data_area();
+        Usage {
+            total_blocks,
+            data_blocks: data_area.blocks,
+            free_data_blocks: data_area.free,
+            inodes,
+            free_inodes: inodes - used_inodes,
+        }
+    }
+    /// Deallocate an inode
+    pub fn dealloc_inode(&self, inode_id: u32) {
+        self.inode_bitmap.lock().dealloc(&self.block_device, inode_id as usize)
+    }
+    /// Whether emptied inodes should store their data inline
+    pub(crate) fn inline_data(&self) -> bool {
+        self.inline_data
+    }
+    /// Whether data blocks can be shared between inodes
+    pub fn cow_enabled(&self) -> bool {
+        self.data_area().refcount.is_enabled()
+    }
+    /// Add an owner to a data block, or copy it if it cannot be shared
+    /// any further; returns the block the new owner should point to
+    pub fn share_data(&self, block_id: u32) -> u32 {
+        let mut data_area = self.data_area();
+        let pos = (block_id - data_area.start_block) as usize;
+        if data_area.refcount.inc(&self.block_device, pos) {
+            block_id
+        } else {
+            let new_block_id = data_area.alloc(&self.block_device).unwrap();
+            self.copy_data(block_id, new_block_id);
+            new_block_id
+        }
+    }
+    /// Count the blocks holding bytes `range` of a disk inode which are
+    /// shared, and would be copied by `cow_data` when written
+    pub(crate) fn shared_blocks(&self, disk_inode: &DiskInode, range: Range<usize>) -> u32 {
+        if range.is_empty() {
+            return 0;
+        }
+        let blocks = range.start / BLOCK_SZ
+            ..((range.end + BLOCK_SZ - 1) / BLOCK_SZ).min(disk_inode.data_blocks() as usize);
+        let data_area = self.data_area();
+        blocks
+            .filter(|inner_id| {
+                let block_id = disk_inode.get_block_id(*inner_id as u32, &self.block_device);
+                let pos = (block_id - data_area.start_block) as usize;
+                data_area.refcount.get(&self.block_device, pos) > 0
+            })
+            .count() as u32
+    }
+    /// Get a block private to the caller before writing into `block_id`
+    ///
+    /// A shared block is copied into one of the `reserved` blocks, or a
+    /// newly allocated one if none is left, and the caller gives up its
+    /// reference on the original
+    pub fn cow_data(&self, block_id: u32, reserved: &mut Vec<u32>) -> u32 {
+        let mut data_area = self.data_area();
+        let pos = (block_id - data_area.start_block) as usize;
+        if data_area.refcount.get(&self.block_device, pos) == 0 {
+            return block_id;
+        }
+        let new_block_id = reserved
+            .pop()
+            .unwrap_or_else(|| data_area.alloc(&self.block_device).unwrap());
+        self.copy_data(block_id, new_block_id);
+        data_area.refcount.dec(&self.block_device, pos);
+        new_block_id
+    }
+    /// Copy data block `block_id` into `new_block_id`
+    fn copy_data(&self, block_id: u32, new_block_id: u32) {
+        let mut data = [0u8; BLOCK_SZ];
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |data_block: &DataBlock| data.copy_from_slice(data_block));
+        get_block_cache(new_block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| data_block.copy_from_slice(&data));
+    }
+    /// Deallocate a data block, or drop one owner if it is shared
+    pub fn dealloc_data(&self, block_id: u32) {
+        self.dealloc_data_blocks(Vec::from([block_id]));
+    }
+    /// Deallocate data blocks, or drop one owner of those which are shared
+    pub fn dealloc_data_blocks(&self, block_ids: Vec<u32>) {
+        let mut data_area = self.data_area();
+        let mut freed = Vec::new();
+        for block_id in block_ids {
+            let pos = (block_id - data_area.start_block) as usize;
+            if data_area.refcount.get(&self.block_device, pos) > 0 {
+                data_area.refcount.dec(&self.block_device, pos);
+            } else {
+                freed.push(block_id);
+            }
+        }
+        self.scrub_data(&mut freed);
+        for block_id in freed {
+            data_area.dealloc(&self.block_device, block_id);
+        }
+    }
+    /// Get rid of the contents of freed data blocks as the mount options say,
+    /// the caller holds `data_area`
+    pub(crate) fn scrub_data(&self, block_ids: &mut [u32]) {
+        match self.discard {
+            DiscardMode::Zero => self.zero_data(block_ids),
+            DiscardMode::Discard => {
+                block_ids.sort_unstable();
+                let mut i = 0;
+                while i < block_ids.len() {
+                    let mut j = i + 1;
+                    while j < block_ids.len() && block_ids[j] == block_ids[j - 1] + 1 {
+                        j += 1;
+                    }
+                    let range = block_ids[i] as usize..block_ids[j - 1] as usize + 1;
+                    block_cache_discard(&self.block_device, range.clone());
+                    if self.block_device.discard(range).is_err() {
+                        self.zero_data(&block_ids[i..j]);
+                    }
+                    i = j;
+                }
+            }
+            DiscardMode::Keep => {}
+        }
+    }
+    /// Overwrite data blocks with zeros
+    fn zero_data(&self, block_ids: &[u32]) {
+        for block_id in block_ids {
+            get_zeroed_block_cache(*block_id as usize, Arc::clone(&self.block_device));
+        }
+    }
+    /// Call a function over the disk inode with the given id to read it
+    pub(crate) fn read_disk_inode<V>(
+        &self,
+        inode_id: u32,
+        f: impl FnOnce(&DiskInode) -> V,
+    ) -> V {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, f)
+    }
+    /// Call a function over the disk inode with the given id to modify it
+    pub(crate) fn modify_disk_inode<V>(
+        &self,
+        inode_id: u32,
+        f: impl FnOnce(&mut DiskInode) -> V,
+    ) -> V {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(block_offset, f)
+    }
+    /// Get a copy of the disk inode with the given id, so that its data can be
+    /// accessed without holding the cache of a block shared with other inodes
+    pub(crate) fn load_disk_inode(&self, inode_id: u32) -> DiskInode {
+        self.read_disk_inode(inode_id, |disk_inode| disk_inode.clone())
+    }
+    /// Check that an inode id read from the image is in range
+    pub(crate) fn check_inode_id(&self, inode_id: u32) -> Result<(), EfsError> {
+        if inode_id as usize >= self.inode_bitmap.lock().maximum() {
+            return Err(EfsError::Corrupted("inode id out of range"));
+        }
+        Ok(())
+    }
+    /// Get a copy of the disk inode with the given id, checking that the
+    /// id is in range and the inode valid
+    pub(crate) fn try_load_disk_inode(&self, inode_id: u32) -> Result<DiskInode, EfsError> {
+        self.check_inode_id(inode_id)?;
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        try_get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .try_read(block_offset, |disk_inode: &DiskInode| disk_inode.clone())
+            .ok_or(EfsError::Corrupted("invalid inode"))
+    }
+    /// Write back a copy of a disk inode taken by `load_disk_inode`
+    pub(crate) fn store_disk_inode(&self, inode_id: u32, disk_inode: DiskInode) {
+        self.modify_disk_inode(inode_id, |old_disk_inode| *old_disk_inode = disk_inode);
+    }
+    /// Allocate and initialize a new inode
+    pub(crate) fn new_inode(&self, type_: DiskInodeType) -> u32 {
+        let inode_id = self.alloc_inode();
+        self.modify_disk_inode(inode_id, |disk_inode| {
+            disk_inode.initialize(type_);
+            if self.inline_data {
+                disk_inode.set_inline();
+            }
         });
-        self.data_bitmap.dealloc(
+        inode_id
+    }
+    /// Get the live (name, inode number) entries of a directory
+    pub(crate) fn dir_entries(&self, dir_id: u32) -> Vec<(String, u32)> {
+        self.read_disk_inode(dir_id, |disk_inode| {
+            assert!(disk_inode.is_dir());
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let mut v = Vec::new();
+            for i in 0..file_count {
+                let dirent = disk_inode.read_dirent(i * DIRENT_SZ, &self.block_device);
+                if !dirent.name().is_empty() {
+                    v.push((String::from(dirent.name()), dirent.inode_number()));
+                }
+            }
+            v
+        })
+    }
+    /// Append an entry to a directory
+    pub(crate) fn push_dir_entry(&self, dir_id: u32, name: &str, inode_id: u32) {
+        let mut disk_inode = self.load_disk_inode(dir_id);
+        let size = disk_inode.size;
+        let new_size = size + DIRENT_SZ as u32;
+        let v = self.alloc_blocks_for(&disk_inode, new_size).unwrap();
+        disk_inode.increase_size(new_size, v, &self.block_device);
+        let dirent = DirEntry::new(name, inode_id);
+        disk_inode.write_at(
+            size as usize,
+            &dirent.to_bytes(),
             &self.block_device,
-            (block_id - self.data_area_start_block) as usize
-        )
+            |block_id| self.cow_data(block_id, &mut Vec::new()),
+        );
+        self.store_disk_inode(dir_id, disk_inode);
+        if name != "." && name != ".." {
+            self.add_link(inode_id);
+        }
+    }
+    /// Replace the first entry of a directory named `name` by `dirent`,
+    /// returning the inode number it used to point to
+    ///
+    /// The link counts follow, an empty `dirent` links to nothing.
+    pub(crate) fn rewrite_dir_entry(
+        &self,
+        dir_id: u32,
+        name: &str,
+        dirent: &DirEntry,
+    ) -> Option<u32> {
+        let mut disk_inode = self.load_disk_inode(dir_id);
+        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        for i in 0..file_count {
+            let old_dirent = disk_inode.read_dirent(i * DIRENT_SZ, &self.block_device);
+            if old_dirent.name() == name {
+                disk_inode.write_at(
+                    i * DIRENT_SZ,
+                    &dirent.to_bytes(),
+                    &self.block_device,
+                    |block_id| self.cow_data(block_id, &mut Vec::new()),
+                );
+                self.store_disk_inode(dir_id, disk_inode);
+                if name != "." && name != ".." {
+                    self.remove_link(old_dirent.inode_number());
+                    if !dirent.name().is_empty() {
+                        self.add_link(dirent.inode_number());
+                    }
+                }
+                return Some(old_dirent.inode_number());
+            }
+        }
+        None
+    }
+    /// Blank the first entry of a directory with the given name
+    pub(crate) fn remove_dir_entry(&self, dir_id: u32, name: &str) -> Option<u32> {
+        self.rewrite_dir_entry(dir_id, name, &DirEntry::empty())
+    }
+    /// Release the data blocks and the inode itself
+    pub(crate) fn free_inode(&self, inode_id: u32) {
+        let block_device = Arc::clone(&self.block_device);
+        let data_blocks_dealloc =
+            self.modify_disk_inode(inode_id, |disk_inode| disk_inode.clear_size(&block_device));
+        self.dealloc_data_blocks(data_blocks_dealloc);
+        self.dealloc_inode(inode_id);
+    }
+    /// Free an inode which lost its last link, or leave it to
+    /// `release_inode` if an `Inode` still refers to it
+    ///
+    /// Until then its id is not reused and its data stays readable and
+    /// writable through the `Inode`s held.
+    pub(crate) fn free_unlinked_inode(&self, inode_id: u32) {
+        if !self.inodes.orphan(inode_id) {
+            self.free_inode(inode_id);
+        }
+    }
+    /// Whether `inode_id` is `root_id` or lives in the tree below it
+    pub(crate) fn in_subtree(&self, root_id: u32, inode_id: u32) -> bool {
+        let mut visited: BTreeSet<u32> = BTreeSet::new();
+        let mut stack: Vec<u32> = Vec::from([root_id]);
+        while let Some(dir_id) = stack.pop() {
+            if dir_id == inode_id {
+                return true;
+            }
+            if visited.contains(&dir_id)
+                || !self.read_disk_inode(dir_id, |disk_inode| disk_inode.is_dir())
+            {
+                continue;
+            }
+            visited.insert(dir_id);
+            stack.extend(
+                self.dir_entries(dir_id)
+                    .into_iter()
+                    .filter(|(name, _)| name != "." && name != "..")
+                    .map(|(_, child_id)| child_id),
+            );
+        }
+        false
+    }
+    /// Whether a directory holds nothing but "." and ".."
+    pub(crate) fn is_empty_dir(&self, dir_id: u32) -> bool {
+        self.dir_entries(dir_id)
+            .iter()
+            .all(|(name, _)| name == "." || name == "..")
+    }
+    /// Count one more entry linking to an inode, if disk inodes keep count
+    fn add_link(&self, inode_id: u32) {
+        if self.link_counts {
+            self.modify_disk_inode(inode_id, |disk_inode| {
+                disk_inode.nlink = disk_inode.nlink.saturating_add(1);
+            });
+        }
+    }
+    /// Count one entry less linking to an inode, if disk inodes keep count
+    fn remove_link(&self, inode_id: u32) {
+        if self.link_counts {
+            self.modify_disk_inode(inode_id, |disk_inode| {
+                disk_inode.nlink = disk_inode.nlink.saturating_sub(1);
+            });
+        }
+    }
+    /// Whether disk inodes hold their link count
+    pub(crate) fn link_counts(&self) -> bool {
+        self.link_counts
+    }
+    /// Get the number of entries linking to an inode, "." and ".." excluded
+    ///
+    /// Images without link counts are walked from the root, which needs
+    /// the exclusive tree lock.
+    pub(crate) fn links_of(&self, inode_id: u32) -> usize {
+        if self.link_counts {
+            self.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink as usize)
+        } else {
+            self.count_links(inode_id)
+        }
+    }
+    /// Whether another entry can link to an inode
+    pub(crate) fn can_link(&self, inode_id: u32) -> bool {
+        !self.link_counts
+            || self.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink < u16::MAX)
+    }
+    /// Count the directory entries pointing to an inode in the tree
+    /// under the root, "." and ".." excluded
+    fn count_links(&self, inode_id: u32) -> usize {
+        let mut links = 0;
+        let mut visited: BTreeSet<u32> = BTreeSet::new();
+        let mut stack: Vec<u32> = Vec::from([0]);
+        while let Some(dir_id) = stack.pop() {
+            if !visited.insert(dir_id) {
+                continue;
+            }
+            for (name, child_id) in self.dir_entries(dir_id) {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                if child_id == inode_id {
+                    links += 1;
+                }
+                if self.read_disk_inode(child_id, |disk_inode| disk_inode.is_dir()) {
+                    stack.push(child_id);
+                }
+            }
+        }
+        links
     }
 }
@@ -0,0 +1,66 @@
+use core::fmt::{self, Display, Formatter};
+use super::BlockError;
+
+/// An error opening or reading a filesystem
+///
+/// The fallible `try_` operations check what they read from the image
+/// and return these instead of panicking, so that images from untrusted
+/// sources can be looked at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfsError {
+    /// The block device failed
+    Device(BlockError),
+    /// The device does not hold an easy-fs image
+    NotEfs,
+    /// The image uses a format version or features this implementation lacks
+    Unsupported,
+    /// On-disk structures are inconsistent, with what was found wrong
+    Corrupted(&'static str),
+    /// The inode is not a directory
+    NotDir,
+    /// There are not enough free blocks
+    NoSpace,
+    /// The file would grow past the largest size an inode can address
+    TooLarge,
+}
+
+impl From<BlockError> for EfsError {
+    fn from(err: BlockError) -> Self {
+        Self::Device(err)
+    }
+}
+
+impl Display for EfsError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Device(err) => write!(f, "block device error: {:?}", err),
+            Self::NotEfs => write!(f, "not an easy-fs image"),
+            Self::Unsupported => write!(f, "unsupported format version or features"),
+            Self::Corrupted(what) => write!(f, "corrupted image: {}", what),
+            Self::NotDir => write!(f, "not a directory"),
+            Self::NoSpace => write!(f, "no space left on device"),
+            Self::TooLarge => write!(f, "file too large"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EfsError {}
+
+/// The error is kept as the inner error of the `io::Error`
+#[cfg(feature = "std")]
+impl From<EfsError> for std::io::Error {
+    fn from(err: EfsError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match err {
+            EfsError::Device(BlockError::OutOfRange) => ErrorKind::UnexpectedEof,
+            EfsError::Device(BlockError::Unsupported) => ErrorKind::Unsupported,
+            EfsError::NotEfs | EfsError::Unsupported | EfsError::Corrupted(_) => ErrorKind::InvalidData,
+            EfsError::Device(BlockError::Io)
+            | EfsError::NotDir
+            | EfsError::NoSpace
+            | EfsError::TooLarge => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,121 @@
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use core::ops::Range;
+use spin::Mutex;
+use super::{
+    BlockDevice,
+    BlockError,
+};
+
+/// Faults injected by a `FaultBlockDevice`
+#[derive(Default)]
+struct Faults {
+    /// Number of I/Os so far
+    ios: usize,
+    /// Number of the I/O to fail
+    fail_io: Option<usize>,
+    /// Number of writes so far
+    writes: usize,
+    /// Number of the first write to drop
+    drop_writes: Option<usize>,
+    /// Blocks read back corrupted
+    corrupted: BTreeSet<usize>,
+}
+
+/// A block device wrapper injecting faults, to test error handling
+/// and crash recovery
+///
+/// I/Os and writes are numbered from 0 in the order they reach the device,
+/// discards count as writes.
+pub struct FaultBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    faults: Mutex<Faults>,
+}
+
+impl FaultBlockDevice {
+    /// A wrapper around `inner` which does not inject anything yet
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(Faults::default()),
+        }
+    }
+    /// Get the wrapped device
+    pub fn inner(&self) -> &Arc<dyn BlockDevice> {
+        &self.inner
+    }
+    /// Get the number of I/Os so far
+    pub fn ios(&self) -> usize {
+        self.faults.lock().ios
+    }
+    /// Get the number of writes so far
+    pub fn writes(&self) -> usize {
+        self.faults.lock().writes
+    }
+    /// Make I/O number `n` fail
+    pub fn fail_io(&self, n: usize) {
+        self.faults.lock().fail_io = Some(n);
+    }
+    /// Silently drop write number `n` and all later ones, as if power
+    /// was cut before they reached the disk
+    pub fn drop_writes_from(&self, n: usize) {
+        self.faults.lock().drop_writes = Some(n);
+    }
+    /// Return a block with all of its bits flipped whenever it is read
+    pub fn corrupt_block(&self, block_id: usize) {
+        self.faults.lock().corrupted.insert(block_id);
+    }
+    /// Stop injecting faults
+    pub fn heal(&self) {
+        let mut faults = self.faults.lock();
+        faults.fail_io = None;
+        faults.drop_writes = None;
+        faults.corrupted.clear();
+    }
+    /// Count an I/O, returning whether it should fail
+    fn next_io_fails(faults: &mut Faults) -> bool {
+        let n = faults.ios;
+        faults.ios += 1;
+        faults.fail_io == Some(n)
+    }
+    /// Count a write, returning whether it should be dropped
+    fn next_write_lost(faults: &mut Faults) -> Result<bool, BlockError> {
+        if Self::next_io_fails(faults) {
+            return Err(BlockError::Io);
+        }
+        let n = faults.writes;
+        faults.writes += 1;
+        Ok(faults.drop_writes.map_or(false, |first| n >= first))
+    }
+}
+
+impl BlockDevice for FaultBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        let mut faults = self.faults.lock();
+        if Self::next_io_fails(&mut faults) {
+            return Err(BlockError::Io);
+        }
+        self.inner.read_block(block_id, buf)?;
+        if faults.corrupted.contains(&block_id) {
+            buf.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        Ok(())
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        let mut faults = self.faults.lock();
+        if Self::next_write_lost(&mut faults)? {
+            return Ok(());
+        }
+        self.inner.write_block(block_id, buf)
+    }
+    fn discard(&self, block_ids: Range<usize>) -> Result<(), BlockError> {
+        let mut faults = self.faults.lock();
+        if Self::next_write_lost(&mut faults)? {
+            return Ok(());
+        }
+        self.inner.discard(block_ids)
+    }
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+}
@@ -0,0 +1,69 @@
+use alloc::sync::Arc;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use super::{EfsError, Inode};
+
+/// A file of an image with a position, to be used through `std::io`
+///
+/// Reads and writes go through the checked operations of `Inode`, their
+/// errors come back as `io::Error`s holding the `EfsError`. Writing past
+/// the end grows the file, any gap reads as zeros.
+pub struct EfsFile {
+    inode: Arc<Inode>,
+    /// Never past `u32::MAX`, the largest size of a file, so it is a
+    /// valid offset as a `usize`
+    pos: u64,
+}
+
+impl EfsFile {
+    /// A handle on `inode` positioned at its start
+    pub fn new(inode: Arc<Inode>) -> Self {
+        Self { inode, pos: 0 }
+    }
+    /// Get the inode of the file
+    pub fn inode(&self) -> &Arc<Inode> {
+        &self.inode
+    }
+    /// Give back the inode of the file
+    pub fn into_inode(self) -> Arc<Inode> {
+        self.inode
+    }
+}
+
+impl Read for EfsFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inode.try_read_at(self.pos as usize, buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for EfsFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inode.try_write_at(self.pos as usize, buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+    /// Write the cached blocks back to the device
+    fn flush(&mut self) -> io::Result<()> {
+        self.inode.sync().map_err(|err| EfsError::Device(err).into())
+    }
+}
+
+impl Seek for EfsFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(offset) => (self.inode.try_size()? as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        self.pos = pos.filter(|pos| *pos <= u32::MAX as u64).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "seek to a negative position or past the largest file")
+        })?;
+        Ok(self.pos)
+    }
+}
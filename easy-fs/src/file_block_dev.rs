@@ -0,0 +1,52 @@
+use core::ops::Range;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use super::{
+    BlockDevice,
+    BlockError,
+    BLOCK_SZ,
+};
+
+/// An image file as a block device
+///
+/// Blocks are read and written at their offset, the position of the file
+/// is left alone so that it can be shared between threads. Reading past
+/// the end of the file fails with `BlockError::OutOfRange`. On Linux
+/// discarded blocks are punched out of the file, elsewhere discarding is
+/// not supported and freed blocks are zeroed instead.
+impl BlockDevice for File {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.read_exact_at(buf, (block_id * BLOCK_SZ) as u64)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => BlockError::OutOfRange,
+                _ => BlockError::Io,
+            })
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.write_all_at(buf, (block_id * BLOCK_SZ) as u64)
+            .map_err(|_| BlockError::Io)
+    }
+    /// Punch a hole in the file, keeping its size
+    #[cfg(target_os = "linux")]
+    fn discard(&self, block_ids: Range<usize>) -> Result<(), BlockError> {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe {
+            libc::fallocate(
+                self.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (block_ids.start * BLOCK_SZ) as libc::off_t,
+                (block_ids.len() * BLOCK_SZ) as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(BlockError::Unsupported)
+        }
+    }
+    #[cfg(target_os = "linux")]
+    fn can_discard(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,194 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    Bitmap,
+    BlockRefcount,
+    DataArea,
+    EasyFileSystem,
+    SuperBlock,
+    BLOCK_SZ,
+    data_area_layout,
+    get_block_cache,
+    block_cache_sync_all,
+};
+
+/// A bitmap block
+type BitmapBlock = [u64; 64];
+/// A refcount block
+type RefcountBlock = [u16; BLOCK_SZ / 2];
+/// A data block
+type DataBlock = [u8; BLOCK_SZ];
+
+impl EasyFileSystem {
+    /// Grow the filesystem in place to `new_total_blocks`
+    ///
+    /// The block device must already hold `new_total_blocks` blocks. All
+    /// new blocks go to the data area; the inode area keeps its size. If
+    /// the data bitmap and refcounts need more blocks, the data blocks in
+    /// their way are moved to free blocks of the enlarged data area and
+    /// every pointer to them is rewritten. Inodes stay where they are, so
+    /// `Inode`s handed out before remain valid. Returns false, leaving the
+    /// image as is, if it would not grow, if the new layout has a smaller
+    /// data area or if there is no room for the blocks to move.
+    pub fn grow(&self, new_total_blocks: u32) -> bool {
+        let _tree = self.tree_lock().write();
+        let inode_bitmap = self.inode_bitmap.lock();
+        let mut data_area = self.data_area();
+        let super_block = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (
+                    super_block.total_blocks,
+                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks,
+                    super_block.data_bitmap_blocks,
+                    super_block.refcount_blocks,
+                )
+            });
+        let (total_blocks, inode_total_blocks, data_bitmap_blocks, refcount_blocks) = super_block;
+        if new_total_blocks <= total_blocks {
+            return false;
+        }
+        let meta_start = 1 + inode_total_blocks;
+        let with_refcount = refcount_blocks > 0;
+        // keep the metadata as is while it covers the enlarged data area
+        let grown_area_blocks =
+            new_total_blocks - meta_start - data_bitmap_blocks - refcount_blocks;
+        let (new_bitmap_blocks, new_refcount_blocks, new_area_blocks) =
+            if grown_area_blocks as usize <= data_area.bitmap.maximum()
+                && (!with_refcount || grown_area_blocks as usize <= data_area.refcount.maximum())
+            {
+                (data_bitmap_blocks, refcount_blocks, grown_area_blocks)
+            } else {
+                data_area_layout(new_total_blocks - meta_start, with_refcount)
+            };
+        let shift = (new_bitmap_blocks + new_refcount_blocks)
+            - (data_bitmap_blocks + refcount_blocks);
+        if new_area_blocks < data_area.blocks {
+            return false;
+        }
+        let old_start = data_area.start_block;
+        let old_end = old_start + data_area.blocks;
+        let new_start = old_start + shift;
+        let new_end = new_start + new_area_blocks;
+        let mut allocated = self.load_allocation(&data_area);
+        let evacuated: Vec<u32> = allocated.range(..new_start).map(|(id, _)| *id).collect();
+        let targets: Vec<u32> = (new_start..new_end)
+            .filter(|block_id| !allocated.contains_key(block_id))
+            .take(evacuated.len())
+            .collect();
+        if targets.len() < evacuated.len() {
+            return false;
+        }
+        // blocks past the old data area are expected to be zero like any
+        // free block
+        for block_id in old_end..new_end {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+        // move blocks out of the way of the enlarged metadata
+        let mut remap: BTreeMap<u32, u32> = BTreeMap::new();
+        for (block_id, target) in evacuated.into_iter().zip(targets) {
+            let mut data = [0u8; BLOCK_SZ];
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| data.copy_from_slice(data_block));
+            get_block_cache(target as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.copy_from_slice(&data));
+            let refcount = allocated.remove(&block_id).unwrap();
+            allocated.insert(target, refcount);
+            remap.insert(block_id, target);
+        }
+        if !remap.is_empty() {
+            let block_device = Arc::clone(&self.block_device);
+            for inode_id in 0..inode_bitmap.maximum() {
+                if !inode_bitmap.is_allocated(&block_device, inode_id) {
+                    continue;
+                }
+                self.modify_disk_inode(inode_id as u32, |disk_inode| {
+                    disk_inode.remap_blocks(&block_device, |block_id| {
+                        *remap.get(&block_id).unwrap_or(&block_id)
+                    });
+                });
+            }
+        }
+        // rewrite data bitmap and refcounts for the new layout
+        self.store_allocation(meta_start, new_bitmap_blocks, new_start, &allocated);
+        let data_bitmap = Bitmap::new(meta_start as usize, new_bitmap_blocks as usize);
+        let block_refcount = BlockRefcount::new(
+            (meta_start + new_bitmap_blocks) as usize,
+            new_refcount_blocks as usize,
+        );
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.total_blocks = new_total_blocks;
+                super_block.data_bitmap_blocks = new_bitmap_blocks;
+                super_block.refcount_blocks = new_refcount_blocks;
+                super_block.data_area_blocks = new_area_blocks;
+            });
+        data_area.bitmap = data_bitmap;
+        data_area.refcount = block_refcount;
+        data_area.start_block = new_start;
+        data_area.free += new_area_blocks - data_area.blocks;
+        data_area.blocks = new_area_blocks;
+        block_cache_sync_all();
+        true
+    }
+    /// Load the allocation state of the data area, as the refcount of
+    /// every allocated block indexed by absolute block id
+    pub(crate) fn load_allocation(&self, data_area: &DataArea) -> BTreeMap<u32, u16> {
+        let mut allocated: BTreeMap<u32, u16> = BTreeMap::new();
+        for pos in 0..data_area.blocks as usize {
+            if data_area.bitmap.is_allocated(&self.block_device, pos) {
+                allocated.insert(
+                    data_area.start_block + pos as u32,
+                    data_area.refcount.get(&self.block_device, pos),
+                );
+            }
+        }
+        allocated
+    }
+    /// Rewrite the data bitmap of `bitmap_blocks` blocks at `meta_start`,
+    /// followed by the refcounts up to the data area at `data_start`, from
+    /// an allocation state as loaded by `load_allocation`
+    pub(crate) fn store_allocation(
+        &self,
+        meta_start: u32,
+        bitmap_blocks: u32,
+        data_start: u32,
+        allocated: &BTreeMap<u32, u16>,
+    ) {
+        for block_id in meta_start..data_start {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+        for (block_id, refcount) in allocated.iter() {
+            let pos = (block_id - data_start) as usize;
+            let bits_per_block = BLOCK_SZ * 8;
+            get_block_cache(
+                meta_start as usize + pos / bits_per_block,
+                Arc::clone(&self.block_device),
+            )
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                let bit = pos % bits_per_block;
+                bitmap_block[bit / 64] |= 1u64 << (bit % 64);
+            });
+            if *refcount > 0 {
+                let refcounts_per_block = BLOCK_SZ / 2;
+                get_block_cache(
+                    (meta_start + bitmap_blocks) as usize + pos / refcounts_per_block,
+                    Arc::clone(&self.block_device),
+                )
+                .lock()
+                .modify(0, |refcount_block: &mut RefcountBlock| {
+                    refcount_block[pos % refcounts_per_block] = *refcount;
+                });
+            }
+        }
+    }
+}
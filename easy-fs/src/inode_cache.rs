@@ -0,0 +1,85 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use alloc::sync::{Arc, Weak};
+use spin::Mutex;
+use super::Inode;
+
+/// Table of the inodes in use, keyed by inode id
+///
+/// Holds weak references only: an entry lives while somebody holds its
+/// `Arc<Inode>` and is evicted when the last one is dropped. While it
+/// lives, every lookup of the inode id returns the same object.
+pub struct InodeCache {
+    inodes: Mutex<InodeTable>,
+}
+
+struct InodeTable {
+    inodes: BTreeMap<u32, Weak<Inode>>,
+    /// Inodes in use which lost their last link, freed on eviction
+    orphans: BTreeSet<u32>,
+    /// Evicted orphans waiting to be freed
+    released: Vec<u32>,
+}
+
+impl InodeCache {
+    pub fn new() -> Self {
+        Self {
+            inodes: Mutex::new(InodeTable {
+                inodes: BTreeMap::new(),
+                orphans: BTreeSet::new(),
+                released: Vec::new(),
+            }),
+        }
+    }
+    /// Get the inode in use with the given id, or insert the one built by `f`
+    pub fn get_or_insert(&self, inode_id: u32, f: impl FnOnce() -> Inode) -> Arc<Inode> {
+        let mut table = self.inodes.lock();
+        if let Some(inode) = table.inodes.get(&inode_id).and_then(|weak| weak.upgrade()) {
+            return inode;
+        }
+        let inode = Arc::new(f());
+        table.inodes.insert(inode_id, Arc::downgrade(&inode));
+        inode
+    }
+    /// Mark an inode which lost its last link as an orphan if it is in use
+    ///
+    /// Returns false if nobody holds it, the caller then frees it at once.
+    pub fn orphan(&self, inode_id: u32) -> bool {
+        let mut table = self.inodes.lock();
+        let in_use = table
+            .inodes
+            .get(&inode_id)
+            .map_or(false, |weak| weak.strong_count() > 0);
+        if in_use {
+            table.orphans.insert(inode_id);
+        }
+        in_use
+    }
+    /// Evict the entry of an inode whose last reference is gone
+    ///
+    /// The entry is kept if it was replaced by a live inode meanwhile.
+    /// Returns true if the evicted inode was an orphan, which is then
+    /// queued for `take_released`.
+    pub fn release(&self, inode_id: u32) -> bool {
+        let mut table = self.inodes.lock();
+        match table.inodes.get(&inode_id) {
+            Some(weak) if weak.strong_count() == 0 => {
+                table.inodes.remove(&inode_id);
+                let orphan = table.orphans.remove(&inode_id);
+                if orphan {
+                    table.released.push(inode_id);
+                }
+                orphan
+            }
+            _ => false,
+        }
+    }
+    /// Take the evicted orphans for the caller to free
+    pub fn take_released(&self) -> Vec<u32> {
+        core::mem::take(&mut self.inodes.lock().released)
+    }
+    /// Number of inodes in use
+    pub fn len(&self) -> usize {
+        self.inodes.lock().inodes.len()
+    }
+}
@@ -0,0 +1,272 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use super::{
+    BlockDevice,
+    BlockError,
+    EasyFileSystem,
+    EfsError,
+    DiskInode,
+    InodeType,
+    OnDisk,
+    SuperBlock,
+    BLOCK_SZ,
+    DIRENT_SZ,
+    INLINE_DATA_LIMIT,
+    get_block_cache,
+    try_get_block_cache,
+};
+
+/// An indirect block
+type IndirectBlock = [u32; BLOCK_SZ / 4];
+/// Number of direct block ids in a disk inode
+const DIRECT_COUNT: usize = INLINE_DATA_LIMIT / 4;
+/// Number of block ids in an indirect block
+const INDIRECT_COUNT: usize = BLOCK_SZ / 4;
+
+/// A copy of the super block, for inspection
+#[derive(Debug, Clone)]
+pub struct SuperBlockInfo {
+    /// The magic number is right
+    pub valid: bool,
+    pub version: u32,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub total_blocks: u32,
+    pub inode_bitmap_blocks: u32,
+    pub inode_area_blocks: u32,
+    pub data_bitmap_blocks: u32,
+    pub refcount_blocks: u32,
+    pub data_area_blocks: u32,
+    /// First block of the data area
+    pub data_area_start: u32,
+    pub snapshot_inode: u32,
+}
+
+/// A copy of a disk inode, for inspection
+#[derive(Debug, Clone)]
+pub struct InodeInfo {
+    pub inode_id: u32,
+    pub type_: InodeType,
+    pub size: u32,
+    /// The data is held in place of the direct block ids
+    pub inline: bool,
+    /// Direct block ids in use
+    pub direct: Vec<u32>,
+    /// The data held inline
+    pub inline_data: Vec<u8>,
+    pub indirect1: u32,
+    pub indirect2: u32,
+}
+
+/// Every block of an inode, for inspection
+#[derive(Debug, Clone, Default)]
+pub struct BlockMap {
+    /// Data blocks in file order
+    pub data: Vec<u32>,
+    /// The block ids held by the indirect1 block
+    pub indirect1: Vec<u32>,
+    /// The indirect1 blocks held by the indirect2 block, each along
+    /// with the block ids it holds
+    pub indirect2: Vec<(u32, Vec<u32>)>,
+}
+
+/// Copy a super block along with the first block of its data area
+fn super_block_info(super_block: &SuperBlock, data_area_start: u32) -> SuperBlockInfo {
+    SuperBlockInfo {
+        valid: super_block.is_valid(),
+        version: super_block.version(),
+        feature_compat: super_block.feature_compat(),
+        feature_incompat: super_block.feature_incompat(),
+        feature_ro_compat: super_block.feature_ro_compat(),
+        total_blocks: super_block.total_blocks,
+        inode_bitmap_blocks: super_block.inode_bitmap_blocks,
+        inode_area_blocks: super_block.inode_area_blocks,
+        data_bitmap_blocks: super_block.data_bitmap_blocks,
+        refcount_blocks: super_block.refcount_blocks,
+        data_area_blocks: super_block.data_area_blocks,
+        data_area_start,
+        snapshot_inode: super_block.snapshot_inode,
+    }
+}
+
+/// Read the first `count` entries of an indirect block, checking that
+/// it lies in `data_area`
+fn read_indirect(
+    block_device: &Arc<dyn BlockDevice>,
+    block_id: u32,
+    count: usize,
+    data_area: &Range<u32>,
+) -> Result<Vec<u32>, EfsError> {
+    if !data_area.contains(&block_id) {
+        return Err(EfsError::Corrupted("indirect block out of the data area"));
+    }
+    Ok(try_get_block_cache(block_id as usize, Arc::clone(block_device))?
+        .lock()
+        .read(0, |indirect_block: &IndirectBlock| indirect_block[..count].to_vec()))
+}
+
+/// Inspection of the on-disk structures for debugging tools; the tree
+/// lock is not taken, so results are only consistent on an idle filesystem.
+/// The `try_` variants check what they read, so that a damaged image can
+/// be looked at item by item.
+impl EasyFileSystem {
+    /// Read the super block from block 0 of a device, bypassing the cache,
+    /// whether or not it is valid; the data area is where the sizes of
+    /// the areas before it put it
+    pub fn read_super_block(block_device: &Arc<dyn BlockDevice>) -> Result<SuperBlockInfo, BlockError> {
+        let mut block = [0u8; BLOCK_SZ];
+        block_device.read_block(0, &mut block)?;
+        let super_block = SuperBlock::decode(&block[..SuperBlock::SIZE]).unwrap();
+        let data_area_start = [
+            super_block.inode_bitmap_blocks,
+            super_block.inode_area_blocks,
+            super_block.data_bitmap_blocks,
+            super_block.refcount_blocks,
+        ]
+        .iter()
+        .fold(1u32, |start, blocks| start.saturating_add(*blocks));
+        Ok(super_block_info(&super_block, data_area_start))
+    }
+    /// Get a copy of the super block
+    pub fn super_block_info(&self) -> SuperBlockInfo {
+        let data_area_start = self.data_area().start_block;
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block_info(super_block, data_area_start))
+    }
+    /// Get the runs of allocated inodes as (first inode, count)
+    pub fn allocated_inodes(&self) -> Vec<(u32, u32)> {
+        self.try_allocated_inodes().unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Like `allocated_inodes`, the error of the device if the bitmap
+    /// can't be read
+    pub fn try_allocated_inodes(&self) -> Result<Vec<(u32, u32)>, EfsError> {
+        let inode_bitmap = self.inode_bitmap.lock();
+        Ok(inode_bitmap
+            .allocated_runs(&self.block_device)?
+            .into_iter()
+            .map(|(start, len)| (start as u32, len as u32))
+            .collect())
+    }
+    /// Get the runs of allocated data blocks as (first block, count)
+    pub fn allocated_data_blocks(&self) -> Vec<(u32, u32)> {
+        self.try_allocated_data_blocks().unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Like `allocated_data_blocks`, the error of the device if the bitmap
+    /// can't be read
+    pub fn try_allocated_data_blocks(&self) -> Result<Vec<(u32, u32)>, EfsError> {
+        let data_area = self.data_area();
+        Ok(data_area
+            .bitmap
+            .allocated_runs(&self.block_device)?
+            .into_iter()
+            .map(|(start, len)| (start as u32 + data_area.start_block, len as u32))
+            .collect())
+    }
+    /// Get a copy of an allocated disk inode
+    pub fn inode_info(&self, inode_id: u32) -> Option<InodeInfo> {
+        self.try_inode_info(inode_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Like `inode_info`, checking that the inode is valid
+    pub fn try_inode_info(&self, inode_id: u32) -> Result<Option<InodeInfo>, EfsError> {
+        if !self.try_is_inode_allocated(inode_id)? {
+            return Ok(None);
+        }
+        let disk_inode = self.try_load_disk_inode(inode_id)?;
+        let used = (disk_inode.data_blocks() as usize).min(DIRECT_COUNT);
+        let mut inline_data = Vec::new();
+        if disk_inode.is_inline() {
+            inline_data = disk_inode
+                .direct
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .take(disk_inode.size as usize)
+                .collect();
+        }
+        Ok(Some(InodeInfo {
+            inode_id,
+            type_: InodeType::of(&disk_inode),
+            size: disk_inode.size,
+            inline: disk_inode.is_inline(),
+            direct: disk_inode.direct[..used].to_vec(),
+            inline_data,
+            indirect1: disk_inode.indirect1,
+            indirect2: disk_inode.indirect2,
+        }))
+    }
+    /// Get every block of an allocated inode
+    pub fn block_map(&self, inode_id: u32) -> Option<BlockMap> {
+        self.try_block_map(inode_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Like `block_map`, checking that every block lies in the data area
+    pub fn try_block_map(&self, inode_id: u32) -> Result<Option<BlockMap>, EfsError> {
+        if !self.try_is_inode_allocated(inode_id)? {
+            return Ok(None);
+        }
+        let disk_inode = self.try_load_disk_inode(inode_id)?;
+        self.disk_inode_block_map(&disk_inode, &self.data_area_range()).map(Some)
+    }
+    /// Get the live (name, inode number) entries of an allocated directory
+    pub fn dir_entries_of(&self, inode_id: u32) -> Option<Vec<(String, u32)>> {
+        self.try_dir_entries_of(inode_id).unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Like `dir_entries_of`, checking the entries and their blocks
+    pub fn try_dir_entries_of(&self, inode_id: u32) -> Result<Option<Vec<(String, u32)>>, EfsError> {
+        if !self.try_is_inode_allocated(inode_id)? {
+            return Ok(None);
+        }
+        let disk_inode = self.try_load_disk_inode(inode_id)?;
+        if !disk_inode.is_dir() {
+            return Ok(None);
+        }
+        let data_area = self.data_area_range();
+        let mut entries = Vec::new();
+        for i in 0..disk_inode.size as usize / DIRENT_SZ {
+            let dirent = disk_inode.try_read_dirent(i * DIRENT_SZ, &self.block_device, &data_area)?;
+            if !dirent.name().is_empty() {
+                entries.push((String::from(dirent.name()), dirent.inode_number()));
+            }
+        }
+        Ok(Some(entries))
+    }
+    /// Whether an inode id is allocated
+    fn try_is_inode_allocated(&self, inode_id: u32) -> Result<bool, EfsError> {
+        let inode_bitmap = self.inode_bitmap.lock();
+        Ok((inode_id as usize) < inode_bitmap.maximum()
+            && inode_bitmap.try_is_allocated(&self.block_device, inode_id as usize)?)
+    }
+    fn disk_inode_block_map(
+        &self,
+        disk_inode: &DiskInode,
+        data_area: &Range<u32>,
+    ) -> Result<BlockMap, EfsError> {
+        let mut map = BlockMap {
+            data: (0..disk_inode.data_blocks())
+                .map(|inner_id| disk_inode.try_get_block_id(inner_id, &self.block_device, data_area))
+                .collect::<Result<_, _>>()?,
+            ..BlockMap::default()
+        };
+        let mut left = (disk_inode.data_blocks() as usize).saturating_sub(DIRECT_COUNT);
+        if left == 0 {
+            return Ok(map);
+        }
+        let count = left.min(INDIRECT_COUNT);
+        map.indirect1 = read_indirect(&self.block_device, disk_inode.indirect1, count, data_area)?;
+        left -= count;
+        if left == 0 {
+            return Ok(map);
+        }
+        let indirect1_count = (left + INDIRECT_COUNT - 1) / INDIRECT_COUNT;
+        let indirect1_blocks =
+            read_indirect(&self.block_device, disk_inode.indirect2, indirect1_count, data_area)?;
+        for block_id in indirect1_blocks {
+            let count = left.min(INDIRECT_COUNT);
+            map.indirect2.push((block_id, read_indirect(&self.block_device, block_id, count, data_area)?));
+            left -= count;
+        }
+        Ok(map)
+    }
+}
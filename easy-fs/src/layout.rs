@@ -1,18 +1,55 @@
-use core::fmt::{Debug, Formatter, Result};
+use core::fmt::{self, Debug, Formatter};
+use core::ops::Range;
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    EfsError,
+    OnDisk,
     get_block_cache,
+    try_get_block_cache,
+    get_u32,
+    put_u32,
 };
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 /// Magic number for sanity check
 const EFS_MAGIC: u32 = 0x3b800001;
+/// Format version written by this implementation
+///
+/// Version 1 images predate versioning: their version and feature fields
+/// read as zero and their features are implied by the area sizes. Version 2
+/// adds inline data and symbolic links, version 3 link counts.
+pub const EFS_VERSION: u32 = 3;
+/// Oldest format version this implementation can convert an image to
+pub const EFS_MIN_VERSION: u32 = 1;
+/// Compatible feature: a hidden directory holds snapshots
+pub const FEATURE_COMPAT_SNAPSHOTS: u32 = 1 << 0;
+/// Incompatible feature: a refcount area follows the data bitmap
+pub const FEATURE_INCOMPAT_REFCOUNT: u32 = 1 << 0;
+/// Incompatible feature: small inodes hold their data in place of block ids
+pub const FEATURE_INCOMPAT_INLINE_DATA: u32 = 1 << 1;
+/// Incompatible feature: some inodes are symbolic links
+pub const FEATURE_INCOMPAT_SYMLINKS: u32 = 1 << 2;
+/// Incompatible feature: disk inodes hold the number of entries linking to them
+pub const FEATURE_INCOMPAT_NLINK: u32 = 1 << 3;
+/// Incompatible features known to this implementation
+const SUPPORTED_INCOMPAT: u32 = VERSION_2_INCOMPAT | FEATURE_INCOMPAT_NLINK;
+/// Read-only compatible features known to this implementation
+const SUPPORTED_RO_COMPAT: u32 = 0;
+/// Incompatible features a version 1 image can express
+const VERSION_1_INCOMPAT: u32 = FEATURE_INCOMPAT_REFCOUNT;
+/// Incompatible features a version 2 image can express
+const VERSION_2_INCOMPAT: u32 =
+    FEATURE_INCOMPAT_REFCOUNT | FEATURE_INCOMPAT_INLINE_DATA | FEATURE_INCOMPAT_SYMLINKS;
 /// The max number of direct inodes
 const INODE_DIRECT_COUNT: usize = 28;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
+/// The max size of data held inline in place of the direct block ids
+pub const INLINE_DATA_LIMIT: usize = INODE_DIRECT_COUNT * 4;
+/// Flag of a disk inode holding its data inline
+const INODE_FLAG_INLINE: u8 = 1 << 0;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
@@ -22,11 +59,13 @@ const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 /// The upper bound of indirect1 inode index
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode index
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The max size of a file, as many blocks as an inode can address
+pub const MAX_FILE_SIZE: u32 = (INDIRECT2_BOUND * BLOCK_SZ) as u32;
+/// Block ids the unchecked accessors accept, any of them
+const ANY_BLOCK: Range<u32> = 0..u32::MAX;
 
 /// Super block of a filesystem
-#[repr(C)]
 pub struct SuperBlock {
     magic: u32,
     pub total_blocks: u32,
@@ -34,16 +73,73 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    pub refcount_blocks: u32,
+    /// Directory holding the snapshots, 0 if none has been taken
+    pub snapshot_inode: u32,
+    /// Format version, 0 for version 1 images
+    version: u32,
+    /// Features an implementation may ignore
+    feature_compat: u32,
+    /// Features an implementation must know to open the image
+    feature_incompat: u32,
+    /// Features an implementation must know to write the image
+    feature_ro_compat: u32,
+}
+
+impl OnDisk for SuperBlock {
+    const SIZE: usize = 48;
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let field = |i: usize| get_u32(bytes, i * 4);
+        Some(Self {
+            magic: field(0),
+            total_blocks: field(1),
+            inode_bitmap_blocks: field(2),
+            inode_area_blocks: field(3),
+            data_bitmap_blocks: field(4),
+            data_area_blocks: field(5),
+            refcount_blocks: field(6),
+            snapshot_inode: field(7),
+            version: field(8),
+            feature_compat: field(9),
+            feature_incompat: field(10),
+            feature_ro_compat: field(11),
+        })
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let fields = [
+            self.magic,
+            self.total_blocks,
+            self.inode_bitmap_blocks,
+            self.inode_area_blocks,
+            self.data_bitmap_blocks,
+            self.data_area_blocks,
+            self.refcount_blocks,
+            self.snapshot_inode,
+            self.version,
+            self.feature_compat,
+            self.feature_incompat,
+            self.feature_ro_compat,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            put_u32(bytes, i * 4, *field);
+        }
+    }
 }
 
 impl Debug for SuperBlock {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("SuperBlock")
             .field("total_blocks", &self.total_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("refcount_blocks", &self.refcount_blocks)
+            .field("snapshot_inode", &self.snapshot_inode)
+            .field("version", &self.version())
+            .field("feature_compat", &self.feature_compat())
+            .field("feature_incompat", &self.feature_incompat())
+            .field("feature_ro_compat", &self.feature_ro_compat())
             .finish()
     }
 }
@@ -57,6 +153,7 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        refcount_blocks: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -65,19 +162,144 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            refcount_blocks,
+            snapshot_inode: 0,
+            version: EFS_VERSION,
+            feature_compat: 0,
+            feature_incompat: if refcount_blocks > 0 {
+                FEATURE_INCOMPAT_REFCOUNT | FEATURE_INCOMPAT_INLINE_DATA | FEATURE_INCOMPAT_NLINK
+            } else {
+                FEATURE_INCOMPAT_INLINE_DATA | FEATURE_INCOMPAT_NLINK
+            },
+            feature_ro_compat: 0,
         }
     }
     /// Check if a super block is valid using efs magic
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// Get the format version
+    pub fn version(&self) -> u32 {
+        if self.version == 0 { 1 } else { self.version }
+    }
+    /// Get the compatible features in use
+    pub fn feature_compat(&self) -> u32 {
+        if self.version() == 1 && self.snapshot_inode != 0 {
+            return FEATURE_COMPAT_SNAPSHOTS;
+        }
+        self.feature_compat
+    }
+    /// Get the incompatible features in use
+    pub fn feature_incompat(&self) -> u32 {
+        if self.version() == 1 && self.refcount_blocks > 0 {
+            return FEATURE_INCOMPAT_REFCOUNT;
+        }
+        self.feature_incompat
+    }
+    /// Get the read-only compatible features in use
+    pub fn feature_ro_compat(&self) -> u32 {
+        self.feature_ro_compat
+    }
+    /// Check if this implementation can open the image for writing
+    ///
+    /// Unknown compatible features are fine, unknown incompatible and
+    /// read-only compatible ones are not since there is no read-only mode.
+    pub fn is_supported(&self) -> bool {
+        self.version() <= EFS_VERSION
+            && self.feature_incompat() & !SUPPORTED_INCOMPAT == 0
+            && self.feature_ro_compat() & !SUPPORTED_RO_COMPAT == 0
+    }
+    /// Record that a compatible feature is in use
+    pub fn add_feature_compat(&mut self, feature: u32) {
+        if self.version() > 1 {
+            self.feature_compat |= feature;
+        }
+    }
+    /// Whether the format version can express an incompatible feature
+    pub fn can_express_incompat(&self, feature: u32) -> bool {
+        feature & !Self::version_incompat(self.version()) == 0
+    }
+    /// Get the incompatible features a format version can express
+    fn version_incompat(version: u32) -> u32 {
+        match version {
+            1 => VERSION_1_INCOMPAT,
+            2 => VERSION_2_INCOMPAT,
+            _ => SUPPORTED_INCOMPAT,
+        }
+    }
+    /// Record that an incompatible feature is in use, return false if
+    /// the format version cannot express it
+    pub fn add_feature_incompat(&mut self, feature: u32) -> bool {
+        if !self.can_express_incompat(feature) {
+            return false;
+        }
+        if self.version() > 1 {
+            self.feature_incompat |= feature;
+        }
+        true
+    }
+    /// Record that an incompatible feature is no longer in use
+    pub fn remove_feature_incompat(&mut self, feature: u32) {
+        self.feature_incompat &= !feature;
+    }
+    /// Whether `set_version` can convert the super block to a format
+    /// version once the incompatible features `dropped` are removed
+    pub fn can_set_version(&self, version: u32, dropped: u32) -> bool {
+        self.is_supported()
+            && (EFS_MIN_VERSION..=EFS_VERSION).contains(&version)
+            && self.feature_incompat() & !dropped & !Self::version_incompat(version) == 0
+            && (version > 1 || self.feature_ro_compat() == 0)
+    }
+    /// Convert the super block to another format version, return false
+    /// if the version is unknown or cannot express the features in use
+    ///
+    /// Link counts are recorded when going to version 3, the disk inodes
+    /// must hold them by then.
+    pub fn set_version(&mut self, version: u32) -> bool {
+        if !self.can_set_version(version, 0) {
+            return false;
+        }
+        let (compat, incompat, ro_compat) =
+            (self.feature_compat(), self.feature_incompat(), self.feature_ro_compat());
+        if version == 1 {
+            self.version = 0;
+            self.feature_compat = 0;
+            self.feature_incompat = 0;
+            self.feature_ro_compat = 0;
+        } else {
+            // inline data only applies to inodes created from now on
+            let mut upgrade = if self.version() == 1 { FEATURE_INCOMPAT_INLINE_DATA } else { 0 };
+            if version >= 3 {
+                upgrade |= FEATURE_INCOMPAT_NLINK;
+            }
+            self.version = version;
+            self.feature_compat = compat;
+            self.feature_incompat = incompat | upgrade;
+            self.feature_ro_compat = ro_compat;
+        }
+        true
+    }
 }
 
 /// Type of a disk inode
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 pub enum DiskInodeType {
-    File,
-    Directory,
+    File = 0,
+    Directory = 1,
+    /// Holds the path it points to as data
+    SymLink = 2,
+}
+
+impl DiskInodeType {
+    /// Get the type stored on disk as `value`
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::File),
+            1 => Some(Self::Directory),
+            2 => Some(Self::SymLink),
+            _ => None,
+        }
+    }
 }
 
 /// A indirect block
@@ -85,14 +307,70 @@ type IndirectBlock = [u32; BLOCK_SZ / 4];
 /// A data block
 type DataBlock = [u8; BLOCK_SZ];
 
+/// Size of a disk inode
+pub const DISK_INODE_SZ: usize = 128;
+
 /// A disk inode
-#[repr(C)]
+#[derive(Clone)]
 pub struct DiskInode {
     pub size: u32,
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
     type_: DiskInodeType,
+    /// Data is stored in the bytes of `direct`, no block is owned
+    inline: bool,
+    /// Number of directory entries linking to the inode, "." and ".."
+    /// excluded; always zero on images without `FEATURE_INCOMPAT_NLINK`
+    pub nlink: u16,
+}
+
+/// Offset of `indirect1` in an encoded disk inode
+const INDIRECT1_OFFSET: usize = 4 + INODE_DIRECT_COUNT * 4;
+
+impl OnDisk for DiskInode {
+    const SIZE: usize = DISK_INODE_SZ;
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut direct = [0u32; INODE_DIRECT_COUNT];
+        for (i, v) in direct.iter_mut().enumerate() {
+            *v = get_u32(bytes, 4 + i * 4);
+        }
+        let type_ = bytes[INDIRECT1_OFFSET + 8];
+        let flags = bytes[INDIRECT1_OFFSET + 10];
+        let nlink = u16::from_le_bytes([bytes[INDIRECT1_OFFSET + 9], bytes[INDIRECT1_OFFSET + 11]]);
+        let inline = flags & INODE_FLAG_INLINE != 0;
+        let size = get_u32(bytes, 0);
+        if flags & !INODE_FLAG_INLINE != 0
+            || (inline && size as usize > INLINE_DATA_LIMIT)
+            || size > MAX_FILE_SIZE
+        {
+            return None;
+        }
+        Some(Self {
+            size,
+            direct,
+            indirect1: get_u32(bytes, INDIRECT1_OFFSET),
+            indirect2: get_u32(bytes, INDIRECT1_OFFSET + 4),
+            type_: DiskInodeType::from_u8(type_)?,
+            inline,
+            nlink,
+        })
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        put_u32(bytes, 0, self.size);
+        for (i, v) in self.direct.iter().enumerate() {
+            put_u32(bytes, 4 + i * 4, *v);
+        }
+        put_u32(bytes, INDIRECT1_OFFSET, self.indirect1);
+        put_u32(bytes, INDIRECT1_OFFSET + 4, self.indirect2);
+        // type and flags used to be 16 bits each, the link count takes
+        // their high bytes which older images leave zero
+        let [nlink_low, nlink_high] = self.nlink.to_le_bytes();
+        bytes[INDIRECT1_OFFSET + 8] = self.type_ as u8;
+        bytes[INDIRECT1_OFFSET + 9] = nlink_low;
+        bytes[INDIRECT1_OFFSET + 10] = if self.inline { INODE_FLAG_INLINE } else { 0 };
+        bytes[INDIRECT1_OFFSET + 11] = nlink_high;
+    }
 }
 
 impl DiskInode {
@@ -104,6 +382,35 @@ impl DiskInode {
         self.indirect1 = 0;
         self.indirect2 = 0;
         self.type_ = type_;
+        self.inline = false;
+        self.nlink = 0;
+    }
+    /// Store the data of current empty disk inode inline from now on
+    pub fn set_inline(&mut self) {
+        assert_eq!(self.size, 0);
+        self.inline = true;
+    }
+    /// Whether the data is stored inline
+    pub fn is_inline(&self) -> bool {
+        self.inline
+    }
+    /// Get the bytes of the direct block ids, which hold inline data
+    fn inline_bytes(&self) -> [u8; INLINE_DATA_LIMIT] {
+        let mut bytes = [0u8; INLINE_DATA_LIMIT];
+        for (i, v) in self.direct.iter().enumerate() {
+            put_u32(&mut bytes, i * 4, *v);
+        }
+        bytes
+    }
+    /// Set the bytes of the direct block ids
+    fn set_inline_bytes(&mut self, bytes: &[u8; INLINE_DATA_LIMIT]) {
+        for (i, v) in self.direct.iter_mut().enumerate() {
+            *v = get_u32(bytes, i * 4);
+        }
+    }
+    /// Get the type of this inode
+    pub fn type_(&self) -> DiskInodeType {
+        self.type_
     }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
@@ -114,8 +421,15 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    /// Whether this inode is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DiskInodeType::SymLink
+    }
     /// Get the number of data blocks corresponding to size
     pub fn data_blocks(&self) -> u32 {
+        if self.inline {
+            return 0;
+        }
         Self::_data_blocks(self.size)
     }
     fn _data_blocks(size: u32) -> u32 {
@@ -140,19 +454,58 @@ impl DiskInode {
     /// Get the number of data blocks that have to be allocated given the new size of data
     pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
         assert!(new_size >= self.size);
+        if self.inline {
+            if new_size as usize <= INLINE_DATA_LIMIT {
+                return 0;
+            }
+            return Self::total_blocks(new_size);
+        }
         Self::total_blocks(new_size) - Self::total_blocks(self.size)
     }
     /// Get id of block given inner id
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        self.try_get_block_id(inner_id, block_device, &ANY_BLOCK)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Get id of block given inner id, checking that it and the indirect
+    /// blocks leading to it lie in `data_area`
+    pub fn try_get_block_id(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        data_area: &Range<u32>,
+    ) -> Result<u32, EfsError> {
         let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
+        let block_id = if inner_id < INODE_DIRECT_COUNT {
             self.direct[inner_id]
+        } else if inner_id < INDIRECT1_BOUND {
+            read_indirect(self.indirect1, inner_id - INODE_DIRECT_COUNT, block_device, data_area)?
+        } else if inner_id < INDIRECT2_BOUND {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 =
+                read_indirect(self.indirect2, last / INODE_INDIRECT1_COUNT, block_device, data_area)?;
+            read_indirect(indirect1, last % INODE_INDIRECT1_COUNT, block_device, data_area)?
+        } else {
+            return Err(EfsError::Corrupted("block past the largest file"));
+        };
+        check_block_id(block_id, data_area)
+    }
+    /// Set id of block given inner id, the block must already be mapped
+    pub fn set_block_id(
+        &mut self,
+        inner_id: u32,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
         } else if inner_id < INDIRECT1_BOUND {
             get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
                 .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
+                .modify(0, |indirect_block: &mut IndirectBlock| {
+                    indirect_block[inner_id - INODE_DIRECT_COUNT] = block_id;
+                });
         } else {
             let last = inner_id - INDIRECT1_BOUND;
             let indirect1 = get_block_cache(
@@ -168,18 +521,141 @@ impl DiskInode {
                 Arc::clone(block_device)
             )
             .lock()
-            .read(0, |indirect1: &IndirectBlock| {
-                indirect1[last % INODE_INDIRECT1_COUNT]
-            })
+            .modify(0, |indirect1: &mut IndirectBlock| {
+                indirect1[last % INODE_INDIRECT1_COUNT] = block_id;
+            });
+        }
+    }
+    /// Get ids of all data blocks in order, not including indirect blocks
+    pub fn data_block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        (0..self.data_blocks())
+            .map(|inner_id| self.get_block_id(inner_id, block_device))
+            .collect()
+    }
+    /// Get every block referenced by current disk inode, indirect blocks
+    /// included, in the order `increase_size` takes them
+    pub fn all_block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let data_blocks = self.data_block_ids(block_device);
+        let mut indirect: Vec<u32> = Vec::new();
+        if data_blocks.len() > DIRECT_BOUND {
+            indirect.push(self.indirect1);
+        }
+        if data_blocks.len() > INDIRECT1_BOUND {
+            indirect.push(self.indirect2);
+            let a1 = (data_blocks.len() - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1)
+                / INODE_INDIRECT1_COUNT;
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    indirect.extend_from_slice(&indirect2[..a1]);
+                });
+        }
+        let mut indirect = indirect.into_iter();
+        Self::with_indirect_blocks(data_blocks, || indirect.next().unwrap())
+    }
+    /// Interleave fresh indirect blocks with data blocks in the order
+    /// expected by `increase_size`
+    pub fn with_indirect_blocks(
+        data_blocks: Vec<u32>,
+        mut alloc: impl FnMut() -> u32,
+    ) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        for (inner_id, block_id) in data_blocks.into_iter().enumerate() {
+            if inner_id == DIRECT_BOUND {
+                // indirect1
+                v.push(alloc());
+            }
+            if inner_id == INDIRECT1_BOUND {
+                // indirect2
+                v.push(alloc());
+            }
+            if inner_id >= INDIRECT1_BOUND
+                && (inner_id - INDIRECT1_BOUND) % INODE_INDIRECT1_COUNT == 0 {
+                // sub indirect1
+                v.push(alloc());
+            }
+            v.push(block_id);
         }
+        v
+    }
+    /// Replace every block id referenced by current disk inode,
+    /// indirect blocks included, by `f(block_id)`
+    ///
+    /// Indirect blocks are remapped before being walked, so their
+    /// contents must already be present at the new location.
+    pub fn remap_blocks(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        mut f: impl FnMut(u32) -> u32,
+    ) {
+        let data_blocks = self.data_blocks() as usize;
+        // direct
+        for block_id in self.direct.iter_mut().take(data_blocks.min(INODE_DIRECT_COUNT)) {
+            *block_id = f(*block_id);
+        }
+        if data_blocks <= INODE_DIRECT_COUNT {
+            return;
+        }
+        // indirect1
+        self.indirect1 = f(self.indirect1);
+        get_block_cache(
+            self.indirect1 as usize,
+            Arc::clone(block_device)
+        )
+        .lock()
+        .modify(0, |indirect1: &mut IndirectBlock| {
+            let count = (data_blocks - INODE_DIRECT_COUNT).min(INODE_INDIRECT1_COUNT);
+            for block_id in indirect1.iter_mut().take(count) {
+                *block_id = f(*block_id);
+            }
+        });
+        if data_blocks <= INDIRECT1_BOUND {
+            return;
+        }
+        // indirect2
+        self.indirect2 = f(self.indirect2);
+        let last = data_blocks - INDIRECT1_BOUND;
+        get_block_cache(
+            self.indirect2 as usize,
+            Arc::clone(block_device)
+        )
+        .lock()
+        .modify(0, |indirect2: &mut IndirectBlock| {
+            let a1 = (last + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            for (a0, indirect1_id) in indirect2.iter_mut().take(a1).enumerate() {
+                *indirect1_id = f(*indirect1_id);
+                let count = (last - a0 * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                get_block_cache(
+                    *indirect1_id as usize,
+                    Arc::clone(block_device)
+                )
+                .lock()
+                .modify(0, |indirect1: &mut IndirectBlock| {
+                    for block_id in indirect1.iter_mut().take(count) {
+                        *block_id = f(*block_id);
+                    }
+                });
+            }
+        });
     }
     /// Inncrease the size of current disk inode
+    ///
+    /// Inline data moves to the first new block once it no longer fits.
     pub fn increase_size(
         &mut self,
         new_size: u32,
         new_blocks: Vec<u32>,
         block_device: &Arc<dyn BlockDevice>,
     ) {
+        if self.inline {
+            if new_size as usize <= INLINE_DATA_LIMIT {
+                // bytes past the old size are kept zero
+                self.size = new_size;
+            } else {
+                self.spill_inline(new_size, new_blocks, block_device);
+            }
+            return;
+        }
         let mut current_blocks = self.data_blocks();
         self.size = new_size;
         let mut total_blocks = self.data_blocks();
@@ -255,10 +731,38 @@ impl DiskInode {
             }
         });
     }
+    /// Move inline data to the first of `new_blocks` and grow to `new_size`,
+    /// `new_blocks` holding `total_blocks(new_size)` fresh blocks
+    pub fn spill_inline(
+        &mut self,
+        new_size: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        assert!(self.inline);
+        let data = self.inline_bytes();
+        let size = self.size as usize;
+        self.inline = false;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.size = 0;
+        self.increase_size(new_size, new_blocks, block_device);
+        if size > 0 {
+            get_block_cache(self.direct[0] as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block[..size].copy_from_slice(&data[..size]);
+                });
+        }
+    }
     /// Clear size to zero and return blocks that should be deallocated
     /// and clear the block contents to zero later
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
         let mut v: Vec<u32> = Vec::new();
+        if self.inline {
+            self.size = 0;
+            self.direct.iter_mut().for_each(|v| *v = 0);
+            return v;
+        }
         let mut data_blocks = self.data_blocks() as usize;
         self.size = 0;
         let mut current_blocks = 0usize;
@@ -308,20 +812,20 @@ impl DiskInode {
         .lock()
         .modify(0, |indirect2: &mut IndirectBlock| {
             // full indirect1 blocks
-            for i in 0..a1 {
-                v.push(indirect2[i]);
+            for entry in indirect2.iter_mut().take(a1) {
+                v.push(*entry);
                 get_block_cache(
-                    indirect2[i] as usize,
+                    *entry as usize,
                     Arc::clone(block_device),
                 )
                 .lock()
                 .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..INODE_INDIRECT1_COUNT {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
+                    for entry in indirect1.iter_mut() {
+                        v.push(*entry);
+                        //*entry = 0;
                     }
                 });
-                //indirect2[i] = 0;
+                //*entry = 0;
             }
             // last indirect1 block
             if b1 > 0 {
@@ -332,9 +836,9 @@ impl DiskInode {
                 )
                 .lock()
                 .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..b1 {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
+                    for entry in indirect1.iter_mut().take(b1) {
+                        v.push(*entry);
+                        //*entry = 0;
                     }
                 });
                 //indirect2[a1] = 0;
@@ -350,10 +854,26 @@ impl DiskInode {
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
+        self.try_read_at(offset, buf, block_device, &ANY_BLOCK)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Read data from current disk inode, checking that the blocks read
+    /// lie in `data_area`
+    pub fn try_read_at(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        data_area: &Range<u32>,
+    ) -> Result<usize, EfsError> {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        let end = offset.saturating_add(buf.len()).min(self.size as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
+        }
+        if self.inline {
+            buf[..end - start].copy_from_slice(&self.inline_bytes()[start..end]);
+            return Ok(end - start);
         }
         let mut start_block = start / BLOCK_SZ;
         let mut read_size = 0usize;
@@ -364,10 +884,10 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
+            try_get_block_cache(
+                self.try_get_block_id(start_block as u32, block_device, data_area)? as usize,
                 Arc::clone(block_device),
-            )
+            )?
             .lock()
             .read(0, |data_block: &DataBlock| {
                 let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
@@ -379,19 +899,49 @@ impl DiskInode {
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        Ok(read_size)
+    }
+    /// Read the directory entry at `offset` of current directory
+    pub fn read_dirent(&self, offset: usize, block_device: &Arc<dyn BlockDevice>) -> DirEntry {
+        self.try_read_dirent(offset, block_device, &ANY_BLOCK)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Read the directory entry at `offset` of current directory, checking
+    /// that its block lies in `data_area`
+    pub fn try_read_dirent(
+        &self,
+        offset: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        data_area: &Range<u32>,
+    ) -> Result<DirEntry, EfsError> {
+        let mut bytes = [0u8; DIRENT_SZ];
+        if self.try_read_at(offset, &mut bytes, block_device, data_area)? != DIRENT_SZ {
+            return Err(EfsError::Corrupted("directory entry past the end"));
+        }
+        DirEntry::decode(&bytes).ok_or(EfsError::Corrupted("invalid directory entry"))
     }
     /// Write data into current disk inode
     /// size must be adjusted properly beforehand
+    ///
+    /// `cow` is called with every data block about to be written and returns
+    /// the block to actually write into, which differs from its argument
+    /// when a block shared with a clone has to be copied first
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
         block_device: &Arc<dyn BlockDevice>,
+        mut cow: impl FnMut(u32) -> u32,
     ) -> usize {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        let end = offset.saturating_add(buf.len()).min(self.size as usize);
         assert!(start <= end);
+        if self.inline {
+            let mut bytes = self.inline_bytes();
+            bytes[start..end].copy_from_slice(&buf[..end - start]);
+            self.set_inline_bytes(&bytes);
+            return end - start;
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
         loop {
@@ -400,8 +950,13 @@ impl DiskInode {
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            let private_block_id = cow(block_id);
+            if private_block_id != block_id {
+                self.set_block_id(start_block as u32, private_block_id, block_device);
+            }
             get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
+                private_block_id as usize,
                 Arc::clone(block_device)
             )
             .lock()
@@ -420,8 +975,29 @@ impl DiskInode {
     }
 }
 
+/// Check that a block id read from an inode lies in `data_area`
+fn check_block_id(block_id: u32, data_area: &Range<u32>) -> Result<u32, EfsError> {
+    if data_area.contains(&block_id) {
+        Ok(block_id)
+    } else {
+        Err(EfsError::Corrupted("block id out of the data area"))
+    }
+}
+
+/// Read entry `index` of an indirect block, checking the indirect block
+fn read_indirect(
+    block_id: u32,
+    index: usize,
+    block_device: &Arc<dyn BlockDevice>,
+    data_area: &Range<u32>,
+) -> Result<u32, EfsError> {
+    let block_id = check_block_id(block_id, data_area)?;
+    Ok(try_get_block_cache(block_id as usize, Arc::clone(block_device))?
+        .lock()
+        .read(0, |indirect_block: &IndirectBlock| indirect_block[index]))
+}
+
 /// A directory entry
-#[repr(C)]
 pub struct DirEntry {
     name: [u8; NAME_LENGTH_LIMIT + 1],
     inode_number: u32,
@@ -448,26 +1024,15 @@ impl DirEntry {
         }
     }
     /// Serialize into bytes
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(
-                self as *const _ as usize as *const u8,
-                DIRENT_SZ,
-            )
-        }
-    }
-    /// Serialize into mutable bytes
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::slice::from_raw_parts_mut(
-                self as *mut _ as usize as *mut u8,
-                DIRENT_SZ,
-            )
-        }
+    pub fn to_bytes(&self) -> [u8; DIRENT_SZ] {
+        let mut bytes = [0u8; DIRENT_SZ];
+        self.encode(&mut bytes);
+        bytes
     }
     /// Get name of the entry
     pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
+        // checked by decode
+        let len = self.name.iter().position(|b| *b == 0).unwrap();
         core::str::from_utf8(&self.name[..len]).unwrap()
     }
     /// Get inode number of the entry
@@ -475,3 +1040,22 @@ impl DirEntry {
         self.inode_number
     }
 }
+
+impl OnDisk for DirEntry {
+    const SIZE: usize = DIRENT_SZ;
+    /// The name must be nul-terminated UTF-8
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut name = [0u8; NAME_LENGTH_LIMIT + 1];
+        name.copy_from_slice(&bytes[..NAME_LENGTH_LIMIT + 1]);
+        let len = name.iter().position(|b| *b == 0)?;
+        core::str::from_utf8(&name[..len]).ok()?;
+        Some(Self {
+            name,
+            inode_number: get_u32(bytes, NAME_LENGTH_LIMIT + 1),
+        })
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[..NAME_LENGTH_LIMIT + 1].copy_from_slice(&self.name);
+        put_u32(bytes, NAME_LENGTH_LIMIT + 1, self.inode_number);
+    }
+}
@@ -1,19 +1,54 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 mod block_dev;
+mod error;
+mod mem_block_dev;
+mod fault_block_dev;
+#[cfg(all(feature = "std", unix))]
+mod file_block_dev;
+mod codec;
 mod layout;
 mod efs;
 mod bitmap;
+mod refcount;
 mod vfs;
 mod block_cache;
+mod inode_cache;
+mod snapshot;
+mod grow;
+mod defrag;
+mod inspect;
+#[cfg(feature = "std")]
+mod file;
 
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
-pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use block_dev::{BlockDevice, BlockError};
+pub use error::EfsError;
+pub use mem_block_dev::MemBlockDevice;
+pub use fault_block_dev::FaultBlockDevice;
+pub use efs::{EasyFileSystem, DiscardMode, MountOptions, Usage};
+pub use defrag::Fragmentation;
+pub use inspect::{BlockMap, InodeInfo, SuperBlockInfo};
+pub use vfs::{Inode, InodeType, ReadDir};
+#[cfg(feature = "std")]
+pub use file::EfsFile;
+pub use layout::{DiskInode, DIRENT_SZ, EFS_VERSION, EFS_MIN_VERSION, MAX_FILE_SIZE, NAME_LENGTH_LIMIT};
 use layout::*;
+use codec::{OnDisk, get_u32, put_u32};
 use bitmap::Bitmap;
-use block_cache::{get_block_cache, block_cache_sync_all};
+use efs::{DataArea, data_area_layout};
+use refcount::BlockRefcount;
+use inode_cache::InodeCache;
+use block_cache::{
+    get_block_cache,
+    try_get_block_cache,
+    get_zeroed_block_cache,
+    block_cache_discard,
+    block_cache_sync,
+    block_cache_sync_all,
+};
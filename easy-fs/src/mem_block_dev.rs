@@ -0,0 +1,64 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+use super::{
+    BlockDevice,
+    BlockError,
+    BLOCK_SZ,
+};
+
+/// A block device held in memory, for tests and embedding
+pub struct MemBlockDevice {
+    blocks: Mutex<Vec<[u8; BLOCK_SZ]>>,
+}
+
+impl MemBlockDevice {
+    /// A zeroed device of `blocks` blocks
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            blocks: Mutex::new(vec![[0u8; BLOCK_SZ]; blocks]),
+        }
+    }
+    /// A device holding a copy of an image, padded with zeros to whole blocks
+    pub fn from_image(image: &[u8]) -> Self {
+        let device = Self::new((image.len() + BLOCK_SZ - 1) / BLOCK_SZ);
+        for (block, chunk) in device.blocks.lock().iter_mut().zip(image.chunks(BLOCK_SZ)) {
+            block[..chunk.len()].copy_from_slice(chunk);
+        }
+        device
+    }
+    /// Get the number of blocks
+    pub fn blocks(&self) -> usize {
+        self.blocks.lock().len()
+    }
+    /// Get a copy of the whole device
+    pub fn to_image(&self) -> Vec<u8> {
+        self.blocks.lock().iter().flatten().copied().collect()
+    }
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        let blocks = self.blocks.lock();
+        let block = blocks.get(block_id).ok_or(BlockError::OutOfRange)?;
+        buf.copy_from_slice(block);
+        Ok(())
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        let mut blocks = self.blocks.lock();
+        let block = blocks.get_mut(block_id).ok_or(BlockError::OutOfRange)?;
+        block.copy_from_slice(buf);
+        Ok(())
+    }
+    /// Discarded blocks read back as zeros
+    fn discard(&self, block_ids: Range<usize>) -> Result<(), BlockError> {
+        let mut blocks = self.blocks.lock();
+        let discarded = blocks.get_mut(block_ids).ok_or(BlockError::OutOfRange)?;
+        discarded.iter_mut().for_each(|block| block.fill(0));
+        Ok(())
+    }
+    fn can_discard(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::sync::Arc;
+use super::{
+    BlockDevice,
+    BLOCK_SZ,
+    get_block_cache,
+};
+
+/// A refcount block
+type RefcountBlock = [u16; BLOCK_SZ / 2];
+
+/// Number of counters in a block
+const REFCOUNTS_PER_BLOCK: usize = BLOCK_SZ / 2;
+
+/// Reference counters of data blocks
+///
+/// Each counter records how many *extra* owners a data block has,
+/// so 0 means the block is owned by a single inode and a zeroed
+/// area describes a filesystem without any shared blocks.
+pub struct BlockRefcount {
+    start_block_id: usize,
+    blocks: usize,
+}
+
+/// Decompose a data block id into (block_pos, inner_pos)
+fn decomposition(pos: usize) -> (usize, usize) {
+    (pos / REFCOUNTS_PER_BLOCK, pos % REFCOUNTS_PER_BLOCK)
+}
+
+impl BlockRefcount {
+    /// A new refcount area from start block id and number of blocks
+    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self {
+            start_block_id,
+            blocks,
+        }
+    }
+    /// Whether the filesystem has a refcount area at all
+    pub fn is_enabled(&self) -> bool {
+        self.blocks > 0
+    }
+    /// Get the number of extra owners of a data block
+    pub fn get(&self, block_device: &Arc<dyn BlockDevice>, pos: usize) -> u16 {
+        if !self.is_enabled() {
+            return 0;
+        }
+        let (block_pos, inner_pos) = decomposition(pos);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device),
+        ).lock().read(0, |refcount_block: &RefcountBlock| {
+            refcount_block[inner_pos]
+        })
+    }
+    /// Add an owner to a data block, return false if the counter is saturated
+    pub fn inc(&self, block_device: &Arc<dyn BlockDevice>, pos: usize) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let (block_pos, inner_pos) = decomposition(pos);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device),
+        ).lock().modify(0, |refcount_block: &mut RefcountBlock| {
+            if refcount_block[inner_pos] == u16::MAX {
+                false
+            } else {
+                refcount_block[inner_pos] += 1;
+                true
+            }
+        })
+    }
+    /// Drop an extra owner of a shared data block
+    pub fn dec(&self, block_device: &Arc<dyn BlockDevice>, pos: usize) {
+        let (block_pos, inner_pos) = decomposition(pos);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device),
+        ).lock().modify(0, |refcount_block: &mut RefcountBlock| {
+            assert!(refcount_block[inner_pos] > 0);
+            refcount_block[inner_pos] -= 1;
+        });
+    }
+    /// Get the max number of data blocks covered by the counters
+    pub fn maximum(&self) -> usize {
+        self.blocks * REFCOUNTS_PER_BLOCK
+    }
+}
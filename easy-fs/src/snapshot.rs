@@ -0,0 +1,207 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    DiskInode,
+    DiskInodeType,
+    EasyFileSystem,
+    SuperBlock,
+    NAME_LENGTH_LIMIT,
+    FEATURE_COMPAT_SNAPSHOTS,
+    get_block_cache,
+    block_cache_sync_all,
+};
+
+impl EasyFileSystem {
+    /// Create an inode of the same type as `src_id` sharing all its data blocks
+    ///
+    /// Indirect blocks are never shared, so copy-on-write only has to
+    /// deal with data blocks.
+    pub(crate) fn clone_file(&self, src_id: u32) -> u32 {
+        let mut src = self.load_disk_inode(src_id);
+        let new_id = self.alloc_inode();
+        if src.is_inline() {
+            // nothing to share, the data lives in the inode itself; the
+            // clone is linked by its caller
+            src.nlink = 0;
+            self.store_disk_inode(new_id, src);
+            return new_id;
+        }
+        let shared: Vec<u32> = src
+            .data_block_ids(&self.block_device)
+            .into_iter()
+            .map(|block_id| self.share_data(block_id))
+            .collect();
+        let v = DiskInode::with_indirect_blocks(shared, || self.alloc_data());
+        let block_device = Arc::clone(&self.block_device);
+        self.modify_disk_inode(new_id, |disk_inode| {
+            disk_inode.initialize(src.type_());
+            disk_inode.increase_size(src.size, v, &block_device);
+        });
+        new_id
+    }
+    /// Clone the tree rooted at `src_id`, whose clone will live in `parent_id`
+    ///
+    /// `cloned` maps already cloned inodes to their clones, which keeps
+    /// hard links inside the tree pointing to a single inode.
+    fn clone_tree(
+        &self,
+        src_id: u32,
+        parent_id: u32,
+        cloned: &mut BTreeMap<u32, u32>,
+    ) -> u32 {
+        if let Some(new_id) = cloned.get(&src_id) {
+            return *new_id;
+        }
+        let is_dir = self.read_disk_inode(src_id, |disk_inode| disk_inode.is_dir());
+        if !is_dir {
+            let new_id = self.clone_file(src_id);
+            cloned.insert(src_id, new_id);
+            return new_id;
+        }
+        let new_id = self.new_inode(DiskInodeType::Directory);
+        cloned.insert(src_id, new_id);
+        self.clone_entries(src_id, new_id, parent_id, cloned);
+        new_id
+    }
+    /// Clone the entries of directory `src_id` into directory `dst_id`
+    fn clone_entries(
+        &self,
+        src_id: u32,
+        dst_id: u32,
+        parent_id: u32,
+        cloned: &mut BTreeMap<u32, u32>,
+    ) {
+        for (name, inode_id) in self.dir_entries(src_id) {
+            let new_id = match name.as_str() {
+                "." => dst_id,
+                ".." => parent_id,
+                _ => self.clone_tree(inode_id, dst_id, cloned),
+            };
+            self.push_dir_entry(dst_id, &name, new_id);
+        }
+    }
+    /// Free every inode reachable from `root_id`, the root itself
+    /// is only emptied if `keep_root` is set
+    fn free_tree(&self, root_id: u32, keep_root: bool) {
+        let mut reachable: BTreeSet<u32> = BTreeSet::new();
+        let mut stack: Vec<u32> = Vec::from([root_id]);
+        while let Some(inode_id) = stack.pop() {
+            if !reachable.insert(inode_id) {
+                continue;
+            }
+            if self.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir()) {
+                stack.extend(
+                    self.dir_entries(inode_id)
+                        .into_iter()
+                        .filter(|(name, _)| name != "." && name != "..")
+                        .map(|(_, child_id)| child_id),
+                );
+            }
+        }
+        for inode_id in reachable {
+            if keep_root && inode_id == root_id {
+                let block_device = Arc::clone(&self.block_device);
+                let data_blocks_dealloc = self.modify_disk_inode(inode_id, |disk_inode| {
+                    disk_inode.clear_size(&block_device)
+                });
+                self.dealloc_data_blocks(data_blocks_dealloc);
+            } else {
+                self.free_inode(inode_id);
+            }
+        }
+    }
+    /// Get the directory holding snapshots, creating it if asked to
+    fn snapshot_dir(&self, create: bool) -> Option<u32> {
+        let snapshot_inode = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.snapshot_inode);
+        if snapshot_inode != 0 {
+            return Some(snapshot_inode);
+        }
+        if !create {
+            return None;
+        }
+        let snapshot_inode = self.new_inode(DiskInodeType::Directory);
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.snapshot_inode = snapshot_inode;
+                super_block.add_feature_compat(FEATURE_COMPAT_SNAPSHOTS);
+            });
+        Some(snapshot_inode)
+    }
+    /// Freeze the current root tree under `name`
+    ///
+    /// Files share their data blocks with the live tree until either side
+    /// writes. Returns false if snapshots are not supported by this image
+    /// or the name is invalid or already taken.
+    pub fn snapshot(&self, name: &str) -> bool {
+        let _tree = self.tree_lock().write();
+        if !self.cow_enabled() || name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
+            return false;
+        }
+        let snapshot_dir = self.snapshot_dir(true).unwrap();
+        if self.dir_entries(snapshot_dir).iter().any(|(n, _)| n == name) {
+            return false;
+        }
+        let mut cloned = BTreeMap::new();
+        let snapshot_root = self.new_inode(DiskInodeType::Directory);
+        cloned.insert(0, snapshot_root);
+        self.clone_entries(0, snapshot_root, snapshot_root, &mut cloned);
+        self.push_dir_entry(snapshot_dir, name, snapshot_root);
+        block_cache_sync_all();
+        true
+    }
+    /// List the names of all snapshots
+    pub fn list_snapshots(&self) -> Vec<String> {
+        let _tree = self.tree_lock().write();
+        match self.snapshot_dir(false) {
+            Some(snapshot_dir) => self
+                .dir_entries(snapshot_dir)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Replace the current root tree with the snapshot `name`
+    ///
+    /// The snapshot itself is kept. All `Inode`s other than the root
+    /// handed out before the restore become invalid.
+    pub fn restore_snapshot(&self, name: &str) -> bool {
+        let _tree = self.tree_lock().write();
+        let snapshot_root = match self.snapshot_dir(false).and_then(|snapshot_dir| {
+            self.dir_entries(snapshot_dir)
+                .into_iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, inode_id)| inode_id)
+        }) {
+            Some(inode_id) => inode_id,
+            None => return false,
+        };
+        self.free_tree(0, true);
+        let mut cloned = BTreeMap::new();
+        cloned.insert(snapshot_root, 0);
+        self.clone_entries(snapshot_root, 0, 0, &mut cloned);
+        block_cache_sync_all();
+        true
+    }
+    /// Delete the snapshot `name` and release its blocks
+    pub fn delete_snapshot(&self, name: &str) -> bool {
+        let _tree = self.tree_lock().write();
+        let snapshot_dir = match self.snapshot_dir(false) {
+            Some(snapshot_dir) => snapshot_dir,
+            None => return false,
+        };
+        match self.remove_dir_entry(snapshot_dir, name) {
+            Some(snapshot_root) => {
+                self.free_tree(snapshot_root, false);
+                block_cache_sync_all();
+                true
+            }
+            None => false,
+        }
+    }
+}
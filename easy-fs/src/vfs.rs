@@ -1,37 +1,63 @@
 // use std::println;
 
-use crate::BLOCK_SZ;
-
 use super::{
-    block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    EasyFileSystem, DIRENT_SZ,
+    block_cache_sync_all, get_block_cache, BlockDevice, BlockError, DirEntry, DiskInode,
+    DiskInodeType, EasyFileSystem, EfsError, SuperBlock, DIRENT_SZ, FEATURE_INCOMPAT_SYMLINKS,
+    MAX_FILE_SIZE, NAME_LENGTH_LIMIT,
 };
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::{Mutex, MutexGuard};
+use core::cmp::Ordering;
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Type of an inode as seen through directory listings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InodeType {
+    File,
+    Directory,
+    SymLink,
+}
+
+impl InodeType {
+    /// Get the type of a disk inode
+    pub(crate) fn of(disk_inode: &DiskInode) -> Self {
+        if disk_inode.is_dir() {
+            Self::Directory
+        } else if disk_inode.is_symlink() {
+            Self::SymLink
+        } else {
+            Self::File
+        }
+    }
+}
 
 /// Virtual filesystem layer over easy-fs
+///
+/// There is a single `Inode` per inode in use, handed out by
+/// `EasyFileSystem::get_inode`. Operations take the tree lock of the
+/// filesystem shared and then the locks of the inodes they touch, see
+/// `EasyFileSystem` for the order.
 pub struct Inode {
     pub block_id: usize,
     pub block_offset: usize,
-    fs: Arc<Mutex<EasyFileSystem>>,
+    inode_id: u32,
+    lock: RwLock<()>,
+    fs: Arc<EasyFileSystem>,
     block_device: Arc<dyn BlockDevice>,
 }
 
 impl Inode {
-    /// Create a vfs inode
-    pub fn new(
-        block_id: u32,
-        block_offset: usize,
-        fs: Arc<Mutex<EasyFileSystem>>,
-        block_device: Arc<dyn BlockDevice>,
-    ) -> Self {
+    /// Create a vfs inode, only to be called by the inode cache
+    pub(crate) fn new(inode_id: u32, fs: Arc<EasyFileSystem>) -> Self {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
         Self {
             block_id: block_id as usize,
             block_offset,
+            inode_id,
+            lock: RwLock::new(()),
+            block_device: Arc::clone(&fs.block_device),
             fs,
-            block_device,
         }
     }
     /// Call a function over a disk inode to read it
@@ -40,23 +66,21 @@ impl Inode {
             .lock()
             .read(self.block_offset, f)
     }
-    /// Call a function over a disk inode to modify it
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
-        get_block_cache(self.block_id, Arc::clone(&self.block_device))
-            .lock()
-            .modify(self.block_offset, f)
+    /// Lock current inode for reading
+    fn lock_shared(&self) -> RwLockReadGuard<()> {
+        self.lock.read()
+    }
+    /// Lock current inode for writing
+    fn lock_exclusive(&self) -> RwLockWriteGuard<()> {
+        self.lock.write()
     }
     /// Find inode under a disk inode by name
     fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
         for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
+            let dirent = disk_inode.read_dirent(DIRENT_SZ * i, &self.block_device);
             if dirent.name() == name {
                 return Some(dirent.inode_number() as u32);
             }
@@ -64,288 +88,547 @@ impl Inode {
         None
     }
     pub fn find_inode_id_by_name(&self, name: &str) -> Option<u32> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
         self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode))
     }
     pub fn get_inode_number(&self) -> usize{
-        let inode_size = core::mem::size_of::<DiskInode>();
-        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
-        let tem1 = self.block_id - self.fs.lock().inode_area_start_block as usize;
-        
-        return tem1 * (inodes_per_block as usize) + self.block_offset/inode_size
-    }
-    pub fn create_hard_link(&self, o_name: &str, n_name: &str) -> isize {  
-        if o_name == n_name {
-            return -1;
+        self.inode_id as usize
+    }
+    /// Create a copy-on-write clone of current file in directory `parent`
+    ///
+    /// The clone shares all data blocks with current inode until one of
+    /// them writes. Returns None if current inode is a directory, `new_name`
+    /// already exists, or the image has no block refcounts.
+    pub fn clone_to(&self, parent: &Inode, new_name: &str) -> Option<Arc<Inode>> {
+        let _tree = self.fs.tree_lock().read();
+        // take both locks in ascending inode id order, or once for the same inode
+        let (_src, _dir) = match self.inode_id.cmp(&parent.inode_id) {
+            Ordering::Equal => (None, parent.lock_exclusive()),
+            Ordering::Less => {
+                let src = self.lock_shared();
+                (Some(src), parent.lock_exclusive())
+            }
+            Ordering::Greater => {
+                let dir = parent.lock_exclusive();
+                (Some(self.lock_shared()), dir)
+            }
+        };
+        if !self.fs.cow_enabled() || self.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return None;
         }
-        if let Some(inode_number) =
-            self.read_disk_inode(|disk_inode| self.find_inode_id(o_name, disk_inode))
-        {
-            let mut fs = self.fs.lock();
-            self.modify_disk_inode(|root_inode| {
-                // append file in the dirent
-                let file_count = (root_inode.size as usize) / DIRENT_SZ;
-                let new_size = (file_count + 1) * DIRENT_SZ;
-                // increase size
-                self.increase_size(new_size as u32, root_inode, &mut fs);
-                // write dirent
-                let dirent = DirEntry::new(n_name, inode_number);
-                root_inode.write_at(
-                    file_count * DIRENT_SZ,
-                    dirent.as_bytes(),
-                    &self.block_device,
-                );
-            });
-            return 0;
-        } else {
+        let op = |dir_inode: &DiskInode| parent.find_inode_id(new_name, dir_inode);
+        if parent.read_disk_inode(op).is_some() {
+            return None;
+        }
+        let new_inode_id = self.fs.clone_file(self.inode_id);
+        self.fs.push_dir_entry(parent.inode_id, new_name, new_inode_id);
+        block_cache_sync_all();
+        Some(EasyFileSystem::get_inode(&self.fs, new_inode_id))
+    }
+    pub fn create_hard_link(&self, o_name: &str, n_name: &str) -> isize {
+        if o_name == n_name || n_name.is_empty() || n_name.len() > NAME_LENGTH_LIMIT {
             return -1;
         }
-        
-
-        // let o_len = (0usize..).find(|i| _old_name[*i] == 0).unwrap();
-        // let o_name = core::str::from_utf8(&_old_name[..o_len]).unwrap();
-
-        // let n_len = (0usize..).find(|i| _new_name[*i] == 0).unwrap();
-        // let n_name = core::str::from_utf8(&_new_name[..n_len]).unwrap();
-        
+        // the linked inode gains a link without being locked
+        let _tree = self.fs.tree_lock().write();
+        let (old, new) = self.read_disk_inode(|disk_inode| {
+            (
+                self.find_inode_id(o_name, disk_inode),
+                self.find_inode_id(n_name, disk_inode),
+            )
+        });
+        match (old, new) {
+            (Some(inode_number), None) if self.fs.can_link(inode_number) => {
+                self.fs.push_dir_entry(self.inode_id, n_name, inode_number);
+                block_cache_sync_all();
+                0
+            }
+            _ => -1,
+        }
     }
     pub fn get_type(&self, disk_inode: &DiskInode) -> usize{
-        if disk_inode.is_dir(){
-            return 0;
-        }else{
-            return 1;
+        if disk_inode.is_dir() {
+            0
+        } else {
+            1
         }
     }
 
     pub fn get_inode_type(&self) -> usize{
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
         self.read_disk_inode(|disk_inode| {
             self.get_type(disk_inode)
         })
     }
 
     pub fn remove_hard_link(&self, name: &str) -> isize {
-        
-
-        if let Some(inode_number) =
-            self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode))
-        {
-            let res = self.get_inode_number_times(inode_number);
-            if res > 1{
-                self.modify_disk_inode(|disk_inode| {
-                    // append file in the dirent
-                    let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-                    let mut dirent = DirEntry::empty();
-                    for i in 0..file_count {
-                        assert_eq!(
-                            disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                            DIRENT_SZ,
-                        );
-                        if dirent.name() == name {
-                            let dirent = DirEntry::empty();
-                            disk_inode.write_at(
-                                DIRENT_SZ * i,
-                                dirent.as_bytes(),
-                                &self.block_device,
-                            );
-                        }
-                    }
-                    
-                });
-                return 0;
-            }else{
-                let inode = self.find(name).unwrap();
-                drop(inode);
-                self.modify_disk_inode(|disk_inode| {
-                    // append file in the dirent
-                    let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-                    let mut dirent = DirEntry::empty();
-                    for i in 0..file_count {
-                        assert_eq!(
-                            disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                            DIRENT_SZ,
-                        );
-                        if dirent.name() == name {
-                            let dirent = DirEntry::empty();
-                            disk_inode.write_at(
-                                DIRENT_SZ * i,
-                                dirent.as_bytes(),
-                                &self.block_device,
-                            );
-                        }
-                    }
-                    
-                });
+        // the unlinked inode loses a link without being locked
+        let _tree = self.fs.tree_lock().write();
+        match self.fs.remove_dir_entry(self.inode_id, name) {
+            Some(_) => {
+                block_cache_sync_all();
+                0
             }
-            return 0;
-        } else {
-            return -1;
+            None => -1,
         }
     }
-    
-    fn get_times_by_inode_number(&self, inode_number: u32, disk_inode: &DiskInode)-> usize{
-        let mut res = 0;
-        let _fs = self.fs.lock();
-        assert!(disk_inode.is_dir());
+
+    pub fn get_inode_number_times(&self, inode_number: u32) -> usize{
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        self.fs
+            .dir_entries(self.inode_id)
+            .iter()
+            .filter(|(_, inode_id)| *inode_id == inode_number)
+            .count()
+    }
+    /// Find inode under current inode by name
+    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
+        self.try_find(name).unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Find inode under current inode by name, checking what is read
+    pub fn try_find(&self, name: &str) -> Result<Option<Arc<Inode>>, EfsError> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        let data_area = self.fs.data_area_range();
+        let disk_inode = self.fs.try_load_disk_inode(self.inode_id)?;
+        if !disk_inode.is_dir() {
+            return Err(EfsError::NotDir);
+        }
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
         for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
-            // println!("DEBUG {} {}",dirent.inode_number(),inode_number);
-            if dirent.inode_number() == inode_number {
-                res+=1;
+            let dirent = disk_inode.try_read_dirent(DIRENT_SZ * i, &self.block_device, &data_area)?;
+            if dirent.name() == name {
+                self.fs.check_inode_id(dirent.inode_number())?;
+                return Ok(Some(EasyFileSystem::get_inode(&self.fs, dirent.inode_number())));
             }
         }
-        return res;
+        Ok(None)
     }
-
-    pub fn get_inode_number_times(&self, inode_number: u32) -> usize{
-        // self.read_disk_inode(|disk_inode| {
-        //     self.get_times_by_inode_number(inode_number, disk_inode)
-        // })
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut res = 0;
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                if dirent.inode_number() == inode_number{
-                    res += 1;
-                }
-            }
-            res
-        })
+    /// Get the type of current inode
+    pub fn inode_type(&self) -> InodeType {
+        self.try_inode_type().unwrap_or_else(|err| panic!("{}", err))
     }
-    /// Find inode under current inode by name
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+    /// Get the type of current inode, checking that it is valid
+    pub fn try_inode_type(&self) -> Result<InodeType, EfsError> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        Ok(InodeType::of(&self.fs.try_load_disk_inode(self.inode_id)?))
     }
-    /// Increase the size of a disk inode
-    fn increase_size(
-        &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
-            return;
+    /// Get the size of current inode in bytes
+    pub fn size(&self) -> usize {
+        self.try_size().unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Get the size of current inode in bytes, checking that it is valid
+    pub fn try_size(&self) -> Result<usize, EfsError> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        Ok(self.fs.try_load_disk_inode(self.inode_id)?.size as usize)
+    }
+    /// Count the directory entries linking to current inode, "." and
+    /// ".." excluded
+    ///
+    /// Images without link counts only count the tree under the root.
+    pub fn links(&self) -> usize {
+        if self.fs.link_counts() {
+            let _tree = self.fs.tree_lock().read();
+            let _inode = self.lock_shared();
+            return self.fs.links_of(self.inode_id);
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        // counting walks the whole tree
+        let _tree = self.fs.tree_lock().write();
+        self.fs.links_of(self.inode_id)
+    }
+    /// Add entry `name` to current directory, linking to `inode`
+    ///
+    /// Return 0 on success, -1 if `name` exists, `inode` is a directory
+    /// or has as many links as it can count.
+    pub fn link(&self, name: &str, inode: &Inode) -> isize {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
+            return -1;
+        }
+        let _tree = self.fs.tree_lock().read();
+        // take both locks in ascending inode id order, a directory can't
+        // be linked so the same inode is refused before locking it twice
+        if self.inode_id == inode.inode_id {
+            return -1;
+        }
+        let (_src, _dir) = if inode.inode_id < self.inode_id {
+            let src = inode.lock_exclusive();
+            (src, self.lock_exclusive())
+        } else {
+            let dir = self.lock_exclusive();
+            (inode.lock_exclusive(), dir)
+        };
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir())
+            || !self.fs.can_link(inode.inode_id)
+            || self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode)).is_some()
+        {
+            return -1;
+        }
+        self.fs.push_dir_entry(self.inode_id, name, inode.inode_id);
+        block_cache_sync_all();
+        0
+    }
+    /// Remove entry `name` of current directory, freeing its inode if
+    /// that was its last link
+    ///
+    /// An inode still in use is only freed when its last `Inode` is dropped.
+    /// A directory must be empty. Return 0 on success, -1 otherwise.
+    pub fn unlink(&self, name: &str) -> isize {
+        // the unlinked inode is not locked, and images without link
+        // counts walk the whole tree
+        let _tree = self.fs.tree_lock().write();
+        let fs = &self.fs;
+        if name == "." || name == ".." {
+            return -1;
+        }
+        let inode_id = match self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode)) {
+            Some(inode_id) => inode_id,
+            None => return -1,
+        };
+        if fs.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir()) && !fs.is_empty_dir(inode_id) {
+            return -1;
         }
+        fs.remove_dir_entry(self.inode_id, name);
+        if fs.links_of(inode_id) == 0 {
+            fs.free_unlinked_inode(inode_id);
+        }
+        block_cache_sync_all();
+        0
+    }
+    /// Increase the size of a disk inode, failing with nothing allocated
+    /// if there are not enough free blocks
+    fn increase_size(&self, new_size: u32, disk_inode: &mut DiskInode) -> Result<(), EfsError> {
+        if new_size < disk_inode.size {
+            return Ok(());
+        }
+        let v = self.fs.alloc_blocks_for(disk_inode, new_size).ok_or(EfsError::NoSpace)?;
         disk_inode.increase_size(new_size, v, &self.block_device);
+        Ok(())
     }
     /// Create inode under current inode by name
     pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self
-            .modify_disk_inode(|root_inode| {
-                // assert it is a directory
-                assert!(root_inode.is_dir());
-                // has the file been created?
-                self.find_inode_id(name, root_inode)
-            })
-            .is_some()
-        {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// Create a directory under current inode by name,
+    /// holding "." and ".." entries
+    pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    /// Create inode of the given type under current inode by name
+    ///
+    /// Return None if `name` exists or does not fit in a directory entry.
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
             return None;
         }
-        // create a new file
-        // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
-        // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
-            });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_exclusive();
+        let op = |root_inode: &DiskInode| {
+            // assert it is a directory
+            assert!(root_inode.is_dir());
+            // has the file been created?
+            self.find_inode_id(name, root_inode)
+        };
+        if self.read_disk_inode(op).is_some() {
+            return None;
+        }
+        // create a new file, nobody else can reach it before its entry exists
+        let new_inode_id = self.fs.new_inode(type_);
+        if type_ == DiskInodeType::Directory {
+            self.fs.push_dir_entry(new_inode_id, ".", new_inode_id);
+            self.fs.push_dir_entry(new_inode_id, "..", self.inode_id);
+        }
+        self.fs.push_dir_entry(self.inode_id, name, new_inode_id);
         block_cache_sync_all();
         // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
-        // release efs lock automatically by compiler
+        Some(EasyFileSystem::get_inode(&self.fs, new_inode_id))
+        // release locks automatically by compiler
+    }
+    /// Create a symbolic link to `target` under current inode by name
+    ///
+    /// The target is stored as is and never followed by easy-fs itself.
+    /// Return None if `name` exists, the image format is too old to
+    /// hold symbolic links or there are not enough free blocks.
+    pub fn symlink(&self, name: &str, target: &str) -> Option<Arc<Inode>> {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT || target.is_empty() {
+            return None;
+        }
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_exclusive();
+        if self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode)).is_some() {
+            return None;
+        }
+        let super_block = get_block_cache(0, Arc::clone(&self.block_device));
+        let supported = super_block.lock().read(0, |super_block: &SuperBlock| {
+            super_block.can_express_incompat(FEATURE_INCOMPAT_SYMLINKS)
+        });
+        if !supported {
+            return None;
+        }
+        // nobody else can reach the link before its entry exists
+        let new_inode_id = self.fs.new_inode(DiskInodeType::SymLink);
+        let mut disk_inode = self.fs.load_disk_inode(new_inode_id);
+        let v = match self.fs.alloc_blocks_for(&disk_inode, target.len() as u32) {
+            Some(v) => v,
+            None => {
+                self.fs.dealloc_inode(new_inode_id);
+                return None;
+            }
+        };
+        super_block.lock().modify(0, |super_block: &mut SuperBlock| {
+            super_block.add_feature_incompat(FEATURE_INCOMPAT_SYMLINKS)
+        });
+        disk_inode.increase_size(target.len() as u32, v, &self.block_device);
+        disk_inode.write_at(0, target.as_bytes(), &self.block_device, |block_id| block_id);
+        self.fs.store_disk_inode(new_inode_id, disk_inode);
+        self.fs.push_dir_entry(self.inode_id, name, new_inode_id);
+        block_cache_sync_all();
+        Some(EasyFileSystem::get_inode(&self.fs, new_inode_id))
+    }
+    /// Get the target of current inode if it is a symbolic link
+    pub fn read_link(&self) -> Option<String> {
+        self.try_read_link().unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Get the target of current inode if it is a symbolic link, checking
+    /// what is read
+    pub fn try_read_link(&self) -> Result<Option<String>, EfsError> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        let data_area = self.fs.data_area_range();
+        let disk_inode = self.fs.try_load_disk_inode(self.inode_id)?;
+        if !disk_inode.is_symlink() {
+            return Ok(None);
+        }
+        let mut target = alloc::vec![0u8; disk_inode.size as usize];
+        disk_inode.try_read_at(0, &mut target, &self.block_device, &data_area)?;
+        String::from_utf8(target)
+            .map(Some)
+            .map_err(|_| EfsError::Corrupted("symbolic link target is not UTF-8"))
+    }
+    /// Move entry `old_name` of current directory to `new_name` in `new_dir`
+    ///
+    /// An existing `new_name` is replaced in a single directory entry write
+    /// and freed if that was its last link, once it is no longer in use.
+    /// A directory may only replace an empty directory and can't be moved
+    /// into its own subtree; its ".." is updated when it changes parent.
+    /// Return 0 on success, -1 otherwise.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> isize {
+        // the subtree check walks the whole tree, and so does the link
+        // count of images without link counts
+        let _tree = self.fs.tree_lock().write();
+        let fs = &self.fs;
+        let is_special = |name: &str| name == "." || name == "..";
+        if is_special(old_name)
+            || is_special(new_name)
+            || new_name.is_empty()
+            || new_name.len() > NAME_LENGTH_LIMIT
+            || !new_dir.read_disk_inode(|disk_inode| disk_inode.is_dir())
+        {
+            return -1;
+        }
+        let inode_id = match self.read_disk_inode(|disk_inode| self.find_inode_id(old_name, disk_inode)) {
+            Some(inode_id) => inode_id,
+            None => return -1,
+        };
+        let old_dir_id = self.inode_id;
+        let new_dir_id = new_dir.inode_id;
+        let is_dir = fs.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir());
+        if is_dir && fs.in_subtree(inode_id, new_dir_id) {
+            return -1;
+        }
+        let target_id =
+            new_dir.read_disk_inode(|disk_inode| new_dir.find_inode_id(new_name, disk_inode));
+        match target_id {
+            // both names already link to the same inode
+            Some(target_id) if target_id == inode_id => return 0,
+            Some(target_id) => {
+                let target_is_dir = fs.read_disk_inode(target_id, |disk_inode| disk_inode.is_dir());
+                if is_dir != target_is_dir || (target_is_dir && !fs.is_empty_dir(target_id)) {
+                    return -1;
+                }
+                fs.rewrite_dir_entry(new_dir_id, new_name, &DirEntry::new(new_name, inode_id));
+            }
+            None => fs.push_dir_entry(new_dir_id, new_name, inode_id),
+        }
+        fs.remove_dir_entry(old_dir_id, old_name);
+        if is_dir && old_dir_id != new_dir_id {
+            fs.rewrite_dir_entry(inode_id, "..", &DirEntry::new("..", new_dir_id));
+        }
+        if let Some(target_id) = target_id {
+            if fs.links_of(target_id) == 0 {
+                fs.free_unlinked_inode(target_id);
+            }
+        }
+        block_cache_sync_all();
+        0
     }
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
-            }
-            v
-        })
+        self.read_dir(0).map(|(name, _, _)| name).collect()
+    }
+    /// Iterate over the entries of current directory from byte `offset`
+    ///
+    /// Empty slots are skipped. Locks are only held while a single entry
+    /// is read, and `ReadDir::offset` can be stored to resume later.
+    pub fn read_dir(&self, offset: usize) -> ReadDir<'_> {
+        ReadDir {
+            inode: self,
+            offset: (offset + DIRENT_SZ - 1) / DIRENT_SZ * DIRENT_SZ,
+        }
     }
     /// Read data from current inode
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        self.try_read_at(offset, buf).unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Read data from current inode, checking that the blocks read
+    /// belong to the data area
+    pub fn try_read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, EfsError> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        let data_area = self.fs.data_area_range();
+        let disk_inode = self.fs.try_load_disk_inode(self.inode_id)?;
+        disk_inode.try_read_at(offset, buf, &self.block_device, &data_area)
+    }
+    /// Get the data blocks of current inode in file order,
+    /// none if its data is inline
+    pub fn data_blocks(&self) -> Vec<u32> {
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_shared();
+        let disk_inode = self.fs.load_disk_inode(self.inode_id);
+        disk_inode.data_block_ids(&self.block_device)
     }
     /// Write data to current inode
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+        self.try_write_at(offset, buf).unwrap_or_else(|err| panic!("{}", err))
+    }
+    /// Write data to current inode, growing it as needed
+    ///
+    /// Fails, leaving the inode as is, if it would grow past the largest
+    /// file or there are not enough free blocks to grow it and copy the
+    /// blocks it shares with clones.
+    pub fn try_write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, EfsError> {
+        let end = offset
+            .checked_add(buf.len())
+            .filter(|end| *end <= MAX_FILE_SIZE as usize)
+            .ok_or(EfsError::TooLarge)?;
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_exclusive();
+        let mut disk_inode = self.fs.try_load_disk_inode(self.inode_id)?;
+        // other owners may only give up shared blocks meanwhile, so this
+        // is enough for every copy the write makes
+        let copies = self.fs.shared_blocks(&disk_inode, offset..end);
+        let mut reserved = self.fs.alloc_data_run(0, copies).ok_or(EfsError::NoSpace)?;
+        if let Err(err) = self.increase_size(end as u32, &mut disk_inode) {
+            self.fs.dealloc_data_blocks(reserved);
+            return Err(err);
+        }
+        let size = disk_inode.write_at(offset, buf, &self.block_device, |block_id| {
+            self.fs.cow_data(block_id, &mut reserved)
         });
+        self.fs.store_disk_inode(self.inode_id, disk_inode);
+        if !reserved.is_empty() {
+            self.fs.dealloc_data_blocks(reserved);
+        }
         block_cache_sync_all();
-        size
+        Ok(size)
+    }
+    /// Reserve space for the first `len` bytes of current inode without
+    /// writing them, growing it to `len` bytes if it is shorter
+    ///
+    /// The blocks are allocated in one contiguous run when possible and
+    /// read as zeros. Returns -1, leaving the inode as is, if `len` is
+    /// past the largest file or there are not enough free blocks.
+    pub fn preallocate(&self, len: usize) -> isize {
+        if len > MAX_FILE_SIZE as usize {
+            return -1;
+        }
+        let len = len as u32;
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_exclusive();
+        let mut disk_inode = self.fs.load_disk_inode(self.inode_id);
+        if len <= disk_inode.size {
+            return 0;
+        }
+        let v = match self.fs.alloc_blocks_for(&disk_inode, len) {
+            Some(v) => v,
+            None => return -1,
+        };
+        disk_inode.increase_size(len, v, &self.block_device);
+        self.fs.store_disk_inode(self.inode_id, disk_inode);
+        block_cache_sync_all();
+        0
+    }
+    /// Write the cached blocks of the filesystem back to the device,
+    /// returning the first error
+    pub fn sync(&self) -> Result<(), BlockError> {
+        self.fs.sync()
     }
     /// Clear the data in current inode
     pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
-        });
+        let _tree = self.fs.tree_lock().read();
+        let _inode = self.lock_exclusive();
+        let mut disk_inode = self.fs.load_disk_inode(self.inode_id);
+        let size = disk_inode.size;
+        let blocks = if disk_inode.is_inline() { 0 } else { DiskInode::total_blocks(size) };
+        let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+        assert!(data_blocks_dealloc.len() == blocks as usize);
+        if self.fs.inline_data() && !disk_inode.is_inline() {
+            disk_inode.set_inline();
+        }
+        self.fs.store_disk_inode(self.inode_id, disk_inode);
+        self.fs.dealloc_data_blocks(data_blocks_dealloc);
         block_cache_sync_all();
     }
 }
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        self.fs.release_inode(self.inode_id);
+    }
+}
+
+/// Cursor over the entries of a directory
+pub struct ReadDir<'a> {
+    inode: &'a Inode,
+    offset: usize,
+}
+
+impl ReadDir<'_> {
+    /// Byte offset of the next slot, to be passed to `Inode::read_dir`
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    /// Get the next entry, checking what is read and the inode it links to
+    pub fn try_next(&mut self) -> Result<Option<(String, u32, InodeType)>, EfsError> {
+        let fs = &self.inode.fs;
+        let _tree = fs.tree_lock().read();
+        let _inode = self.inode.lock_shared();
+        let data_area = fs.data_area_range();
+        let disk_inode = fs.try_load_disk_inode(self.inode.inode_id)?;
+        if !disk_inode.is_dir() {
+            return Err(EfsError::NotDir);
+        }
+        while self.offset + DIRENT_SZ <= disk_inode.size as usize {
+            let dirent = disk_inode.try_read_dirent(self.offset, &self.inode.block_device, &data_area)?;
+            self.offset += DIRENT_SZ;
+            if !dirent.name().is_empty() {
+                let type_ = InodeType::of(&fs.try_load_disk_inode(dirent.inode_number())?);
+                return Ok(Some((String::from(dirent.name()), dirent.inode_number(), type_)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    /// (name, inode number, type)
+    type Item = (String, u32, InodeType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().unwrap_or_else(|err| panic!("{}", err))
+    }
+}
@@ -0,0 +1,11 @@
+//! Helpers shared by the integration tests
+
+use easy_fs::{EasyFileSystem, MemBlockDevice};
+use std::sync::Arc;
+
+/// Create an empty in-memory image of `blocks` blocks
+pub fn fresh_fs(blocks: usize) -> (Arc<MemBlockDevice>, Arc<EasyFileSystem>) {
+    let device = Arc::new(MemBlockDevice::new(blocks));
+    let efs = EasyFileSystem::create(device.clone(), blocks as u32, 1);
+    (device, efs)
+}
@@ -0,0 +1,84 @@
+//! Copy-on-write clones and snapshots
+
+mod common;
+
+use common::fresh_fs;
+use easy_fs::{EasyFileSystem, EfsError, InodeType, BLOCK_SZ};
+
+#[test]
+fn efs_cow_test() -> std::io::Result<()> {
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let read_all = |inode: &easy_fs::Inode| {
+        let mut buffer = [0u8; BLOCK_SZ];
+        let mut v: Vec<u8> = Vec::new();
+        loop {
+            let len = inode.read_at(v.len(), &mut buffer);
+            if len == 0 {
+                break v;
+            }
+            v.extend_from_slice(&buffer[..len]);
+        }
+    };
+    // large enough to go through indirect2
+    let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, &data);
+    let fileb = filea.clone_to(&root_inode, "fileb").unwrap();
+    assert!(filea.clone_to(&root_inode, "fileb").is_none());
+    assert_eq!(read_all(&fileb), data);
+    // writing the clone leaves the original untouched
+    fileb.write_at(200 * BLOCK_SZ + 7, b"copy-on-write");
+    assert_eq!(read_all(&filea), data);
+    let mut modified = data;
+    modified[200 * BLOCK_SZ + 7..200 * BLOCK_SZ + 20].copy_from_slice(b"copy-on-write");
+    assert_eq!(read_all(&fileb), modified);
+    // freeing the original keeps the shared blocks alive
+    filea.clear();
+    assert_eq!(read_all(&fileb), modified);
+
+    // symbolic links too long to be inline stay links
+    let target = "long/".repeat(40);
+    let link = root_inode.symlink("link", &target).unwrap();
+    let link_clone = link.clone_to(&root_inode, "link_clone").unwrap();
+    assert_eq!(link_clone.inode_type(), InodeType::SymLink);
+    assert_eq!(link_clone.read_link(), Some(target.clone()));
+
+    assert!(efs.snapshot("snap"));
+    assert!(!efs.snapshot("snap"));
+    assert_eq!(efs.list_snapshots(), vec![String::from("snap")]);
+    fileb.write_at(0, b"after snapshot");
+    root_inode.create("filec").unwrap();
+    assert!(efs.restore_snapshot("snap"));
+    assert!(root_inode.find("filec").is_none());
+    assert_eq!(read_all(&root_inode.find("fileb").unwrap()), modified);
+    assert_eq!(root_inode.find("link").unwrap().read_link(), Some(target));
+    assert!(efs.delete_snapshot("snap"));
+    assert!(efs.list_snapshots().is_empty());
+    assert_eq!(read_all(&root_inode.find("fileb").unwrap()), modified);
+
+    // copying shared blocks on a full disk fails instead of panicking
+    let (_, efs) = fresh_fs(2048);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data = vec![1u8; 20 * BLOCK_SZ];
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, &data);
+    let fileb = filea.clone_to(&root_inode, "fileb").unwrap();
+    let filler = root_inode.create("filler").unwrap();
+    while filler.try_write_at(filler.size(), &[2u8; BLOCK_SZ]).is_ok() {}
+    assert_eq!(efs.free_data_blocks(), 0);
+    assert_eq!(fileb.try_write_at(0, &[3u8; 3 * BLOCK_SZ]), Err(EfsError::NoSpace));
+    assert_eq!(read_all(&fileb), data);
+    // so does a symbolic link, leaving the image without the symlinks feature
+    let free_inodes = efs.usage().free_inodes;
+    assert!(root_inode.symlink("link", &"long/".repeat(80)).is_none());
+    assert_eq!(efs.usage().free_inodes, free_inodes);
+    assert_eq!(efs.super_block_info().feature_incompat & (1 << 2), 0);
+    // a write into private blocks needs none
+    let tail = filler.size() - 3 * BLOCK_SZ;
+    assert_eq!(filler.try_write_at(tail, &[4u8; 3 * BLOCK_SZ]), Ok(3 * BLOCK_SZ));
+    filler.clear();
+    assert_eq!(fileb.try_write_at(0, &[3u8; 3 * BLOCK_SZ]), Ok(3 * BLOCK_SZ));
+    assert_eq!(read_all(&filea), data);
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Defragmenting an image
+
+mod common;
+
+use common::fresh_fs;
+use easy_fs::{EasyFileSystem, BLOCK_SZ};
+use std::sync::Arc;
+
+#[test]
+fn efs_defrag_test() -> std::io::Result<()> {
+    let (device, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.create_dir("dir").unwrap();
+    // interleaved appends scatter the files, the last ones need indirect2
+    let mut files = Vec::new();
+    for (i, blocks) in [40usize, 40, 40, 200, 180].iter().enumerate() {
+        let parent = if i % 2 == 0 { &root_inode } else { &dir };
+        files.push((parent.create(&format!("file{}", i)).unwrap(), *blocks));
+    }
+    for round in 0..200 {
+        for (i, (file, blocks)) in files.iter().enumerate() {
+            if round < *blocks {
+                let data = [(round * 7 + i) as u8; BLOCK_SZ];
+                assert_eq!(file.write_at(round * BLOCK_SZ, &data), BLOCK_SZ);
+            }
+        }
+    }
+    // leave holes behind and share a file with a clone
+    files[1].0.clear();
+    files[2].0.clone_to(&root_inode, "clone").unwrap();
+    let contents = |efs: &Arc<EasyFileSystem>| -> Vec<(String, Vec<u8>)> {
+        let root_inode = EasyFileSystem::root_inode(efs);
+        let dir = root_inode.find("dir").unwrap();
+        let mut v = Vec::new();
+        for (parent, name) in [
+            (&root_inode, "file0"),
+            (&dir, "file1"),
+            (&root_inode, "file2"),
+            (&dir, "file3"),
+            (&root_inode, "file4"),
+            (&root_inode, "clone"),
+        ] {
+            let mut buffer = vec![0u8; 256 * BLOCK_SZ];
+            let len = parent.find(name).unwrap().read_at(0, &mut buffer);
+            buffer.truncate(len);
+            v.push((String::from(name), buffer));
+        }
+        v
+    };
+    let before = contents(&efs);
+    let free = efs.free_data_blocks();
+    let fragmentation = efs.fragmentation();
+    assert!(fragmentation.fragmented >= 4);
+    assert!(efs.defrag() > 0);
+    let defragmented = efs.fragmentation();
+    assert_eq!(defragmented.files, fragmentation.files);
+    assert_eq!(defragmented.blocks, fragmentation.blocks);
+    // only the file and its clone, which share blocks, may stay scattered
+    assert!(defragmented.fragmented <= 2);
+    assert!(defragmented.extents < fragmentation.extents);
+    assert_eq!(efs.free_data_blocks(), free);
+    assert_eq!(contents(&efs), before);
+    // a second pass has nothing left to move
+    assert_eq!(efs.defrag(), 0);
+    drop((files, dir, root_inode, efs));
+
+    let efs = EasyFileSystem::open(device);
+    assert_eq!(contents(&efs), before);
+    assert_eq!(efs.fragmentation(), defragmented);
+    // the bitmaps still match the blocks in use
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.find("file0").unwrap();
+    file.clear();
+    assert_eq!(efs.free_data_blocks(), free + 41);
+    let file = root_inode.create("new").unwrap();
+    assert_eq!(file.write_at(0, &[9u8; 50 * BLOCK_SZ]), 50 * BLOCK_SZ);
+    assert_eq!(contents(&efs)[1..], before[1..]);
+    Ok(())
+}
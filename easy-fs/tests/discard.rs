@@ -0,0 +1,94 @@
+//! Discarding freed blocks
+
+use easy_fs::{
+    BlockDevice, BlockError, DiscardMode, EasyFileSystem, EfsError, MemBlockDevice, MountOptions, BLOCK_SZ,
+};
+use std::sync::Arc;
+
+/// A device which can only read and write blocks
+struct PlainBlockDevice(MemBlockDevice);
+
+impl BlockDevice for PlainBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.0.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.0.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn efs_discard_test() -> std::io::Result<()> {
+    let open_mem = |discard: DiscardMode| {
+        let device = Arc::new(MemBlockDevice::new(4096));
+        EasyFileSystem::create(device.clone(), 4096, 1);
+        let efs = EasyFileSystem::open_with_options(device.clone(), MountOptions { discard });
+        (device, efs)
+    };
+    let holds = |device: &MemBlockDevice, block_id: u32, byte: u8| {
+        let start = block_id as usize * BLOCK_SZ;
+        device.to_image()[start..start + BLOCK_SZ].iter().all(|&b| b == byte)
+    };
+    let mut buffer = vec![0xffu8; 40 * BLOCK_SZ];
+    // discarded blocks are gone from the device, kept ones are left as is
+    for (discard, left) in [(DiscardMode::Discard, 0u8), (DiscardMode::Keep, 1u8)] {
+        let (device, efs) = open_mem(discard);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let file = root_inode.create("file").unwrap();
+        file.write_at(0, &[1u8; 40 * BLOCK_SZ]);
+        let blocks = file.data_blocks();
+        efs.sync().unwrap();
+        file.clear();
+        efs.sync().unwrap();
+        assert!(blocks.iter().all(|block_id| holds(&device, *block_id, left)));
+        // whatever is left, reallocated blocks read back as zeros
+        assert_eq!(file.preallocate(40 * BLOCK_SZ), 0);
+        assert_eq!(file.read_at(0, &mut buffer), 40 * BLOCK_SZ);
+        assert!(buffer.iter().all(|&byte| byte == 0));
+    }
+    // a device which can't discard is not mounted to discard
+    let device = Arc::new(PlainBlockDevice(MemBlockDevice::new(4096)));
+    EasyFileSystem::create(device.clone(), 4096, 1);
+    let err = EasyFileSystem::try_open_with_options(
+        device.clone(),
+        MountOptions { discard: DiscardMode::Discard },
+    )
+    .err();
+    assert_eq!(err, Some(EfsError::Device(BlockError::Unsupported)));
+    assert!(EasyFileSystem::try_open(device).is_ok());
+    Ok(())
+}
+
+/// Freed blocks become holes in an image file
+#[cfg(feature = "std")]
+#[test]
+fn efs_discard_file_test() -> std::io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::MetadataExt;
+    let mut buffer = vec![0xffu8; BLOCK_SZ];
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open("target/fs_discard.img")?;
+    f.set_len((4096 * BLOCK_SZ) as u64).unwrap();
+    let block_file = Arc::new(f.try_clone()?);
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let efs = EasyFileSystem::open_with_options(
+        block_file,
+        MountOptions { discard: DiscardMode::Discard },
+    );
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, &vec![1u8; 1024 * BLOCK_SZ]);
+    efs.sync().unwrap();
+    let allocated = f.metadata()?.blocks();
+    file.clear();
+    efs.sync().unwrap();
+    // st_blocks counts 512-byte units, holes are punched in whole pages
+    assert!(allocated - f.metadata()?.blocks() >= 1000);
+    assert_eq!(file.preallocate(BLOCK_SZ), 0);
+    assert_eq!(file.read_at(0, &mut buffer), BLOCK_SZ);
+    assert!(buffer[..BLOCK_SZ].iter().all(|&byte| byte == 0));
+    Ok(())
+}
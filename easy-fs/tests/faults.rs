@@ -0,0 +1,222 @@
+//! Corrupted images, failing devices and power cuts
+
+mod common;
+
+use common::fresh_fs;
+use easy_fs::{BlockDevice, BlockError, EasyFileSystem, EfsError, FaultBlockDevice, InodeType, MemBlockDevice, BLOCK_SZ};
+use std::sync::Arc;
+
+/// List and read everything reachable in an image through the checked
+/// operations, as the fuzz target of easy-fs does, returning the number
+/// of entries which could be looked at
+fn walk_checked(efs: &Arc<EasyFileSystem>) -> usize {
+    let mut seen = 0;
+    let mut visited = Vec::new();
+    let mut dirs = vec![EasyFileSystem::root_inode(efs)];
+    while let Some(dir) = dirs.pop() {
+        if visited.contains(&dir.get_inode_number()) || visited.len() == 64 {
+            continue;
+        }
+        visited.push(dir.get_inode_number());
+        let mut entries = dir.read_dir(0);
+        while let Ok(Some((name, _, type_))) = entries.try_next() {
+            let inode = match dir.try_find(&name) {
+                Ok(Some(inode)) => inode,
+                _ => continue,
+            };
+            seen += 1;
+            let mut buffer = [0u8; BLOCK_SZ];
+            match type_ {
+                InodeType::Directory => dirs.push(inode),
+                InodeType::File => {
+                    let _ = inode.try_read_at(0, &mut buffer);
+                    if let Ok(size) = inode.try_size() {
+                        let _ = inode.try_read_at(size.saturating_sub(1), &mut buffer);
+                    }
+                }
+                InodeType::SymLink => {
+                    let _ = inode.try_read_link();
+                }
+            }
+        }
+    }
+    seen
+}
+
+#[test]
+fn efs_corruption_test() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    let (device, efs) = fresh_fs(2048);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut ids = Vec::new();
+    // inline, direct blocks only, and indirect2 blocks
+    for (name, len) in [("inline", 20), ("direct", 4 * BLOCK_SZ), ("indirect", 200 * BLOCK_SZ)] {
+        let file = root_inode.create(name).unwrap();
+        file.write_at(0, &vec![0x5a; len]);
+        ids.push(file.get_inode_number());
+    }
+    let dir = root_inode.create_dir("dir").unwrap();
+    dir.create("file").unwrap().write_at(0, b"in dir");
+    dir.symlink("link", "../direct").unwrap();
+    drop((dir, root_inode, efs));
+    let image = device.to_image();
+    assert_eq!(walk_checked(&EasyFileSystem::try_open(device).unwrap()), 8);
+
+    // byte offset of a disk inode, behind the super block and inode bitmap
+    let inode_at = |inode_id: usize| 2 * BLOCK_SZ + inode_id * 128;
+    let put = |image: &mut Vec<u8>, offset: usize, value: u32| {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    let open = |image: &Vec<u8>| EasyFileSystem::try_open(Arc::new(MemBlockDevice::from_image(image)));
+    let read = |image: &Vec<u8>, name: &str| {
+        let efs = open(image).unwrap();
+        let file = EasyFileSystem::root_inode(&efs).find(name).unwrap();
+        file.try_read_at(0, &mut [0u8; 2 * BLOCK_SZ]).map(|_| file.size())
+    };
+    let mut bad = image.clone();
+    bad[0] ^= 1;
+    assert_eq!(open(&bad).err(), Some(EfsError::NotEfs));
+    let mut bad = image.clone();
+    put(&mut bad, 4, 4096);
+    assert_eq!(open(&bad).err(), Some(EfsError::Device(BlockError::OutOfRange)));
+    let mut bad = image.clone();
+    put(&mut bad, 8, 1000);
+    assert!(matches!(open(&bad), Err(EfsError::Corrupted(_))));
+    // a direct block pointing at the super block
+    let mut bad = image.clone();
+    put(&mut bad, inode_at(ids[1]) + 4, 0);
+    assert!(matches!(read(&bad, "direct"), Err(EfsError::Corrupted(_))));
+    // an indirect1 block past the end of the device
+    let mut bad = image.clone();
+    put(&mut bad, inode_at(ids[2]) + 4 + 28 * 4, 1_000_000);
+    let efs = open(&bad).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find("indirect").unwrap();
+    assert_eq!(file.try_read_at(0, &mut [0u8; 16]), Ok(16));
+    assert!(matches!(file.try_read_at(28 * BLOCK_SZ, &mut [0u8; 16]), Err(EfsError::Corrupted(_))));
+    // a size no inode can address
+    let mut bad = image.clone();
+    put(&mut bad, inode_at(ids[1]), u32::MAX);
+    assert!(matches!(read(&bad, "direct"), Err(EfsError::Corrupted(_))));
+    // a name which is not UTF-8
+    let mut bad = image.clone();
+    let at = (0..bad.len()).step_by(32).find(|at| bad[*at..].starts_with(b"direct\0")).unwrap();
+    bad[at] = 0xff;
+    let efs = open(&bad).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut entries = root_inode.read_dir(0);
+    assert_eq!(entries.try_next().unwrap().unwrap().0, "inline");
+    assert!(matches!(entries.try_next(), Err(EfsError::Corrupted(_))));
+    assert!(matches!(root_inode.try_find("dir"), Err(EfsError::Corrupted(_))));
+    drop((root_inode, efs));
+
+    // random damage to the blocks in use never panics
+    let used: Vec<usize> = (0..image.len() / BLOCK_SZ)
+        .filter(|block| image[block * BLOCK_SZ..(block + 1) * BLOCK_SZ].iter().any(|b| *b != 0))
+        .collect();
+    let mut rng = StdRng::seed_from_u64(49);
+    for _ in 0..300 {
+        let mut bad = image.clone();
+        for _ in 0..rng.gen_range(1..8) {
+            let offset = used[rng.gen_range(0..used.len())] * BLOCK_SZ + rng.gen_range(0..BLOCK_SZ / 4) * 4;
+            let value = match rng.gen_range(0..3) {
+                0 => rng.gen(),
+                1 => rng.gen_range(0..4096),
+                _ => bad[offset] as u32 ^ 1 << rng.gen_range(0..8),
+            };
+            put(&mut bad, offset, value);
+        }
+        if let Ok(efs) = open(&bad) {
+            walk_checked(&efs);
+        }
+    }
+}
+
+#[test]
+fn efs_fault_test() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    let mem = Arc::new(MemBlockDevice::new(2048));
+    let fault = Arc::new(FaultBlockDevice::new(mem.clone()));
+    let efs = EasyFileSystem::create(fault.clone(), 2048, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, b"old");
+    let mut buffer = [0u8; 16];
+    assert_eq!(file.read_at(0, &mut buffer), 3);
+    // a failed write keeps the block dirty and is reported by the next sync
+    // look at a copy of the disk, bypassing the caches of earlier looks
+    let on_disk = |buffer: &mut [u8]| {
+        let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_image(&mem.to_image())));
+        EasyFileSystem::root_inode(&efs).find("file").unwrap().read_at(0, buffer)
+    };
+    fault.fail_io(fault.ios());
+    file.write_at(0, b"new");
+    let len = on_disk(&mut buffer);
+    assert_eq!(&buffer[..len], b"old");
+    fault.fail_io(fault.ios());
+    assert_eq!(efs.sync(), Err(BlockError::Io));
+    assert_eq!(efs.sync(), Ok(()));
+    let len = on_disk(&mut buffer);
+    assert_eq!(&buffer[..len], b"new");
+    // failed reads are fatal
+    let fresh = Arc::new(FaultBlockDevice::new(mem.clone()));
+    fresh.fail_io(0);
+    assert!(catch_unwind(AssertUnwindSafe(|| EasyFileSystem::open(fresh.clone()))).is_err());
+    // so are corrupted superblocks
+    let fresh = Arc::new(FaultBlockDevice::new(mem.clone()));
+    fresh.corrupt_block(0);
+    assert!(catch_unwind(AssertUnwindSafe(|| EasyFileSystem::open(fresh.clone()))).is_err());
+}
+
+/// Cut the power at every write of a workload, then check that all the
+/// operations completed before the cut can be read back
+#[test]
+fn efs_crash_test() {
+    let files = ["a", "b", "c"];
+    let base = Arc::new(MemBlockDevice::new(2048));
+    {
+        let efs = EasyFileSystem::create(base.clone(), 2048, 1);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        for name in files {
+            // past the inline limit, spilling is not crash safe
+            root_inode.create(name).unwrap().write_at(0, &[0xff; 200]);
+        }
+        assert_eq!(efs.sync(), Ok(()));
+    }
+    let appends = 30;
+    let mut cut = 0;
+    loop {
+        let mem = Arc::new(MemBlockDevice::from_image(&base.to_image()));
+        let fault = Arc::new(FaultBlockDevice::new(mem.clone()));
+        fault.drop_writes_from(cut);
+        let efs = EasyFileSystem::open(fault.clone());
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let inodes: Vec<_> = files.iter().map(|name| root_inode.find(name).unwrap()).collect();
+        let mut model: Vec<Vec<u8>> = vec![vec![0xff; 200]; files.len()];
+        let mut durable = model.clone();
+        for i in 0..appends {
+            let file = i % files.len();
+            let data = vec![i as u8; 300];
+            inodes[file].write_at(model[file].len(), &data);
+            model[file].extend_from_slice(&data);
+            if fault.writes() <= cut {
+                durable = model.clone();
+            }
+        }
+        let done = fault.writes() <= cut;
+        drop((inodes, root_inode, efs));
+        let mem: Arc<dyn BlockDevice> = mem;
+        let efs = EasyFileSystem::open(mem);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        for (name, content) in files.iter().zip(durable.iter()) {
+            let mut buffer = vec![0u8; content.len()];
+            let len = root_inode.find(name).unwrap().read_at(0, &mut buffer);
+            assert_eq!(len, content.len(), "cut at write {}", cut);
+            assert!(buffer == *content, "cut at write {}: {} differs", cut, name);
+        }
+        if done {
+            break;
+        }
+        cut += 1;
+    }
+    assert!(cut > appends);
+}
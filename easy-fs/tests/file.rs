@@ -0,0 +1,79 @@
+//! Images as host files, and files of an image as std::io streams
+
+#![cfg(feature = "std")]
+
+use easy_fs::{EasyFileSystem, EfsError, EfsFile, BLOCK_SZ};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+#[test]
+fn efs_file_test() -> std::io::Result<()> {
+    use std::io::copy;
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("target/fs_file.img")?;
+    f.set_len((2048 * BLOCK_SZ) as u64)?;
+    // a plain host file is a block device
+    let efs = EasyFileSystem::create(Arc::new(f), 2048, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i * 7 % 251) as u8).collect();
+    let mut file = EfsFile::new(root_inode.create("file").unwrap());
+    assert_eq!(copy(&mut &data[..], &mut file)?, data.len() as u64);
+    file.flush()?;
+    assert_eq!(file.seek(SeekFrom::Current(0))?, data.len() as u64);
+    // reads follow the position
+    let mut buffer = vec![0u8; 100];
+    file.seek(SeekFrom::Start(30 * BLOCK_SZ as u64 - 50))?;
+    file.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &data[30 * BLOCK_SZ - 50..30 * BLOCK_SZ + 50]);
+    assert_eq!(file.seek(SeekFrom::End(-10))?, data.len() as u64 - 10);
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    assert_eq!(tail, &data[data.len() - 10..]);
+    assert_eq!(file.seek(SeekFrom::Current(-20))?, data.len() as u64 - 20);
+    assert_eq!(file.seek(SeekFrom::End(-1 - data.len() as i64)).unwrap_err().kind(), ErrorKind::InvalidInput);
+    // writing past the end leaves a gap of zeros
+    file.seek(SeekFrom::End(3))?;
+    file.write_all(b"end")?;
+    let mut copied = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    copy(&mut file, &mut copied)?;
+    assert_eq!(copied.len(), data.len() + 6);
+    assert_eq!(&copied[data.len()..], b"\0\0\0end");
+    // the inode is shared with the rest of the filesystem
+    let mut again = EfsFile::new(root_inode.find("file").unwrap());
+    let mut head = [0u8; 4];
+    again.read_exact(&mut head)?;
+    assert_eq!(head, data[..4]);
+    assert_eq!(again.inode().get_inode_number(), file.into_inode().get_inode_number());
+    // errors of the filesystem come back as io errors holding an EfsError
+    let mut full = EfsFile::new(root_inode.create("full").unwrap());
+    let err = full.write_all(&vec![1u8; 2048 * BLOCK_SZ]).unwrap_err();
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<EfsError>(), Some(&EfsError::NoSpace));
+    assert_eq!(full.inode().size(), 0);
+    full.seek(SeekFrom::Start(1 << 30))?;
+    let err = full.write(b"far").unwrap_err();
+    assert_eq!(err.to_string(), "file too large");
+    // positions stay within the largest file, reads past the end are empty
+    assert_eq!(full.seek(SeekFrom::Start(u32::MAX as u64))?, u32::MAX as u64);
+    assert_eq!(full.read(&mut buffer)?, 0);
+    assert_eq!(full.seek(SeekFrom::Current(1)).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(full.seek(SeekFrom::Start(u64::MAX)).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(full.stream_position()?, u32::MAX as u64);
+    assert_eq!(full.inode().read_at(usize::MAX, &mut buffer), 0);
+    let mut dir = EfsFile::new(root_inode.create_dir("dir").unwrap());
+    assert_eq!(dir.read(&mut buffer)?, 64);
+    let err: Box<dyn std::error::Error> = Box::new(EfsError::Corrupted("bad"));
+    assert_eq!(err.to_string(), "corrupted image: bad");
+    drop((dir, full, again, root_inode, efs));
+    // and the image can be opened through the file again
+    let f = File::open("target/fs_file.img")?;
+    let efs = EasyFileSystem::try_open(Arc::new(f)).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    assert_eq!(file.size(), data.len() + 6);
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Growing an image in place
+
+use easy_fs::{EasyFileSystem, MemBlockDevice, BLOCK_SZ};
+use std::sync::Arc;
+
+#[test]
+fn efs_grow_test() -> std::io::Result<()> {
+    // the image starts out using only part of the device
+    let device = Arc::new(MemBlockDevice::new(16384));
+    let efs = EasyFileSystem::create(device.clone(), 2048, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data: Vec<u8> = (0..600 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, &data);
+    root_inode.create("fileb").unwrap().write_at(0, b"hello");
+    // 16384 blocks need more data bitmap blocks, so filea has to move
+    assert!(efs.grow(16384));
+    assert!(!efs.grow(16384));
+    let big: Vec<u8> = (0..8000 * BLOCK_SZ).map(|i| (i % 247) as u8).collect();
+    root_inode.create("filec").unwrap().write_at(0, &big);
+    drop(root_inode);
+    drop(efs);
+
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buffer = vec![0u8; 8000 * BLOCK_SZ];
+    let len = root_inode.find("filea").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], data.as_slice());
+    let len = root_inode.find("fileb").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"hello");
+    let len = root_inode.find("filec").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], big.as_slice());
+    Ok(())
+}
+
+#[test]
+fn efs_grow_boundary_test() {
+    // a full group of data blocks, one more needs a second group of
+    // metadata blocks and leaves a smaller data area
+    let total = 1026 + 4113;
+    let device = Arc::new(MemBlockDevice::new(total + 20));
+    let efs = EasyFileSystem::create(device.clone(), total as u32, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let content = |i: usize| vec![i as u8; 100 * BLOCK_SZ];
+    for i in 0..40 {
+        root_inode.create(&format!("file{}", i)).unwrap().write_at(0, &content(i));
+    }
+    let free = efs.free_data_blocks();
+    assert!(!efs.grow(total as u32 + 1));
+    assert_eq!(efs.free_data_blocks(), free);
+    // a little more is enough to move the blocks in the way
+    assert!(efs.grow(total as u32 + 20));
+    drop(root_inode);
+    drop(efs);
+
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buffer = vec![0u8; 100 * BLOCK_SZ];
+    for i in 0..40 {
+        let file = root_inode.find(&format!("file{}", i)).unwrap();
+        assert_eq!(file.read_at(0, &mut buffer), buffer.len());
+        assert!(buffer == content(i), "file{} differs", i);
+    }
+    assert_eq!(efs.free_data_blocks(), free + 3);
+}
@@ -0,0 +1,121 @@
+//! Random operations on an in-memory image, checked against a model
+
+use easy_fs::{BlockDevice, EasyFileSystem, MemBlockDevice, BLOCK_SZ};
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// An operation of the model test
+#[derive(Debug)]
+enum ModelOp {
+    Create(String),
+    Write(String, usize, usize, u8),
+    Link(String, String),
+    Unlink(String),
+    Clear(String),
+    Reopen,
+}
+
+fn random_model_op(rng: &mut impl rand::Rng) -> ModelOp {
+    let name = |rng: &mut dyn rand::RngCore| format!("f{}", rng.next_u32() % 8);
+    match rng.gen_range(0..20) {
+        0..=3 => ModelOp::Create(name(rng)),
+        4..=11 => {
+            let name = name(rng);
+            // stay inline, fill direct blocks or reach indirect1
+            let offset = match rng.gen_range(0..3) {
+                0 => rng.gen_range(0..128),
+                1 => rng.gen_range(0..28 * BLOCK_SZ),
+                _ => rng.gen_range(0..40 * BLOCK_SZ),
+            };
+            ModelOp::Write(name, offset, rng.gen_range(1..3 * BLOCK_SZ), rng.gen())
+        }
+        12..=13 => ModelOp::Link(name(rng), name(rng)),
+        14..=15 => ModelOp::Unlink(name(rng)),
+        16..=18 => ModelOp::Clear(name(rng)),
+        _ => ModelOp::Reopen,
+    }
+}
+
+/// Run random operations on an in-memory image and on a model of the
+/// root directory, checking they always agree
+#[test]
+fn efs_model_test() {
+    for seed in 0..8u64 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let block_device: Arc<dyn BlockDevice> = Arc::new(MemBlockDevice::new(8192));
+        let mut efs = EasyFileSystem::create(block_device.clone(), 8192, 1);
+        let mut root_inode = EasyFileSystem::root_inode(&efs);
+        // name -> index in `files`
+        let mut names: BTreeMap<String, usize> = BTreeMap::new();
+        let mut files: Vec<Vec<u8>> = Vec::new();
+        let mut history = Vec::new();
+        for _ in 0..150 {
+            let op = random_model_op(&mut rng);
+            let context = format!("seed {}, after {:?}", seed, history);
+            match &op {
+                ModelOp::Create(name) => {
+                    let created = root_inode.create(name);
+                    assert_eq!(created.is_some(), !names.contains_key(name), "{}", context);
+                    if created.is_some() {
+                        names.insert(name.clone(), files.len());
+                        files.push(Vec::new());
+                    }
+                }
+                ModelOp::Write(name, offset, len, byte) => {
+                    if let Some(&file) = names.get(name) {
+                        let data = vec![*byte; *len];
+                        let inode = root_inode.find(name).unwrap();
+                        assert_eq!(inode.write_at(*offset, &data), *len, "{}", context);
+                        let model = &mut files[file];
+                        if model.len() < offset + len {
+                            model.resize(offset + len, 0);
+                        }
+                        model[*offset..offset + len].copy_from_slice(&data);
+                    }
+                }
+                ModelOp::Link(old_name, new_name) => {
+                    let expected = if old_name != new_name
+                        && names.contains_key(old_name)
+                        && !names.contains_key(new_name)
+                    {
+                        names.insert(new_name.clone(), names[old_name]);
+                        0
+                    } else {
+                        -1
+                    };
+                    assert_eq!(root_inode.create_hard_link(old_name, new_name), expected, "{}", context);
+                }
+                ModelOp::Unlink(name) => {
+                    let expected = if names.remove(name).is_some() { 0 } else { -1 };
+                    assert_eq!(root_inode.remove_hard_link(name), expected, "{}", context);
+                }
+                ModelOp::Clear(name) => {
+                    if let Some(&file) = names.get(name) {
+                        root_inode.find(name).unwrap().clear();
+                        files[file].clear();
+                    }
+                }
+                ModelOp::Reopen => {
+                    drop(root_inode);
+                    drop(efs);
+                    efs = EasyFileSystem::open(block_device.clone());
+                    root_inode = EasyFileSystem::root_inode(&efs);
+                }
+            }
+            history.push(op);
+            let context = format!("seed {}, after {:?}", seed, history);
+            let mut ls = root_inode.ls();
+            ls.sort();
+            assert_eq!(ls, names.keys().cloned().collect::<Vec<_>>(), "{}", context);
+            for (name, &file) in names.iter() {
+                let mut buffer = vec![0u8; files[file].len() + BLOCK_SZ];
+                let len = root_inode.find(name).unwrap().read_at(0, &mut buffer);
+                assert!(buffer[..len] == files[file][..], "{}: {} differs", context, name);
+            }
+            // the free block count is kept in step with the data bitmap
+            let allocated: u32 = efs.allocated_data_blocks().iter().map(|(_, len)| len).sum();
+            assert_eq!(efs.free_data_blocks(), efs.usage().data_blocks - allocated, "{}", context);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! The on-disk layout and its versions
+
+mod common;
+
+use common::fresh_fs;
+use easy_fs::{BlockDevice, EasyFileSystem, EfsError, MemBlockDevice, BLOCK_SZ, EFS_VERSION};
+use std::sync::Arc;
+
+#[test]
+fn efs_version_test() -> std::io::Result<()> {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    let (device, efs) = fresh_fs(4096);
+    let block_file: Arc<dyn BlockDevice> = device.clone();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("file").unwrap().write_at(0, b"versioned");
+    assert!(efs.snapshot("snap"));
+    drop(root_inode);
+    drop(efs);
+    assert_eq!(EasyFileSystem::version(&block_file), Ok(EFS_VERSION));
+    assert_eq!(EasyFileSystem::set_version(&block_file, EFS_VERSION + 1), Err(EfsError::Unsupported));
+    assert_eq!(EasyFileSystem::set_version(&block_file, 0), Err(EfsError::Unsupported));
+    // snapshots and refcounts are implied by the areas of a version 1 image
+    EasyFileSystem::set_version(&block_file, 1).unwrap();
+    assert_eq!(EasyFileSystem::version(&block_file), Ok(1));
+    let efs = EasyFileSystem::open(block_file.clone());
+    assert_eq!(efs.list_snapshots(), vec![String::from("snap")]);
+    let mut buffer = [0u8; 16];
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    let len = file.read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"versioned");
+    // version 1 has no symbolic links
+    assert!(EasyFileSystem::root_inode(&efs).symlink("link", "file").is_none());
+    drop(file);
+    drop(efs);
+    EasyFileSystem::set_version(&block_file, EFS_VERSION).unwrap();
+    assert_eq!(EasyFileSystem::version(&block_file), Ok(EFS_VERSION));
+    let efs = EasyFileSystem::open(block_file.clone());
+    assert!(EasyFileSystem::root_inode(&efs).symlink("link", "file").is_some());
+    drop(efs);
+    // symbolic links keep it from going back, nothing is changed
+    assert_eq!(EasyFileSystem::set_version(&block_file, 1), Err(EfsError::Unsupported));
+    assert_eq!(EasyFileSystem::version(&block_file), Ok(EFS_VERSION));
+    let efs = EasyFileSystem::open(block_file.clone());
+    assert_eq!(EasyFileSystem::root_inode(&efs).find("file").unwrap().links(), 1);
+    drop(efs);
+
+    // an unknown incompatible feature makes the image unusable
+    let mut image = device.to_image();
+    image[40..44].copy_from_slice(&0x8000_0001u32.to_le_bytes());
+    let block_file: Arc<dyn BlockDevice> = Arc::new(MemBlockDevice::from_image(&image));
+    let device = block_file.clone();
+    assert!(catch_unwind(AssertUnwindSafe(|| EasyFileSystem::open(device))).is_err());
+    assert_eq!(EasyFileSystem::set_version(&block_file, 1), Err(EfsError::Unsupported));
+
+    // inline data which can't move to blocks keeps the image as it is
+    let (device, efs) = fresh_fs(2048);
+    let block_file: Arc<dyn BlockDevice> = device;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("inline").unwrap().write_at(0, b"inline");
+    let filler = root_inode.create("filler").unwrap();
+    while filler.try_write_at(filler.size(), &[1u8; BLOCK_SZ]).is_ok() {}
+    drop((filler, root_inode, efs));
+    assert_eq!(EasyFileSystem::set_version(&block_file, 1), Err(EfsError::NoSpace));
+    assert_eq!(EasyFileSystem::version(&block_file), Ok(EFS_VERSION));
+    let efs = EasyFileSystem::open(block_file.clone());
+    let mut buffer = [0u8; 16];
+    let len = EasyFileSystem::root_inode(&efs).find("inline").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"inline");
+    let blank: Arc<dyn BlockDevice> = Arc::new(MemBlockDevice::new(16));
+    assert_eq!(EasyFileSystem::set_version(&blank, 1), Err(EfsError::NotEfs));
+    Ok(())
+}
+
+#[test]
+fn efs_layout_test() -> std::io::Result<()> {
+    use std::convert::TryInto;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    let (device, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("hello").unwrap();
+    efs.sync().unwrap();
+    let mut image = device.to_image();
+    let le = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    // super block: magic, total blocks, inode bitmap blocks, inode area blocks
+    let block = &image[..BLOCK_SZ];
+    assert_eq!(le(&block[0..4]), 0x3b800001);
+    assert_eq!(le(&block[4..8]), 4096);
+    assert_eq!(le(&block[8..12]), 1);
+    let inode_area_blocks = le(&block[12..16]);
+    assert_eq!(inode_area_blocks, 1024);
+    // root inode: size, inline data in place of the direct blocks,
+    // then type, low byte of the link count, flags, high byte of the
+    // link count
+    let block = &image[2 * BLOCK_SZ..3 * BLOCK_SZ];
+    assert_eq!(le(&block[0..4]), 32);
+    assert_eq!(le(&block[124..128]), 0x10001);
+    assert_eq!(le(&block[128 + 124..128 + 128]), 0x10100);
+    // directory entry: nul-terminated name then inode number
+    assert_eq!(&block[4..10], b"hello\0");
+    assert_eq!(le(&block[4 + 28..4 + 32]), 1);
+    // an invalid inode type is rejected instead of being reinterpreted
+    drop(root_inode);
+    drop(efs);
+    image[2 * BLOCK_SZ + 128 + 124] = 7;
+    let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_image(&image)));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let hello = root_inode.find("hello").unwrap();
+    assert!(catch_unwind(AssertUnwindSafe(|| hello.get_inode_type())).is_err());
+    Ok(())
+}
+
+#[test]
+fn efs_link_count_test() -> std::io::Result<()> {
+    let (device, efs) = fresh_fs(4096);
+    let block_file: Arc<dyn BlockDevice> = device.clone();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    let dir = root_inode.create_dir("dir").unwrap();
+    assert_eq!(dir.link("a", &file), 0);
+    assert_eq!(dir.link("b", &file), 0);
+    assert_eq!(file.links(), 3);
+    assert_eq!(dir.links(), 1);
+    assert_eq!(root_inode.links(), 0);
+    // replacing a name moves a link from the old target to the new one
+    let other = root_inode.create("other").unwrap();
+    assert_eq!(root_inode.rename("other", &dir, "a"), 0);
+    assert_eq!((file.links(), other.links()), (2, 1));
+    assert_eq!(dir.unlink("b"), 0);
+    assert_eq!(file.links(), 1);
+    // snapshots link their own copies
+    assert!(efs.snapshot("snap"));
+    assert_eq!(file.links(), 1);
+    drop((file, dir, other, root_inode, efs));
+
+    // converting to version 2 clears the counts, converting back
+    // counts every entry again
+    EasyFileSystem::set_version(&block_file, 2).unwrap();
+    let image = device.to_image();
+    assert_eq!(image[2 * BLOCK_SZ + 128 + 125], 0);
+    let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_image(&image)));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find("file").unwrap().links(), 1);
+    assert_eq!(root_inode.find("dir").unwrap().find("a").unwrap().links(), 1);
+    drop((root_inode, efs));
+    EasyFileSystem::set_version(&block_file, EFS_VERSION).unwrap();
+    let mut image = device.to_image();
+    assert_eq!(image[2 * BLOCK_SZ + 128 + 125], 1);
+    let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_image(&image)));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.find("file").unwrap();
+    assert_eq!(file.links(), 1);
+    assert_eq!(root_inode.link("again", &file), 0);
+    assert_eq!(file.links(), 2);
+    drop((file, root_inode, efs));
+
+    // a link count at its maximum can't grow
+    image[2 * BLOCK_SZ + 128 + 125] = 0xff;
+    image[2 * BLOCK_SZ + 128 + 127] = 0xff;
+    let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_image(&image)));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.find("file").unwrap();
+    assert_eq!(file.links(), u16::MAX as usize);
+    assert_eq!(root_inode.link("more", &file), -1);
+    assert_eq!(root_inode.create_hard_link("file", "more"), -1);
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Files and directories of an image
+
+mod common;
+
+use common::fresh_fs;
+use easy_fs::{BlockDevice, EasyFileSystem, BLOCK_SZ, NAME_LENGTH_LIMIT};
+use std::sync::Arc;
+
+#[test]
+fn efs_test() -> std::io::Result<()> {
+    let (device, _) = fresh_fs(4096);
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("filea");
+    root_inode.create("fileb");
+    for name in root_inode.ls() {
+        println!("{}", name);
+    }
+    let filea = root_inode.find("filea").unwrap();
+    let greet_str = "Hello, world!";
+    filea.write_at(0, greet_str.as_bytes());
+    //let mut buffer = [0u8; BLOCK_SZ];
+    let mut buffer = [0u8; 233];
+    let len = filea.read_at(0, &mut buffer);
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
+
+    let mut random_str_test = |len: usize| {
+        filea.clear();
+        assert_eq!(filea.read_at(0, &mut buffer), 0,);
+        let mut str = String::new();
+        use rand;
+        // random digit
+        for _ in 0..len {
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
+        }
+        filea.write_at(0, str.as_bytes());
+        let mut read_buffer = [0u8; 127];
+        let mut offset = 0usize;
+        let mut read_str = String::new();
+        loop {
+            let len = filea.read_at(offset, &mut read_buffer);
+            if len == 0 {
+                break;
+            }
+            offset += len;
+            read_str.push_str(core::str::from_utf8(&read_buffer[..len]).unwrap());
+        }
+        assert_eq!(str, read_str);
+    };
+
+    random_str_test(4 * BLOCK_SZ);
+    random_str_test(8 * BLOCK_SZ + BLOCK_SZ / 2);
+    random_str_test(100 * BLOCK_SZ);
+    random_str_test(70 * BLOCK_SZ + BLOCK_SZ / 7);
+    random_str_test((12 + 128) * BLOCK_SZ);
+    random_str_test(400 * BLOCK_SZ);
+    random_str_test(1000 * BLOCK_SZ);
+    random_str_test(2000 * BLOCK_SZ);
+
+    Ok(())
+}
+
+#[test]
+fn efs_rename_test() -> std::io::Result<()> {
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("filea").unwrap().write_at(0, b"a");
+    let fileb = root_inode.create("fileb").unwrap();
+    fileb.write_at(0, b"b");
+    let fileb_number = fileb.get_inode_number();
+    assert_eq!(root_inode.rename("filea", &root_inode, "filec"), 0);
+    assert!(root_inode.find("filea").is_none());
+    assert_eq!(root_inode.rename("filea", &root_inode, "filed"), -1);
+    // replacing fileb frees its inode
+    assert_eq!(root_inode.rename("filec", &root_inode, "fileb"), 0);
+    let mut buffer = [0u8; 1];
+    root_inode.find("fileb").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer, b"a");
+    // the replaced inode stays readable until its last user is gone
+    let filee = root_inode.create("filee").unwrap();
+    assert_ne!(filee.get_inode_number(), fileb_number);
+    filee.write_at(0, b"e");
+    fileb.read_at(0, &mut buffer);
+    assert_eq!(&buffer, b"b");
+    drop(fileb);
+    assert_eq!(root_inode.create("filef").unwrap().get_inode_number(), fileb_number);
+    // so does an unlinked one
+    let fileg = root_inode.create("fileg").unwrap();
+    fileg.write_at(0, b"g");
+    assert_eq!(root_inode.unlink("fileg"), 0);
+    root_inode.create("fileh").unwrap().write_at(0, b"h");
+    fileg.read_at(0, &mut buffer);
+    assert_eq!(&buffer, b"g");
+    let fileg_number = fileg.get_inode_number();
+    drop(fileg);
+    assert_eq!(root_inode.create("filei").unwrap().get_inode_number(), fileg_number);
+
+    let dir1 = root_inode.create_dir("dir1").unwrap();
+    let dir2 = root_inode.create_dir("dir2").unwrap();
+    dir1.create("file").unwrap();
+    assert_eq!(root_inode.rename("fileb", &root_inode, "dir1"), -1);
+    assert_eq!(root_inode.rename("dir2", &root_inode, "dir1"), -1);
+    assert_eq!(root_inode.rename("dir1", &dir2, "sub"), 0);
+    let sub = dir2.find("sub").unwrap();
+    assert!(sub.find("file").is_some());
+    assert_eq!(
+        sub.find("..").unwrap().get_inode_number(),
+        dir2.get_inode_number()
+    );
+    // a directory can't be moved below itself
+    assert_eq!(root_inode.rename("dir2", &sub, "dir2"), -1);
+    assert_eq!(root_inode.rename("dir2", &dir2, "dir2"), -1);
+    Ok(())
+}
+
+#[test]
+fn efs_read_dir_test() -> std::io::Result<()> {
+    use easy_fs::InodeType;
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    for i in 0..40 {
+        root_inode.create(format!("file{}", i).as_str()).unwrap();
+    }
+    let dir = root_inode.create_dir("dir").unwrap();
+    // names must leave room for the terminating NUL
+    assert!(root_inode.create(&"n".repeat(NAME_LENGTH_LIMIT + 1)).is_none());
+    assert!(root_inode.create_dir(&"n".repeat(40)).is_none());
+    assert!(root_inode.create("").is_none());
+    assert_eq!(root_inode.remove_hard_link("file3"), 0);
+    let entries: Vec<_> = root_inode.read_dir(0).collect();
+    assert_eq!(entries.len(), 40);
+    assert!(entries.iter().all(|(name, _, _)| name != "file3" && !name.is_empty()));
+    assert_eq!(
+        entries.last().unwrap(),
+        &(String::from("dir"), dir.get_inode_number() as u32, InodeType::Directory)
+    );
+    assert_eq!(root_inode.ls().len(), 40);
+    // resume from a stored cursor
+    let mut cursor = root_inode.read_dir(0);
+    let first: Vec<_> = cursor.by_ref().take(10).collect();
+    let rest: Vec<_> = root_inode.read_dir(cursor.offset()).collect();
+    assert_eq!([first, rest].concat(), entries);
+    let names: Vec<_> = dir.read_dir(0).map(|(name, _, type_)| (name, type_)).collect();
+    assert_eq!(
+        names,
+        vec![
+            (String::from("."), InodeType::Directory),
+            (String::from(".."), InodeType::Directory)
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn efs_concurrent_test() -> std::io::Result<()> {
+    use std::thread;
+    let (device, efs) = fresh_fs(8192);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let content = |t: usize, round: usize| -> Vec<u8> {
+        (0..1000 + t * 700 + round * 37)
+            .map(|i| (i * 7 + t * 13 + round) as u8)
+            .collect()
+    };
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let root_inode = Arc::clone(&root_inode);
+            thread::spawn(move || {
+                let dir = root_inode.create_dir(format!("dir{}", t).as_str()).unwrap();
+                let file = dir.create("data").unwrap();
+                for round in 0..10 {
+                    let data = content(t, round);
+                    file.clear();
+                    assert_eq!(file.write_at(0, &data), data.len());
+                    let mut buf = vec![0u8; data.len() + 10];
+                    assert_eq!(file.read_at(0, &mut buf), data.len());
+                    assert_eq!(&buf[..data.len()], &data[..]);
+                    root_inode.create(format!("f{}_{}", t, round).as_str()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(root_inode);
+    drop(efs);
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut names = root_inode.ls();
+    names.sort();
+    let mut expected: Vec<String> = (0..4)
+        .flat_map(|t| {
+            (0..10)
+                .map(move |round| format!("f{}_{}", t, round))
+                .chain(std::iter::once(format!("dir{}", t)))
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(names, expected);
+    for t in 0..4 {
+        let file = root_inode.find(format!("dir{}", t).as_str()).unwrap().find("data").unwrap();
+        let data = content(t, 9);
+        let mut buf = vec![0u8; data.len() + 10];
+        assert_eq!(file.read_at(0, &mut buf), data.len());
+        assert_eq!(&buf[..data.len()], &data[..]);
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_inode_cache_test() -> std::io::Result<()> {
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(Arc::ptr_eq(&root_inode, &EasyFileSystem::root_inode(&efs)));
+    let created = root_inode.create("file").unwrap();
+    let found = root_inode.find("file").unwrap();
+    assert!(Arc::ptr_eq(&created, &found));
+    assert_eq!(created.get_inode_number(), found.get_inode_number());
+    root_inode.create_hard_link("file", "link");
+    assert!(Arc::ptr_eq(&found, &root_inode.find("link").unwrap()));
+    assert_eq!(efs.inodes_in_use(), 2);
+    // unreferenced inodes are evicted
+    drop(created);
+    assert_eq!(efs.inodes_in_use(), 2);
+    drop(found);
+    assert_eq!(efs.inodes_in_use(), 1);
+    let dir = root_inode.create_dir("dir").unwrap();
+    let inode_id = dir.get_inode_number();
+    drop(dir);
+    assert_eq!(efs.inodes_in_use(), 1);
+    assert_eq!(root_inode.find("dir").unwrap().get_inode_number(), inode_id);
+    Ok(())
+}
+
+#[test]
+fn efs_inline_test() -> std::io::Result<()> {
+    let (device, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("config").unwrap();
+    let small: Vec<u8> = (0..100u8).collect();
+    assert_eq!(file.write_at(0, &small), 100);
+    assert_eq!(file.write_at(100, &[0xff; 12]), 12);
+    let mut buffer = [0u8; 1024];
+    assert_eq!(file.read_at(0, &mut buffer), 112);
+    assert_eq!(&buffer[..100], small.as_slice());
+    assert_eq!(&buffer[100..112], &[0xff; 12]);
+    assert_eq!(file.read_at(50, &mut buffer[..10]), 10);
+    assert_eq!(&buffer[..10], &small[50..60]);
+    // growing past the inode moves the data to blocks
+    let big: Vec<u8> = (0..3000).map(|i| (i % 241) as u8).collect();
+    assert_eq!(file.write_at(112, &big), big.len());
+    let mut buffer = vec![0u8; 4096];
+    assert_eq!(file.read_at(0, &mut buffer), 112 + big.len());
+    assert_eq!(&buffer[..100], small.as_slice());
+    assert_eq!(&buffer[112..112 + big.len()], big.as_slice());
+    // and clearing makes it inline again
+    file.clear();
+    assert_eq!(file.write_at(0, b"tiny"), 4);
+    // a directory stays inline up to three entries
+    let dir = root_inode.create_dir("dir").unwrap();
+    dir.create("a").unwrap().write_at(0, b"a");
+    for name in ["b", "c", "d", "e"] {
+        dir.create(name).unwrap().write_at(0, name.as_bytes());
+    }
+    assert!(efs.snapshot("snap"));
+    file.write_at(4, b" change");
+    drop((file, dir, root_inode, efs));
+
+    let efs = EasyFileSystem::open(device.clone());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buffer = [0u8; 64];
+    let len = root_inode.find("config").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"tiny change");
+    let dir = root_inode.find("dir").unwrap();
+    assert_eq!(dir.ls(), vec![".", "..", "a", "b", "c", "d", "e"]);
+    for name in ["a", "b", "c", "d", "e"] {
+        let len = dir.find(name).unwrap().read_at(0, &mut buffer);
+        assert_eq!(&buffer[..len], name.as_bytes());
+    }
+    assert!(efs.restore_snapshot("snap"));
+    let len = root_inode.find("config").unwrap().read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"tiny");
+    drop((dir, root_inode, efs));
+    // version 1 images can't hold inline data, it moves to blocks
+    let device: Arc<dyn BlockDevice> = device;
+    EasyFileSystem::set_version(&device, 1).unwrap();
+    let efs = EasyFileSystem::open(device.clone());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.find("config").unwrap();
+    let len = file.read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"tiny");
+    file.write_at(4, b" again");
+    let len = file.read_at(0, &mut buffer);
+    assert_eq!(&buffer[..len], b"tiny again");
+    assert_eq!(root_inode.find("dir").unwrap().ls().len(), 7);
+    Ok(())
+}
+
+#[test]
+fn efs_preallocate_test() -> std::io::Result<()> {
+    let (_, efs) = fresh_fs(4096);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let is_contiguous = |blocks: &[u32]| blocks.windows(2).all(|w| w[1] == w[0] + 1);
+    // a single large write is laid out in one run, indirect block included
+    let big = root_inode.create("big").unwrap();
+    assert_eq!(big.write_at(0, &vec![1u8; 60 * BLOCK_SZ]), 60 * BLOCK_SZ);
+    let blocks = big.data_blocks();
+    assert_eq!(blocks.len(), 60);
+    assert!(is_contiguous(&blocks[..28]) && is_contiguous(&blocks[28..]));
+    assert_eq!(blocks[28], blocks[27] + 2);
+    // files preallocated up front stay contiguous under interleaved appends
+    let a = root_inode.create("a").unwrap();
+    let b = root_inode.create("b").unwrap();
+    let free = efs.free_data_blocks();
+    assert_eq!(a.preallocate(20 * BLOCK_SZ), 0);
+    assert_eq!(b.preallocate(20 * BLOCK_SZ), 0);
+    assert_eq!(efs.free_data_blocks(), free - 40);
+    let mut buffer = vec![0xffu8; 20 * BLOCK_SZ];
+    assert_eq!(a.read_at(0, &mut buffer), 20 * BLOCK_SZ);
+    assert!(buffer.iter().all(|&byte| byte == 0));
+    for i in 0..20 {
+        a.write_at(i * BLOCK_SZ, &[b'a'; BLOCK_SZ]);
+        b.write_at(i * BLOCK_SZ, &[b'b'; BLOCK_SZ]);
+    }
+    assert_eq!(efs.free_data_blocks(), free - 40);
+    assert!(is_contiguous(&a.data_blocks()));
+    assert!(is_contiguous(&b.data_blocks()));
+    assert_eq!(b.read_at(0, &mut buffer), 20 * BLOCK_SZ);
+    assert!(buffer.iter().all(|&byte| byte == b'b'));
+    // preallocating less than the size keeps the file as is
+    assert_eq!(a.preallocate(BLOCK_SZ), 0);
+    assert_eq!(a.data_blocks().len(), 20);
+    // asking for more than is free fails without allocating anything
+    let c = root_inode.create("c").unwrap();
+    let free = efs.free_data_blocks();
+    assert_eq!(c.preallocate((free as usize + 1) * BLOCK_SZ), -1);
+    assert_eq!(efs.free_data_blocks(), free);
+    assert_eq!(c.read_at(0, &mut buffer), 0);
+    // and so does a length past the largest file
+    assert_eq!(c.preallocate((1 << 32) + 10), -1);
+    assert_eq!(c.size(), 0);
+    Ok(())
+}
@@ -12,6 +12,7 @@ use crate::mm::{
     kernel_token,
 };
 use super::BlockDevice;
+use easy_fs::BlockError;
 use crate::sync::UPSafeCell;
 use alloc::vec::Vec;
 use lazy_static::*;
@@ -27,16 +28,19 @@ lazy_static! {
     };
 }
 
+/// VirtIOBlk neither negotiates VIRTIO_BLK_F_DISCARD nor sends
+/// VIRTIO_BLK_T_DISCARD requests, so `discard` and `can_discard` keep
+/// their defaults and easy-fs refuses to mount with `DiscardMode::Discard`
 impl BlockDevice for VirtIOBlock {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
         self.0.exclusive_access()
         .read_block(block_id, buf)
-        .expect("Error when reading VirtIOBlk");
+        .map_err(|_| BlockError::Io)
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
         self.0.exclusive_access()
         .write_block(block_id, buf)
-        .expect("Error when writing VirtIOBlk");
+        .map_err(|_| BlockError::Io)
     }
 }
 
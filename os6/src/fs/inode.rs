@@ -64,7 +64,7 @@ lazy_static! {
     /// The root of all inodes, or '/' in short
     pub static ref ROOT_INODE: Arc<Inode> = {
         let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
+        EasyFileSystem::root_inode(&efs)
     };
 }
 
@@ -103,6 +103,14 @@ impl OpenFlags {
     }
 }
 
+bitflags! {
+    /// Flags for renaming files
+    pub struct RenameFlags: u32 {
+        const NOREPLACE = 1 << 0;
+        const EXCHANGE = 1 << 1;
+    }
+}
+
 /// Open a file by path
 pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
     let (readable, writable) = flags.read_write();
@@ -177,6 +185,13 @@ impl File for OSInode {
         let inner = self.inner.exclusive_access();
         return inner.inode.get_inode_type()
     }
+    fn preallocate(&self, len: usize) -> isize {
+        if !self.writable {
+            return -1;
+        }
+        let inner = self.inner.exclusive_access();
+        inner.inode.preallocate(len)
+    }
 }
 
 pub fn create_new_dir_entry(o_name: &str,n_name: &str)->isize{
@@ -191,4 +206,16 @@ pub fn remove_hard_link(name: &str) -> isize{
 
 pub fn get_hard_links_by_inode_number(inode_num: u32) -> usize{
     ROOT_INODE.get_inode_number_times(inode_num)
-}
\ No newline at end of file
+}
+
+/// Rename a file in the root directory
+pub fn rename_file(old_name: &str, new_name: &str, flags: RenameFlags) -> isize {
+    if flags.contains(RenameFlags::EXCHANGE) {
+        // not supported
+        return -1;
+    }
+    if flags.contains(RenameFlags::NOREPLACE) && ROOT_INODE.find(new_name).is_some() {
+        return -1;
+    }
+    ROOT_INODE.rename(old_name, &ROOT_INODE, new_name)
+}
@@ -2,7 +2,7 @@ mod stdio;
 mod inode;
 
 use crate::mm::UserBuffer;
-pub use inode::{create_new_dir_entry,remove_hard_link, get_hard_links_by_inode_number};
+pub use inode::{create_new_dir_entry,remove_hard_link, get_hard_links_by_inode_number, rename_file};
 /// The common abstraction of all IO resources
 pub trait File : Send + Sync {
     fn readable(&self) -> bool;
@@ -11,6 +11,8 @@ pub trait File : Send + Sync {
     fn write(&self, buf: UserBuffer) -> usize;
     fn get_inode_number(&self) -> usize;
     fn get_type(&self) -> usize;
+    /// Reserve space for the first `len` bytes, -1 if impossible
+    fn preallocate(&self, len: usize) -> isize;
 }
 
 /// The stat of a inode
@@ -42,4 +44,4 @@ bitflags! {
 }    
 
 pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, OpenFlags, list_apps};
+pub use inode::{OSInode, open_file, OpenFlags, RenameFlags, list_apps};
@@ -37,6 +37,9 @@ impl File for Stdin {
     fn get_type(&self) -> usize {
         0
     }
+    fn preallocate(&self, _len: usize) -> isize {
+        -1
+    }
 }
 
 impl File for Stdout {
@@ -57,4 +60,7 @@ impl File for Stdout {
     fn get_type(&self) -> usize {
         0
     }
+    fn preallocate(&self, _len: usize) -> isize {
+        -1
+    }
 }
@@ -7,6 +7,7 @@ use crate::fs::StatMode;
 use crate::fs::create_new_dir_entry;
 use crate::fs::get_hard_links_by_inode_number;
 use crate::fs::remove_hard_link;
+use crate::fs::rename_file;
 use crate::mm::VirtAddr;
 // use crate::fs::get_inode_by_name;
 use crate::mm::translated_byte_buffer;
@@ -16,6 +17,7 @@ use crate::task::current_user_token;
 use crate::task::current_task;
 use crate::fs::open_file;
 use crate::fs::OpenFlags;
+use crate::fs::RenameFlags;
 use crate::fs::Stat;
 use crate::mm::UserBuffer;
 use crate::task::translate;
@@ -175,3 +177,39 @@ pub fn sys_unlinkat(_name: *const u8) -> isize {
     remove_hard_link(&name)
         
 }
+
+/// Reserve space for `len` bytes at `offset` of an open file, growing it
+/// if needed; only mode 0 is supported
+pub fn sys_fallocate(fd: usize, mode: u32, offset: usize, len: usize) -> isize {
+    if mode != 0 || len == 0 {
+        return -1;
+    }
+    let end = match offset.checked_add(len) {
+        Some(end) => end,
+        None => return -1,
+    };
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        file.preallocate(end)
+    } else {
+        -1
+    }
+}
+
+/// Rename a file, replacing the target unless `RENAME_NOREPLACE` is set.
+/// Directory fds are ignored as everything lives in the root directory.
+pub fn sys_renameat2(old_path: *const u8, new_path: *const u8, flags: u32) -> isize {
+    let token = current_user_token();
+    let old_name = translated_str(token, old_path);
+    let new_name = translated_str(token, new_path);
+    match RenameFlags::from_bits(flags) {
+        Some(flags) => rename_file(&old_name, &new_name, flags),
+        None => -1,
+    }
+}
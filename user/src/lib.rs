@@ -199,6 +199,10 @@ pub fn link(old_path: &str, new_path: &str) -> isize {
     sys_linkat(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path, 0)
 }
 
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_renameat2(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path, 0)
+}
+
 pub fn unlink(path: &str) -> isize {
     sys_unlinkat(AT_FDCWD as usize, path, 0)
 }
@@ -207,6 +211,10 @@ pub fn fstat(fd: usize, st: &Stat) -> isize {
     sys_fstat(fd, st)
 }
 
+pub fn fallocate(fd: usize, offset: usize, len: usize) -> isize {
+    sys_fallocate(fd, 0, offset, len)
+}
+
 pub fn mail_read(buf: &mut [u8]) -> isize {
     sys_mail_read(buf)
 }